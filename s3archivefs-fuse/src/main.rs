@@ -1,15 +1,32 @@
 use std::env;
-use std::rc::Rc;
+use std::io::{Error, ErrorKind};
 use std::ffi::{CString, CStr};
 use std::collections::VecDeque;
+use std::sync::Mutex;
 use aws_config::meta::region::RegionProviderChain;
 use libc::{c_int, c_char, c_void, off_t, size_t};
 use libfuse_sys::fuse;
-use log::{info, debug};
+use log::{info, debug, warn};
 use env_logger;
 use tokio;
-use s3archivefs::squashfs::Archive;
-use s3archivefs::repo::{Remote, Local, HoleDetectMode, CONTEXT};
+use s3archivefs::repo::{Remote, Local, HoleDetectMode, FetchMode, Hydrator, CONTEXT};
+
+// requested rate of background hydrate, and its handle once started
+static HYDRATE: Mutex<Option<Option<usize>>> = Mutex::new(None);
+static HYDRATOR: Mutex<Option<Hydrator>> = Mutex::new(None);
+
+// archive served by this mount, kept in context for read hook
+fn local() -> Local {
+    CONTEXT.with(|c| c.borrow().clone()).expect("archive is not opened")
+}
+
+fn errno(e: &Error) -> c_int {
+    e.raw_os_error().unwrap_or(match e.kind() {
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    })
+}
 
 unsafe extern "C" fn ops_init(conn: *mut fuse::fuse_conn_info, config: *mut fuse::fuse_config) -> *mut c_void
 {
@@ -20,6 +37,19 @@ unsafe extern "C" fn ops_init(conn: *mut fuse::fuse_conn_info, config: *mut fuse
         debug!("FUSE_CAP_READDIRPLUS is set");
     }
 
+    if let Some(rate) = *HYDRATE.lock().unwrap() {
+        info!("start background hydrate, rate: {:?}", rate);
+        let res = CONTEXT.with(|c| c.borrow().as_ref().unwrap().hydrate(rate));
+        match res {
+            Ok(h) => {
+                *HYDRATOR.lock().unwrap() = Some(h);
+            },
+            Err(e) => {
+                warn!("failed to start background hydrate, error: {}", e);
+            },
+        }
+    }
+
     let fuse_ctx = fuse::fuse_get_context();
     (*fuse_ctx).private_data
 }
@@ -29,11 +59,16 @@ unsafe extern "C" fn ops_destroy(private_data: *mut c_void)
     debug!("ops_destroy -");
 
     let _ = private_data;
+
+    if let Some(h) = HYDRATOR.lock().unwrap().take() {
+        info!("stop background hydrate at {:.2}%", h.progress());
+        h.stop();
+    }
 }
 
 unsafe extern "C" fn ops_open(path: *const c_char, fi: *mut fuse::fuse_file_info) -> c_int
 {
-    debug!("ops_open - path: {}", CStr::from_ptr(path).to_str().unwrap());
+    debug!("ops_open - path: {}", CStr::from_ptr(path).to_string_lossy());
 
     let _ = fi;
 
@@ -45,66 +80,53 @@ unsafe extern "C" fn ops_open(path: *const c_char, fi: *mut fuse::fuse_file_info
 
 unsafe extern "C" fn ops_getattr(path: *const c_char, stbuf: *mut libc::stat, fi: *mut fuse::fuse_file_info) -> c_int
 {
-    debug!("ops_getattr - path: {}", CStr::from_ptr(path).to_str().unwrap());
+    debug!("ops_getattr - path: {}", CStr::from_ptr(path).to_string_lossy());
 
     let _ = fi;
-    let fuse_ctx = fuse::fuse_get_context();
-
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    debug!("ops_getattr - ref count {}", Rc::strong_count(&rc));
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    let ret = arcfs.getattr(path, stbuf);
-
-    let _ = Rc::into_raw(rc);
-    ret
-}
-
-#[allow(dead_code)]
-unsafe extern "C" fn fuse_readdir_cb(buf: *mut c_void, name: *const c_char,
-        stbuf: *const libc::stat, filler: Option<unsafe extern "C" fn()>) -> c_int {
 
-    let filler_func = std::mem::transmute::<Option<unsafe extern "C" fn()>, fuse::fuse_fill_dir_t>(filler).unwrap();
-    filler_func(buf, name, stbuf, 0, fuse::fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS);
-    0
+    let path = CStr::from_ptr(path).to_bytes();
+    match local().metadata_bytes(path) {
+        Ok(meta) => {
+            *stbuf = meta.to_stat();
+            0
+        },
+        Err(e) => -errno(&e),
+    }
 }
 
 unsafe extern "C" fn ops_readdir(path: *const c_char, buf: *mut c_void, filler: fuse::fuse_fill_dir_t,
         offset: off_t, fi: *mut fuse::fuse_file_info, flags: fuse::fuse_readdir_flags) -> c_int
 {
     let filler_func = filler.unwrap();
-    debug!("ops_readdir - path: {}, flag: {}", CStr::from_ptr(path).to_str().unwrap(), flags);
+    debug!("ops_readdir - path: {}, flag: {}", CStr::from_ptr(path).to_string_lossy(), flags);
 
     let _ = offset;
     let _ = fi;
 
-    let fuse_ctx = fuse::fuse_get_context();
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    /*
-    // method 1
-    arcfs.readdir_cb(path, buf,
-        std::mem::transmute::<fuse::fuse_fill_dir_t, Option<unsafe extern "C" fn()>>(filler),
-        Some(fuse_readdir_cb)
-    );
-    */
-    // method 2
-    match arcfs.readdir(path) {
-        None => {},
-        Some(dr) => {
-            let _ = dr.map(|(name, st)| {
-                // ownership transferr to ptr
-                let name_ptr = CString::new(name).expect("failed to cstring").into_raw();
-
-                filler_func(buf, name_ptr, std::ptr::addr_of!(st), 0, fuse::fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS);
-
-                // retake ptr to free memory
-                let _ = CString::from_raw(name_ptr);
-            }).collect::<Vec<_>>();
+    let path = CStr::from_ptr(path).to_bytes();
+    let entries = match local().read_dir_bytes(path) {
+        Ok(entries) => entries,
+        Err(e) => return -errno(&e),
+    };
+    for (name, meta) in entries {
+        let meta = match meta {
+            Ok(meta) => meta,
+            Err(e) => {
+                warn!("skip entry {} in {}, {}", String::from_utf8_lossy(&name),
+                    String::from_utf8_lossy(path), e);
+                continue;
+            },
+        };
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let st = meta.to_stat();
+        // buffer full
+        if filler_func(buf, name.as_ptr(), std::ptr::addr_of!(st), 0, fuse::fuse_fill_dir_flags_FUSE_FILL_DIR_PLUS) != 0 {
+            break;
         }
     }
-    let _ = Rc::into_raw(rc);
     0
 }
 
@@ -112,37 +134,43 @@ unsafe extern "C" fn ops_read(path: *const c_char, buf: *mut c_char, size: size_
         offset: off_t, fi: *mut fuse::fuse_file_info) -> c_int
 {
     debug!("ops_read - path: {}, size: {}, offset: {}",
-        CStr::from_ptr(path).to_str().unwrap(), size, offset);
+        CStr::from_ptr(path).to_string_lossy(), size, offset);
 
     let _ = fi;
 
-    let fuse_ctx = fuse::fuse_get_context();
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    let ret = arcfs.read(path, buf, size, offset);
-
-    let _ = Rc::into_raw(rc);
-    ret
+    let path = CStr::from_ptr(path).to_bytes();
+    match local().read_at_bytes(path, offset as usize, size) {
+        Ok(data) => {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len());
+            data.len() as c_int
+        },
+        Err(e) => -errno(&e),
+    }
 }
 
 unsafe extern "C" fn ops_readlink(path: *const c_char, buf: *mut c_char, size: size_t) -> c_int
 {
-    debug!("ops_readlink - path: {}, size: {}", CStr::from_ptr(path).to_str().unwrap(), size);
-
-    let fuse_ctx = fuse::fuse_get_context();
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    let ret = arcfs.readlink(path, buf, size);
-
-    let _ = Rc::into_raw(rc);
-    ret
+    debug!("ops_readlink - path: {}, size: {}", CStr::from_ptr(path).to_string_lossy(), size);
+
+    let path = CStr::from_ptr(path).to_bytes();
+    let target = match local().metadata_bytes(path) {
+        Ok(meta) => match meta.link_target {
+            Some(target) => target,
+            None => return -libc::EINVAL,
+        },
+        Err(e) => return -errno(&e),
+    };
+    if target.len() + 1 > size {
+        return -libc::EIO;
+    }
+    std::ptr::copy_nonoverlapping(target.as_ptr(), buf as *mut u8, target.len());
+    *buf.add(target.len()) = 0;
+    0
 }
 
 unsafe extern "C" fn ops_release(path: *const c_char, fi: *mut fuse::fuse_file_info) -> c_int
 {
-    debug!("ops_release - path: {}", CStr::from_ptr(path).to_str().unwrap());
+    debug!("ops_release - path: {}", CStr::from_ptr(path).to_string_lossy());
 
     let _ = fi;
 
@@ -151,41 +179,62 @@ unsafe extern "C" fn ops_release(path: *const c_char, fi: *mut fuse::fuse_file_i
 
 unsafe extern "C" fn ops_getxattr(path: *const c_char, name: *const c_char, value: *mut c_char, size: size_t) -> c_int
 {
-    debug!("ops_getxattr - path: {}, name: {}, size: {}",
-        CStr::from_ptr(path).to_str().unwrap(), CStr::from_ptr(name).to_str().unwrap(), size);
-
     if name.is_null() {
         return 0;
     }
+    debug!("ops_getxattr - path: {}, name: {}, size: {}",
+        CStr::from_ptr(path).to_string_lossy(), CStr::from_ptr(name).to_string_lossy(), size);
 
-    let key_len = libc::strlen(name);
-    if key_len == 0 {
+    let name = CStr::from_ptr(name).to_bytes();
+    if name.is_empty() {
         return 0;
     }
 
-    let fuse_ctx = fuse::fuse_get_context();
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    let ret = arcfs.getxattr(path, name, value, size);
-
-    let _ = Rc::into_raw(rc);
-    ret
+    let path = CStr::from_ptr(path).to_bytes();
+    let xattrs = match local().metadata_bytes(path) {
+        Ok(meta) => meta.xattrs,
+        Err(e) => return -errno(&e),
+    };
+    let val = match xattrs.get(name) {
+        Some(val) => val,
+        None => return -libc::ENODATA,
+    };
+    // zero size asks for size of value only
+    if size != 0 {
+        if val.len() > size {
+            return -libc::ERANGE;
+        }
+        std::ptr::copy_nonoverlapping(val.as_ptr(), value as *mut u8, val.len());
+    }
+    val.len() as c_int
 }
 
 unsafe extern "C" fn ops_listxattr(path: *const c_char, list: *mut c_char, size: size_t) -> c_int
 {
-    debug!("ops_listxattr - path: {}, size: {}", CStr::from_ptr(path).to_str().unwrap(), size);
+    debug!("ops_listxattr - path: {}, size: {}", CStr::from_ptr(path).to_string_lossy(), size);
 
-    let fuse_ctx = fuse::fuse_get_context();
-    let mut rc = Rc::from_raw((*fuse_ctx).private_data as *mut Archive);
-    let arcfs = Rc::get_mut(&mut rc).unwrap();
-
-    let ret = arcfs.listxattr(path, list, size);
-
-    let _ = Rc::into_raw(rc);
-    ret
-} 
+    let path = CStr::from_ptr(path).to_bytes();
+    let xattrs = match local().metadata_bytes(path) {
+        Ok(meta) => meta.xattrs,
+        Err(e) => return -errno(&e),
+    };
+    // names one after another, each ends with nul
+    let mut names: Vec<&Vec<u8>> = xattrs.keys().collect();
+    names.sort();
+    let mut out = Vec::new();
+    for name in names {
+        out.extend_from_slice(name);
+        out.push(0);
+    }
+    // zero size asks for size of list only
+    if size != 0 {
+        if out.len() > size {
+            return -libc::ERANGE;
+        }
+        std::ptr::copy_nonoverlapping(out.as_ptr(), list as *mut u8, out.len());
+    }
+    out.len() as c_int
+}
 
 fn show_help(args: VecDeque<String>) {
 
@@ -211,6 +260,12 @@ fn show_help(args: VecDeque<String>) {
     println!("  -r, --region\t\tRegion of archive object in Amazon S3");
     println!("  -s, --size\t\tSize of chunk when read data from Amazon S3,");
     println!("\t\t\twhich NO less than underlayer block size. DEFAULT: block size");
+    println!("  --offline\t\tServe from existing local cache only, never access Amazon S3,");
+    println!("\t\t\tbucket and key are not required");
+    println!("  --hydrate [rate]\tFill in whole local cache in background,");
+    println!("\t\t\toptional max download rate in bytes per second");
+    println!("  --trace <file>\tRecord chunk fetches and cache hits to trace file");
+    println!("  --exact\t\tFetch exactly compressed extents needed instead of whole chunks");
     println!("  -h, --help\t\tThis help message");
     println!("\nShow FUSE help below:\n");
 
@@ -249,6 +304,11 @@ fn main() {
     let mut key = None;
     let mut cachefile = None;
     let mut chunksize = None;
+    let mut offline = false;
+    let mut hydrate = false;
+    let mut rate = None;
+    let mut tracefile = None;
+    let mut exact = false;
 
     // app args filter
     while let Some(arg) = args.pop_front() {
@@ -298,6 +358,30 @@ fn main() {
                     }
                 }
             },
+            "--offline" => {
+                offline = true;
+            },
+            "--trace" => {
+                if let Some(next) = args.front() {
+                    if !next.starts_with("-") {
+                        tracefile = args.pop_front();
+                        continue;
+                    }
+                }
+                panic!("please specify --trace <tracefile>");
+            },
+            "--exact" => {
+                exact = true;
+            },
+            "--hydrate" => {
+                hydrate = true;
+                if let Some(next) = args.front() {
+                    if let Ok(r) = next.parse::<usize>() {
+                        rate = Some(r);
+                        args.pop_front();
+                    }
+                }
+            },
             "-h" | "--help" => {
                 help = true;
                 rest_args.push_back(arg)
@@ -315,10 +399,10 @@ fn main() {
     }
 
     // check MUST args
-    if bucket.is_none() {
+    if bucket.is_none() && !offline {
         panic!("please specify -b|--bucket <bucket>");
     }
-    if key.is_none() {
+    if key.is_none() && !offline {
         panic!("please specify -k|--key <key>");
     }
     if cachefile.is_none() {
@@ -326,8 +410,6 @@ fn main() {
     }
 
     let chunksize = chunksize.and_then(|x| x.parse::<usize>().ok());
-    let bucket = bucket.unwrap();
-    let key = key.unwrap();
     let cachefile = cachefile.unwrap();
     let hdmode = HoleDetectMode::LSEEK;
     let force = false;
@@ -352,28 +434,54 @@ fn main() {
         allocated: 0,
     };
 
-    let arcfs = tokio::runtime::Builder::new_current_thread()
+    tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()
         .unwrap()
         .block_on(async {
-            let default_region = RegionProviderChain::default_provider().region().await;
-            let region = region.or(default_region
-                                .map(|r| r.as_ref().to_string())
-                            )
-                            .expect("no region config found in cli or profile");
-            info!("creating Remote - region: {}, bucket: {}, key: {}", region, bucket, key);
-            let remote = Remote::new(&region, &bucket, &key).await;
-            info!("creating Local - cache: {}, chunksize: {:?}, hdmode: LSEEK, force: {}, init_root: {}, last_ver: true",
-                cachefile, chunksize, force, init_root);
-            let local = Local::new(&cachefile, chunksize, hdmode, force, init_root, Some(remote.clone()), true).await;
-            let arcfs = local.get_arcfs();
+            let mut remote = None;
+            if !offline {
+                let default_region = RegionProviderChain::default_provider().region().await;
+                let region = region.or(default_region
+                                    .map(|r| r.as_ref().to_string())
+                                )
+                                .expect("no region config found in cli or profile");
+                let bucket = bucket.unwrap();
+                let key = key.unwrap();
+                info!("creating Remote - region: {}, bucket: {}, key: {}", region, bucket, key);
+                remote = Some(Remote::new(&region, &bucket, &key).await);
+            }
+            info!("creating Local - cache: {}, chunksize: {:?}, hdmode: LSEEK, force: {}, init_root: {}, last_ver: true, offline: {}",
+                cachefile, chunksize, force, init_root, offline);
+            let mut local = match Local::new(&cachefile, chunksize, hdmode, force, init_root, remote, true, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    eprintln!("failed to open archive, {}", e);
+                    std::process::exit(1);
+                },
+            };
+            if let Some(tracefile) = tracefile {
+                info!("record trace to {}", tracefile);
+                local.set_trace(&tracefile).expect("failed to open trace file");
+            }
+            if exact {
+                local.set_fetch_mode(FetchMode::EXTENT);
+            }
+            let l = local.clone();
             CONTEXT.with(|c| *c.borrow_mut() = Some(local));
-            arcfs
+            // index must be built with local in context, it reads through the hook
+            if let Err(e) = l.build_index(Some(&format!("{}.idx", cachefile))) {
+                warn!("failed to build path index, {}", e);
+            }
         });
 
+    // hydrate thread must start after fuse daemonized, see ops_init
+    if hydrate {
+        *HYDRATE.lock().unwrap() = Some(rate);
+    }
+
     info!("starting fuse");
     unsafe {
-        let _ = fuse::fuse_main(fuse_args.argc, fuse_args.argv, &fuse_ops as *const fuse::fuse_operations, arcfs as *mut c_void);
+        let _ = fuse::fuse_main(fuse_args.argc, fuse_args.argv, &fuse_ops as *const fuse::fuse_operations, std::ptr::null_mut());
     }
 }
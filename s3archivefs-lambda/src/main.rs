@@ -1,11 +1,15 @@
 mod vmap;
 mod output;
 use std::collections::HashMap;
+use std::io::{BufRead, ErrorKind, Seek, SeekFrom};
 use aws_lambda_events::s3::object_lambda::S3ObjectLambdaEvent;
 use aws_sdk_s3::Client;
 use aws_sdk_s3::types::DateTime;
 use aws_smithy_types::date_time::Format;
-use aws_smithy_http::byte_stream::{ByteStream, Length};
+use aws_smithy_http::byte_stream::ByteStream;
+use aws_smithy_http::body::SdkBody;
+use hyper::Body;
+use hyper::body::{Bytes, Sender};
 use serde_json::{json, Value};
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use aws_endpoint::partition;
@@ -13,12 +17,12 @@ use aws_endpoint::partition::endpoint;
 use aws_endpoint::{CredentialScope, Partition, PartitionResolver};
 use url::Url;
 use log::{debug, info, warn};
-use rand::distributions::DistString;
 use crate::output::{Object, ListBucketResult, ListObjectsResponse, ListResultXml, HeadObjectResponse, HeadObjectHeaders};
 use crate::vmap::PrefixVMap;
 use s3archivefs::repo;
-
-const EXTRACT_TMP_DIR: &str = "/tmp/s3archivefs_temp_files";
+use s3archivefs::entry::Metadata;
+use s3archivefs::manifest;
+use s3archivefs::file::ArchiveFile;
 
 #[allow(dead_code)]
 fn get_repo_prefix(repo_path: &str, repo_object: &str) -> Option<String> {
@@ -44,7 +48,7 @@ fn get_repo_search_top_and_key(search_prefix: &str, virtual_prefix: &str) -> (St
     (split[0..split.len()-1].join("/").to_string(), search_key)
 }
 
-fn filter_result(v: &mut Vec<(String, libc::stat64)>, filter_key: String, max_count: usize, last_end: Option<String>) -> Option<String> {
+fn filter_result(v: &mut Vec<(String, Metadata)>, filter_key: String, max_count: usize, last_end: Option<String>) -> Option<String> {
 
     // start from last_end, trim head head parts of result vec
     if last_end.is_some() {
@@ -82,6 +86,53 @@ struct Env {
     cache_dir: String,
     chunk_size: Option<usize>,
     hdmode: repo::HoleDetectMode,
+    fetch_mode: repo::FetchMode,
+    // check full object content against manifest sidecar of archive
+    verify: bool,
+}
+
+// send length bytes from current position of file, hashed on the way when
+// manifest given, body is aborted on error or mismatch so client never takes
+// a truncated or unverified object as complete
+async fn stream_content(file: &mut ArchiveFile, length: u64, manifest: Option<&manifest::Manifest>, mut sender: Sender) -> Result<(), std::io::Error> {
+    let mut hash = manifest::StreamHash::default();
+    let mut remain = length;
+    while remain > 0 {
+        let res = file.fill_buf().and_then(|data| {
+            if data.is_empty() {
+                return Err(std::io::Error::new(ErrorKind::UnexpectedEof, "end of file before end of range"));
+            }
+            Ok(data[..std::cmp::min(data.len() as u64, remain) as usize].to_vec())
+        });
+        let data = match res {
+            Ok(data) => data,
+            Err(e) => {
+                sender.abort();
+                return Err(e);
+            },
+        };
+        let n = data.len();
+        if manifest.is_some() {
+            hash.update(&data);
+        }
+        if let Err(e) = sender.send_data(Bytes::from(data)).await {
+            return Err(std::io::Error::new(ErrorKind::BrokenPipe, e));
+        }
+        file.consume(n);
+        remain -= n as u64;
+    }
+
+    if let Some(manifest) = manifest {
+        let (size, sha256) = hash.finish();
+        let what = match manifest.check(file.path(), size, &sha256) {
+            manifest::Check::OK => return Ok(()),
+            manifest::Check::MISMATCH(what) => format!("does not match manifest, {}", what),
+            manifest::Check::UNLISTED => "not in manifest".to_string(),
+        };
+        sender.abort();
+        return Err(std::io::Error::new(ErrorKind::InvalidData, what));
+    }
+    Ok(())
 }
 
 async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -> Result<Value, Error> {
@@ -137,23 +188,40 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
     let repo_key = format!("{}/{}", repo_prefix, repo_object);
     let cachefiledir = format!("{}/{}/{}", env.cache_dir, repo_bucket, repo_prefix);
     let cachefile = format!("{}/{}", cachefiledir, repo_object);
-    tokio::fs::create_dir_all(cachefiledir).await.unwrap();
+    tokio::fs::create_dir_all(cachefiledir).await?;
     let remote = repo::Remote::new(&env.region, &repo_bucket, &repo_key).await;
     debug!("Remote object created");
-    let local = repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false).await;
+    let mut local = match repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false, false).await {
+        Ok(local) => local,
+        Err(e) => {
+            warn!("failed to open archive s3://{}/{}, {}", repo_bucket, repo_key, e);
+            let resp = client.write_get_object_response()
+                            .request_route(output_route)
+                            .request_token(output_token)
+                            .status_code(500)
+                            .error_code("InternalServerError")
+                            .error_message("Internal Server Error")
+                            .send()
+                            .await;
+            debug!("send 500 to client, result: {:?}", resp);
+            return Ok(json!({"status_code": 200}))
+        },
+    };
+    local.set_fetch_mode(env.fetch_mode);
     debug!("Local object created");
     let repo = local.clone();
     repo::CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+    if let Err(e) = repo.build_index(Some(&format!("{}.idx", cachefile))) {
+        warn!("failed to build path index, {}", e);
+    }
     let (repo_top, key) = get_repo_search_top_and_key(key, &matched_virtual_prefix);
     debug!("repo_top {:?}", repo_top);
     debug!("key {:?}", key);
 
-    tokio::fs::create_dir_all(EXTRACT_TMP_DIR).await.unwrap();
-    let tempfile = format!("{}/{}", EXTRACT_TMP_DIR, rand::distributions::Alphanumeric.sample_string(&mut rand::thread_rng(), 16));
-    info!("extract {} to {}", key, tempfile);
-    let res = repo.extract_one(&key, &tempfile);
-    if res.is_err() {
-        warn!("extract failed: {:?}", res);
+    // only regular file has content to serve
+    let res = repo.metadata(&key).ok().filter(|m| m.is_file());
+    if res.is_none() {
+        warn!("{} is not found or not a regular file", key);
         let _ = client.write_get_object_response()
                         .request_route(output_route)
                         .request_token(output_token)
@@ -164,7 +232,7 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
                         .await;
         return Ok(json!({"status_code": 200}))
     }
-    let filesz = res.unwrap();
+    let filesz = res.unwrap().size as usize;
 
     let mut is_range = false;
     let mut offset = 0;
@@ -205,14 +273,11 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
         return Ok(json!({"status_code": 200}))
     }
 
-    let res = ByteStream::read_from()
-                    .path(tempfile)
-                    .offset(offset)
-                    .length(Length::Exact(length))
-                    .build()
-                    .await;
+    // range get decodes only blocks covering the range
+    info!("extract {} range {}+{}", key, offset, length);
+    let res = repo.open(&key).and_then(|mut file| file.seek(SeekFrom::Start(offset)).map(|_| file));
     if res.is_err() {
-        warn!("failed to open tmp file: {:?}", res);
+        warn!("extract failed: {:?}", res.as_ref().err());
         let resp = client.write_get_object_response()
                         .request_route(output_route)
                         .request_token(output_token)
@@ -224,9 +289,34 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
         debug!("send 400 to client, result: {:?}", resp);
         return Ok(json!({"status_code": 200}))
     }
-    let bytestream = res.unwrap();
+    let mut file = res.unwrap();
+
+    // only whole object can be checked, a range is served as is
+    let mut manifest = None;
+    if env.verify && !is_range {
+        match repo.manifest(None).await {
+            Ok(m) => manifest = Some(m),
+            Err(e) => {
+                warn!("failed to load manifest, {}", e);
+                let resp = client.write_get_object_response()
+                                .request_route(output_route)
+                                .request_token(output_token)
+                                .status_code(500)
+                                .error_code("InternalServerError")
+                                .error_message("Object content failed verification")
+                                .send()
+                                .await;
+                debug!("send 500 to client, result: {:?}", resp);
+                return Ok(json!({"status_code": 200}))
+            },
+        }
+    }
 
-    let res = if is_range {
+    // body is fed block by block while response is being sent
+    let (sender, body) = Body::channel();
+    let bytestream = ByteStream::new(SdkBody::from(body));
+
+    let request = if is_range {
         let content_ranges = format!("bytes {}-{}/{}", offset, offset+length-1, filesz);
         client.write_get_object_response()
                     .request_route(output_route)
@@ -237,7 +327,6 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
                     .content_length(length as i64)
                     .body(bytestream)
                     .send()
-                    .await
     } else {
         client.write_get_object_response()
                     .request_route(output_route)
@@ -246,8 +335,11 @@ async fn get_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env) -
                     .content_length(filesz as i64)
                     .body(bytestream)
                     .send()
-                    .await
     };
+    let (res, streamed) = tokio::join!(request, stream_content(&mut file, length, manifest.as_ref(), sender));
+    if let Err(e) = streamed {
+        warn!("failed to stream {}, response aborted, {}", key, e);
+    }
 
     if res.is_err() {
         warn!("failed to send object content back to client, result: {:?}", res);
@@ -281,15 +373,19 @@ async fn head_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env)
     let cachefile = format!("{}/{}", cachefiledir, repo_object);
     info!("repo prefix: {}, repo_key: {}, cachefiledir: {}, cachefile: {}",
             repo_prefix, repo_key, cachefiledir, cachefile);
-    tokio::fs::create_dir_all(cachefiledir).await.unwrap();
+    tokio::fs::create_dir_all(cachefiledir).await?;
     let remote = repo::Remote::new(&env.region, &repo_bucket, &repo_key).await;
-    let local = repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false).await;
+    let mut local = repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false, false).await?;
+    local.set_fetch_mode(env.fetch_mode);
     let repo = local.clone();
     repo::CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+    if let Err(e) = repo.build_index(Some(&format!("{}.idx", cachefile))) {
+        warn!("failed to build path index, {}", e);
+    }
 
     let (repo_top, key) = get_repo_search_top_and_key(key, &matched_virtual_prefix);
     info!("repo_top {:?}, key {:?}", repo_top, key);
-    let res = repo.file_stat(&key);
+    let res = repo.metadata(&key).ok();
     let output;
     if res.is_none() {
         output = HeadObjectResponse {
@@ -299,8 +395,8 @@ async fn head_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env)
             headers: None,
         };
     } else {
-        let stat = res.unwrap();
-        if (stat.st_mode & libc::S_IFDIR) > 0 {
+        let meta = res.unwrap();
+        if meta.is_dir() {
             output = HeadObjectResponse {
                 status_code: 404,
                 error_code: Some("NotFound".to_string()),
@@ -308,7 +404,7 @@ async fn head_object_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: Env)
                 headers: None,
             };
         } else {
-            let filesz = stat.st_size;
+            let filesz = meta.size;
             let headers = HeadObjectHeaders {
                 content_length: filesz as i32,
             };
@@ -352,34 +448,44 @@ async fn list_objects_v2_handler(event: LambdaEvent<S3ObjectLambdaEvent>, env: E
     let repo_key = format!("{}/{}", repo_prefix, repo_object);
     let cachefiledir = format!("{}/{}/{}", env.cache_dir, repo_bucket, repo_prefix);
     let cachefile = format!("{}/{}", cachefiledir, repo_object);
-    tokio::fs::create_dir_all(&cachefiledir).await.unwrap();
+    tokio::fs::create_dir_all(&cachefiledir).await?;
     info!("repo prefix: {}, repo_key: {}, cachefiledir: {}, cachefile: {}",
             repo_prefix, repo_key, cachefiledir, cachefile);
     let remote = repo::Remote::new(&env.region, &repo_bucket, &repo_key).await;
-    let local = repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false).await;
+    let mut local = repo::Local::new(&cachefile, env.chunk_size, env.hdmode, false, false, Some(remote.clone()), false, false).await?;
+    local.set_fetch_mode(env.fetch_mode);
     let repo = local.clone();
     repo::CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+    if let Err(e) = repo.build_index(Some(&format!("{}.idx", cachefile))) {
+        warn!("failed to build path index, {}", e);
+    }
 
     let (repo_search_top, repo_search_key) = get_repo_search_top_and_key(search_prefix, &matched_virtual_prefix);
     info!("matched_virtual_prefix: {}, repo_prefix: {}, repo_search_top: {}, repo_search_key: {}",
         matched_virtual_prefix, repo_prefix, repo_search_top, repo_search_key);
-    let top;
-    if repo_search_top == "" {
-        top = None;
-    } else {
-        top = Some(repo_search_top);
+    // keys keep leading slash of archive path, skip directories
+    let mut v = Vec::new();
+    match repo.walk(&repo_search_top) {
+        Ok(walk) => {
+            for res in walk {
+                match res {
+                    Ok(entry) if !entry.metadata.is_dir() => v.push((format!("/{}", entry.path), entry.metadata)),
+                    Ok(_) => {},
+                    Err(e) => warn!("error reading entry under {}, {}", repo_search_top, e),
+                }
+            }
+        },
+        Err(e) => warn!("error walking {}, {}", repo_search_top, e),
     }
-
-    let mut v = repo.file_list(top);
     let last_end = filter_result(&mut v, repo_search_key, max_keys, continue_token);
 
     let mut contents = Vec::new();
     for f in &v {
         contents.push(Object {
             key: format!("{}{}", matched_virtual_prefix, f.0),
-            last_modified: Some(DateTime::from_secs(f.1.st_mtime).fmt(Format::DateTime).unwrap()),
+            last_modified: Some(DateTime::from_secs(f.1.mtime).fmt(Format::DateTime).unwrap()),
             etag: None,
-            size: f.1.st_size as i32,
+            size: f.1.size as i32,
             checksum_algorighm: None,
             owner: None,
             storage_class: None,
@@ -439,6 +545,13 @@ async fn function_handler(event: LambdaEvent<S3ObjectLambdaEvent>) -> Result<Val
         hdmode = repo::HoleDetectMode::LSEEK;
     }
 
+    let fetch_mode = match std::env::var("S3ARCHIVEFS_FETCH_MODE").unwrap_or_default().as_str() {
+        "EXTENT" | "extent" => repo::FetchMode::EXTENT,
+        _ => repo::FetchMode::CHUNK,
+    };
+
+    let verify = matches!(std::env::var("S3ARCHIVEFS_VERIFY").unwrap_or_default().as_str(), "1" | "true" | "TRUE");
+
     let vmap = PrefixVMap::new();
 
     let env = Env {
@@ -447,6 +560,8 @@ async fn function_handler(event: LambdaEvent<S3ObjectLambdaEvent>) -> Result<Val
         cache_dir: cache_dir,
         chunk_size: chunk_size,
         hdmode: hdmode,
+        fetch_mode: fetch_mode,
+        verify: verify,
     };
 
     if event.payload.get_object_context.is_some() {
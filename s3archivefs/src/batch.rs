@@ -0,0 +1,220 @@
+use std::io::{Error, ErrorKind};
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::OsStr;
+use std::path::Path;
+use log::{info, warn};
+use crate::repo::Local;
+use crate::entry::BlockLayout;
+use crate::extract::{self, ExtractOptions, PathMode, SafeDir, EntryMeta};
+use crate::find::Filter;
+
+#[derive(Debug, Default)]
+pub struct BatchResult {
+    // regular files written, in order extracted
+    pub extracted: Vec<String>,
+    // symlinks, device nodes and other non regular entries written
+    pub others: usize,
+    pub bytes: usize,
+    // paths not in archive
+    pub missing: Vec<String>,
+    // path and what went wrong
+    pub failed: Vec<(String, String)>,
+    // distinct fragment blocks holding tails of extracted files
+    pub fragments: usize,
+}
+
+impl BatchResult {
+
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty() && self.failed.is_empty()
+    }
+}
+
+// files sharing a fragment block are keyed by it and so come one after
+// another, data reader keeps last fragment block it decompressed and serves
+// all their tails from it, others go by offset of their first data block
+pub fn read_order(layout: &BlockLayout) -> (u64, u64) {
+    match layout.fragment {
+        Some(f) => (f.start, layout.start),
+        None => (layout.start, layout.start),
+    }
+}
+
+// open rel under root one component at a time, so a symlink left in
+// destination never leads outside of it
+fn open_dir(root: &SafeDir, rel: &str, mode: PathMode) -> Result<SafeDir, Error> {
+    let mut names = rel.split('/');
+    let mut dir = root.subdir(OsStr::new(names.next().unwrap_or("")), mode)?;
+    for name in names {
+        dir = dir.subdir(OsStr::new(name), mode)?;
+    }
+    Ok(dir)
+}
+
+// every non directory entry of paths, a directory stands for all below it,
+// regular files in read order after other entries
+fn resolve(local: &Local, paths: &[String], res: &mut BatchResult) -> Result<Vec<(String, bool)>, Error> {
+    let filter = Filter::default();
+    let mut seen = HashSet::new();
+    let mut others = Vec::new();
+    let mut files = Vec::new();
+    for path in paths {
+        let found = match local.find(path, &filter) {
+            Ok(found) => found,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                res.missing.push(path.clone());
+                continue;
+            },
+            Err(e) => return Err(e),
+        };
+        for entry in found {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    res.failed.push((path.clone(), format!("failed to read entry, {}", e)));
+                    continue;
+                },
+            };
+            if entry.metadata.is_dir() || !seen.insert(entry.path.clone()) {
+                continue;
+            }
+            if !entry.metadata.is_file() {
+                others.push(entry.path);
+                continue;
+            }
+            match local.block_layout(&entry.path) {
+                Ok(layout) => files.push((read_order(&layout), layout.fragment.map(|f| f.index), entry.path)),
+                Err(e) => res.failed.push((entry.path, e.to_string())),
+            }
+        }
+    }
+    files.sort();
+    res.fragments = files.iter().filter_map(|f| f.1).collect::<HashSet<_>>().len();
+
+    let mut order: Vec<(String, bool)> = others.into_iter().map(|p| (p, false)).collect();
+    order.extend(files.into_iter().map(|(_, _, p)| (p, true)));
+    Ok(order)
+}
+
+// extract entries of paths into the tree under outdir, keeping archive
+// paths, with their blocks fetched ahead in coalesced concurrent GETs in
+// archive offset order, an entry failed is recorded and the rest go on,
+// except an unsafe path in strict mode which aborts
+pub async fn extract_list(local: &Local, paths: &[String], outdir: &str, opts: &ExtractOptions, concurrency: usize) -> Result<BatchResult, Error> {
+
+    local.activate();
+    let mut res = BatchResult::default();
+    let order = resolve(local, paths, &mut res)?;
+    info!("{} entries to extract, tails in {} fragment blocks", order.len(), res.fragments);
+
+    let files = order.iter().filter(|e| e.1).map(|(p, _)| (p.clone(), ())).collect();
+    let (files, unresolved) = local.extents_by_offset(files);
+    // left to extract, which records them if they fail there too
+    for (path, e) in unresolved {
+        warn!("failed to resolve blocks of {}, not prefetched, {}", path, e);
+    }
+    local.prefetch_ahead(files.into_iter().flat_map(|f| f.2).collect(), concurrency).await?;
+
+    let root = SafeDir::open_root(Path::new(outdir))?;
+    // parent directories made or reused, metadata restored once all is written
+    let mut dirs = BTreeSet::new();
+    for (path, regular) in order {
+        // strict mode aborts the batch on an unsafe path, lenient skips it
+        if let Err(e) = extract::check_path(&path) {
+            opts.skip_unsafe(Err(e), Path::new(&path))?;
+            continue;
+        }
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (Some(parent), name),
+            None => (None, path.as_str()),
+        };
+        // parent opened one component at a time, entry created relative to its fd
+        let dir = match parent.map(|p| open_dir(&root, p, opts.path_mode)) {
+            Some(Ok(dir)) => Some(dir),
+            Some(Err(e)) => {
+                res.failed.push((path, format!("failed to create parent directory, {}", e)));
+                continue;
+            },
+            None => None,
+        };
+        if let Some(parent) = parent {
+            let mut prefix = String::new();
+            for name in parent.split('/') {
+                if !prefix.is_empty() {
+                    prefix.push('/');
+                }
+                prefix.push_str(name);
+                dirs.insert(prefix.clone());
+            }
+        }
+
+        match local.extract_one_at(&path, dir.as_ref().unwrap_or(&root), OsStr::new(name), opts) {
+            Ok(size) if regular => {
+                res.bytes += size;
+                res.extracted.push(path);
+            },
+            Ok(_) => res.others += 1,
+            Err(e) => {
+                warn!("failed to extract {}, {}", path, e);
+                res.failed.push((path, e.to_string()));
+            },
+        }
+    }
+
+    // deepest first, writing a child changes mtime of its parent
+    for dir in dirs.iter().rev() {
+        let meta = match local.metadata(dir) {
+            Ok(m) => EntryMeta {
+                mode: m.mode,
+                uid: m.uid,
+                gid: m.gid,
+                mtime: m.mtime,
+                xattrs: if m.xattrs.is_empty() { None } else { Some(m.xattrs) },
+                symlink: false,
+            },
+            Err(e) => {
+                warn!("failed to read metadata of directory {}, {}", dir, e);
+                continue;
+            },
+        };
+        let set = match dir.rsplit_once('/') {
+            Some((parent, name)) => open_dir(&root, parent, opts.path_mode).and_then(|d| d.set_metadata(OsStr::new(name), &meta)),
+            None => root.set_metadata(OsStr::new(dir), &meta),
+        };
+        if let Err(e) = set {
+            warn!("failed to set metadata of directory {}, {}", dir, e);
+        }
+    }
+    info!("{} files and {} other entries extracted to {}, {} failed",
+        res.extracted.len(), res.others, outdir, res.failed.len());
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entry::Fragment;
+
+    fn layout(start: u64, fragment: Option<(u32, u64)>) -> BlockLayout {
+        BlockLayout {
+            start: start,
+            sizes: Vec::new(),
+            fragment: fragment.map(|(index, start)| Fragment { index: index, offset: 0, start: start, size: 100 }),
+        }
+    }
+
+    #[test]
+    fn test_read_order() {
+        let mut files = vec![
+            (read_order(&layout(5000, Some((1, 9000)))), "big"),
+            (read_order(&layout(7000, None)), "whole"),
+            (read_order(&layout(0, Some((0, 3000)))), "small"),
+            (read_order(&layout(0, Some((1, 9000)))), "tiny"),
+            (read_order(&layout(1000, Some((0, 3000)))), "mid"),
+        ];
+        files.sort();
+        let names: Vec<&str> = files.iter().map(|f| f.1).collect();
+        // tails of one fragment block next to each other
+        assert_eq!(names, vec!["small", "mid", "whole", "tiny", "big"]);
+    }
+}
@@ -0,0 +1,227 @@
+use std::io::{Error, BufRead};
+use std::collections::BTreeMap;
+use log::{debug, warn};
+use crate::repo::Local;
+use crate::entry::{Entry, Metadata, BlockLayout};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Change {
+    // only in second archive
+    ADDED,
+    // only in first archive
+    REMOVED,
+    MODIFIED,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Difference {
+    // without leading slash, "" for root
+    pub path: String,
+    pub change: Change,
+    // what differs for a modified entry, e.g. size, mode, content
+    pub fields: Vec<&'static str>,
+}
+
+// fields of b differing from a, inode number and link count are left out
+// as they depend on how each archive was packed, not on the tree
+pub fn compare(a: &Metadata, b: &Metadata) -> Vec<&'static str> {
+    let mut fields = Vec::new();
+    if a.file_type != b.file_type {
+        fields.push("type");
+    }
+    // size of a directory is that of its listing in archive
+    if a.size != b.size && !(a.is_dir() && b.is_dir()) {
+        fields.push("size");
+    }
+    if a.mode != b.mode {
+        fields.push("mode");
+    }
+    if a.uid != b.uid || a.gid != b.gid {
+        fields.push("owner");
+    }
+    if a.mtime != b.mtime {
+        fields.push("mtime");
+    }
+    if a.xattrs != b.xattrs {
+        fields.push("xattrs");
+    }
+    if a.link_target != b.link_target {
+        fields.push("link_target");
+    }
+    if a.rdev != b.rdev {
+        fields.push("rdev");
+    }
+    fields
+}
+
+// path and every entry below it, keyed by path
+fn collect(local: &Local, path: &str) -> Result<BTreeMap<String, Metadata>, Error> {
+    local.activate();
+    let mut entries = BTreeMap::new();
+    let top = local.metadata(path)?;
+    let is_dir = top.is_dir();
+    entries.insert(path.trim_matches('/').to_string(), top);
+    if is_dir {
+        for entry in local.walk(path)? {
+            match entry {
+                Ok(Entry { path, metadata }) => {
+                    entries.insert(path, metadata);
+                },
+                Err(e) => warn!("failed to read entry under {}, {}", path, e),
+            }
+        }
+    }
+    Ok(entries)
+}
+
+// compare hierarchies under path of both archives on metadata, with content
+// regular files alike on metadata are compared on data too
+pub fn diff(a: &Local, b: &Local, path: &str, content: bool) -> Result<Vec<Difference>, Error> {
+    let mut left = collect(a, path)?;
+    let right = collect(b, path)?;
+    debug!("{} entries in first archive, {} in second", left.len(), right.len());
+
+    let mut diffs = Vec::new();
+    for (path, mb) in right {
+        let ma = match left.remove(&path) {
+            Some(ma) => ma,
+            None => {
+                diffs.push(Difference { path: path, change: Change::ADDED, fields: Vec::new() });
+                continue;
+            },
+        };
+        let mut fields = compare(&ma, &mb);
+        if content && fields.is_empty() && ma.is_file() && ma.size > 0 && !same_content(a, b, &path)? {
+            fields.push("content");
+        }
+        if !fields.is_empty() {
+            diffs.push(Difference { path: path, change: Change::MODIFIED, fields: fields });
+        }
+    }
+    for path in left.into_keys() {
+        diffs.push(Difference { path: path, change: Change::REMOVED, fields: Vec::new() });
+    }
+    diffs.sort_by(|x, y| x.path.cmp(&y.path));
+    Ok(diffs)
+}
+
+// files of same size, compressed block sizes differ with compressor options
+// too, so they only pick which blocks to compare first, content is always
+// decided on decompressed data
+fn same_content(a: &Local, b: &Local, filepath: &str) -> Result<bool, Error> {
+    if a.superblock().block_size != b.superblock().block_size {
+        return same_stream(a, b, filepath);
+    }
+
+    a.activate();
+    let la = a.block_layout(filepath)?;
+    b.activate();
+    let lb = b.block_layout(filepath)?;
+
+    // each read may fetch from remote, so hook must serve the archive read
+    for i in block_order(&la, &lb) {
+        a.activate();
+        let da = a.read_block(filepath, i)?;
+        b.activate();
+        let db = b.read_block(filepath, i)?;
+        if da != db {
+            debug!("{} differs in data block {}", filepath, i);
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+// block sizes on disk, fragment tail last
+fn disk_sizes(l: &BlockLayout) -> Vec<u32> {
+    let mut sizes = l.sizes.clone();
+    sizes.extend(l.fragment.map(|f| f.size));
+    sizes
+}
+
+// indexes of all blocks, ones differing in size on disk first as they most
+// likely hold the change
+fn block_order(la: &BlockLayout, lb: &BlockLayout) -> Vec<usize> {
+    let (sa, sb) = (disk_sizes(la), disk_sizes(lb));
+    let (mut first, mut rest): (Vec<usize>, Vec<usize>) = (0..std::cmp::max(sa.len(), sb.len()))
+        .partition(|i| sa.get(*i) != sb.get(*i));
+    first.append(&mut rest);
+    first
+}
+
+// block sizes differ between archives, compare decompressed data as streams
+fn same_stream(a: &Local, b: &Local, filepath: &str) -> Result<bool, Error> {
+    a.activate();
+    let mut fa = a.open(filepath)?;
+    b.activate();
+    let mut fb = b.open(filepath)?;
+    loop {
+        a.activate();
+        let da = fa.fill_buf()?;
+        b.activate();
+        let db = fb.fill_buf()?;
+        let n = std::cmp::min(da.len(), db.len());
+        if n == 0 {
+            return Ok(da.len() == db.len());
+        }
+        if da[..n] != db[..n] {
+            debug!("{} differs in data", filepath);
+            return Ok(false);
+        }
+        fa.consume(n);
+        fb.consume(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::entry::{FileType, Fragment};
+
+    fn sample(file_type: FileType, size: u64) -> Metadata {
+        Metadata {
+            file_type: file_type,
+            size: size,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
+            mtime: 1000,
+            nlink: 1,
+            rdev: 0,
+            ino: 2,
+            xattrs: HashMap::new(),
+            link_target: None,
+        }
+    }
+
+    #[test]
+    fn test_compare() {
+        let a = sample(FileType::REGULAR, 10);
+        let mut b = a.clone();
+        b.ino = 9;
+        b.nlink = 2;
+        assert!(compare(&a, &b).is_empty());
+
+        b.size = 11;
+        b.gid = 1;
+        b.xattrs.insert(b"user.k".to_vec(), b"v".to_vec());
+        assert_eq!(compare(&a, &b), vec!["size", "owner", "xattrs"]);
+
+        assert!(compare(&sample(FileType::DIRECTORY, 3), &sample(FileType::DIRECTORY, 40)).is_empty());
+        assert_eq!(compare(&sample(FileType::DIRECTORY, 3), &sample(FileType::SYMLINK, 3)), vec!["type"]);
+    }
+
+    #[test]
+    fn test_block_order() {
+        let la = BlockLayout { start: 0, sizes: vec![10, 20, 30], fragment: None };
+        let mut lb = BlockLayout { start: 0, sizes: vec![10, 25, 30], fragment: None };
+        assert_eq!(block_order(&la, &lb), vec![1, 0, 2]);
+        // tail stored as fragment in one, as a block in the other
+        lb.sizes.pop();
+        lb.fragment = Some(Fragment { index: 0, offset: 0, start: 0, size: 30 });
+        assert_eq!(block_order(&la, &lb), vec![1, 0, 2]);
+        lb.fragment = None;
+        assert_eq!(block_order(&la, &lb), vec![1, 2, 0]);
+    }
+}
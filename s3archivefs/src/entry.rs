@@ -0,0 +1,261 @@
+use std::io::Error;
+use std::rc::Rc;
+use std::collections::HashMap;
+use crate::ArchiveFs;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FileType {
+    REGULAR,
+    DIRECTORY,
+    SYMLINK,
+    BLOCKDEV,
+    CHARDEV,
+    FIFO,
+    SOCKET,
+}
+
+impl FileType {
+
+    pub fn from_mode(mode: u32) -> Option<Self> {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Some(FileType::REGULAR),
+            libc::S_IFDIR => Some(FileType::DIRECTORY),
+            libc::S_IFLNK => Some(FileType::SYMLINK),
+            libc::S_IFBLK => Some(FileType::BLOCKDEV),
+            libc::S_IFCHR => Some(FileType::CHARDEV),
+            libc::S_IFIFO => Some(FileType::FIFO),
+            libc::S_IFSOCK => Some(FileType::SOCKET),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileType::REGULAR => "file",
+            FileType::DIRECTORY => "dir",
+            FileType::SYMLINK => "symlink",
+            FileType::BLOCKDEV => "blockdev",
+            FileType::CHARDEV => "chardev",
+            FileType::FIFO => "fifo",
+            FileType::SOCKET => "socket",
+        }
+    }
+}
+
+// everything squashfs keeps about an inode, mode holds permission bits only
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub nlink: u32,
+    pub rdev: u32,
+    pub ino: u32,
+    pub xattrs: HashMap<Vec<u8>, Vec<u8>>,
+    pub link_target: Option<Vec<u8>>,
+}
+
+impl Metadata {
+
+    pub fn is_file(&self) -> bool {
+        self.file_type == FileType::REGULAR
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type == FileType::DIRECTORY
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type == FileType::SYMLINK
+    }
+
+    // permission bits with file type bits, as st_mode
+    pub fn st_mode(&self) -> u32 {
+        let fmt = match self.file_type {
+            FileType::REGULAR => libc::S_IFREG,
+            FileType::DIRECTORY => libc::S_IFDIR,
+            FileType::SYMLINK => libc::S_IFLNK,
+            FileType::BLOCKDEV => libc::S_IFBLK,
+            FileType::CHARDEV => libc::S_IFCHR,
+            FileType::FIFO => libc::S_IFIFO,
+            FileType::SOCKET => libc::S_IFSOCK,
+        };
+        fmt | (self.mode & !libc::S_IFMT)
+    }
+
+    // for callers that speak stat, e.g. fuse, blocks are counted from size
+    pub fn to_stat(&self) -> libc::stat {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        st.st_ino = self.ino as libc::ino_t;
+        st.st_mode = self.st_mode();
+        st.st_nlink = self.nlink as libc::nlink_t;
+        st.st_uid = self.uid;
+        st.st_gid = self.gid;
+        st.st_rdev = self.rdev as libc::dev_t;
+        st.st_size = self.size as libc::off_t;
+        st.st_blksize = 4096;
+        if self.is_file() {
+            st.st_blocks = ((self.size + 511) >> 9) as libc::blkcnt_t;
+        }
+        st.st_atime = self.mtime;
+        st.st_mtime = self.mtime;
+        st.st_ctime = self.mtime;
+        st
+    }
+}
+
+// path is absolute inside archive without leading slash, "" for root
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: String,
+    pub metadata: Metadata,
+}
+
+impl Entry {
+
+    pub fn name(&self) -> &str {
+        match self.path.rfind('/') {
+            Some(i) => &self.path[i + 1..],
+            None => &self.path,
+        }
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.metadata.file_type
+    }
+}
+
+// where data of a regular file is in archive, block sizes are sizes on
+// disk, 0 for a sparse block
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockLayout {
+    pub start: u64,
+    pub sizes: Vec<u32>,
+    pub fragment: Option<Fragment>,
+}
+
+// tail of file packed with others into a fragment block
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Fragment {
+    pub index: u32,
+    pub offset: u32,
+    pub start: u64,
+    pub size: u32,
+}
+
+// entries of one directory, read at once as dir reader is not reentrant
+pub struct ReadDir {
+    entries: std::vec::IntoIter<Result<Entry, Error>>,
+}
+
+impl ReadDir {
+
+    pub fn new(entries: Vec<Result<Entry, Error>>) -> Self {
+        Self {
+            entries: entries.into_iter(),
+        }
+    }
+}
+
+impl Iterator for ReadDir {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.entries.next()
+    }
+}
+
+// depth first walk below a directory, a child directory is read only when
+// walk reaches it, so an error in it does not stop the rest of the walk
+pub struct WalkDir {
+    arcfs: Rc<dyn ArchiveFs>,
+    stack: Vec<ReadDir>,
+}
+
+impl WalkDir {
+
+    pub fn new(arcfs: Rc<dyn ArchiveFs>, path: &str) -> Result<Self, Error> {
+        let top = arcfs.read_dir(path)?;
+        Ok(Self {
+            arcfs: arcfs,
+            stack: vec![top],
+        })
+    }
+}
+
+impl Iterator for WalkDir {
+    type Item = Result<Entry, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let rd = self.stack.last_mut()?;
+            match rd.next() {
+                Some(Ok(entry)) => {
+                    if entry.metadata.is_dir() {
+                        match self.arcfs.read_dir(&entry.path) {
+                            Ok(sub) => self.stack.push(sub),
+                            Err(e) => return Some(Err(e)),
+                        }
+                    }
+                    return Some(Ok(entry));
+                },
+                Some(Err(e)) => return Some(Err(e)),
+                None => {
+                    self.stack.pop();
+                },
+            }
+        }
+    }
+}
+
+pub(crate) fn join_path(parent: &str, name: &str) -> String {
+    let parent = parent.trim_matches('/');
+    if parent.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_join_path() {
+        assert_eq!(join_path("", "a"), "a");
+        assert_eq!(join_path("/", "a"), "a");
+        assert_eq!(join_path("/x/y/", "a"), "x/y/a");
+    }
+
+    #[test]
+    fn test_file_type_from_mode() {
+        assert_eq!(FileType::from_mode(libc::S_IFREG | 0o644), Some(FileType::REGULAR));
+        assert_eq!(FileType::from_mode(libc::S_IFLNK | 0o777), Some(FileType::SYMLINK));
+        assert_eq!(FileType::from_mode(0o644), None);
+    }
+
+    #[test]
+    fn test_to_stat() {
+        let meta = Metadata {
+            file_type: FileType::REGULAR,
+            size: 513,
+            mode: 0o640,
+            uid: 1,
+            gid: 2,
+            mtime: 77,
+            nlink: 1,
+            rdev: 0,
+            ino: 9,
+            xattrs: HashMap::new(),
+            link_target: None,
+        };
+        let st = meta.to_stat();
+        assert_eq!(st.st_mode, libc::S_IFREG | 0o640);
+        assert_eq!((st.st_ino, st.st_size, st.st_blocks), (9, 513, 2));
+        assert_eq!((st.st_uid, st.st_gid, st.st_mtime), (1, 2, 77));
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+use crate::bindings::*;
+
+// crate wide error, converts into io::Error so it passes through
+// functions still returning io::Result, with kind mapped from variant
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    // libsquashfs SQFS_ERROR_* code, with what was being done
+    SQFS(i32, String),
+    S3(String),
+    NOTFOUND(String),
+    INVALID(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::IO(e) => e.kind(),
+            Error::SQFS(code, _) => match *code {
+                SQFS_ERROR_SQFS_ERROR_NO_ENTRY => io::ErrorKind::NotFound,
+                SQFS_ERROR_SQFS_ERROR_CORRUPTED |
+                SQFS_ERROR_SQFS_ERROR_SUPER_MAGIC |
+                SQFS_ERROR_SQFS_ERROR_SUPER_VERSION |
+                SQFS_ERROR_SQFS_ERROR_SUPER_BLOCK_SIZE => io::ErrorKind::InvalidData,
+                SQFS_ERROR_SQFS_ERROR_ARG_INVALID => io::ErrorKind::InvalidInput,
+                _ => io::ErrorKind::Other,
+            },
+            Error::S3(_) => io::ErrorKind::Other,
+            Error::NOTFOUND(_) => io::ErrorKind::NotFound,
+            Error::INVALID(_) => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+pub fn sqfs(code: i32, what: &str) -> Error {
+    Error::SQFS(code, what.to_string())
+}
+
+pub fn sqfs_error_name(code: i32) -> &'static str {
+    match code {
+        SQFS_ERROR_SQFS_ERROR_ALLOC => "out of memory",
+        SQFS_ERROR_SQFS_ERROR_IO => "I/O error",
+        SQFS_ERROR_SQFS_ERROR_COMPRESSOR => "compressor error",
+        SQFS_ERROR_SQFS_ERROR_INTERNAL => "internal error",
+        SQFS_ERROR_SQFS_ERROR_CORRUPTED => "data corrupted",
+        SQFS_ERROR_SQFS_ERROR_UNSUPPORTED => "unsupported feature",
+        SQFS_ERROR_SQFS_ERROR_OVERFLOW => "numeric overflow",
+        SQFS_ERROR_SQFS_ERROR_OUT_OF_BOUNDS => "location out of bounds",
+        SQFS_ERROR_SQFS_ERROR_SUPER_MAGIC => "wrong superblock magic",
+        SQFS_ERROR_SQFS_ERROR_SUPER_VERSION => "unsupported squashfs version",
+        SQFS_ERROR_SQFS_ERROR_SUPER_BLOCK_SIZE => "invalid block size",
+        SQFS_ERROR_SQFS_ERROR_NOT_DIR => "not a directory",
+        SQFS_ERROR_SQFS_ERROR_NO_ENTRY => "no such entry",
+        SQFS_ERROR_SQFS_ERROR_LINK_LOOP => "hard link loop",
+        SQFS_ERROR_SQFS_ERROR_NOT_FILE => "not a regular file",
+        SQFS_ERROR_SQFS_ERROR_ARG_INVALID => "invalid argument",
+        SQFS_ERROR_SQFS_ERROR_SEQUENCE => "wrong call sequence",
+        _ => "unknown error",
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::SQFS(code, what) => write!(f, "error {}: {} ({})", what, sqfs_error_name(*code), code),
+            Error::S3(msg) => write!(f, "S3 error: {}", msg),
+            Error::NOTFOUND(what) => write!(f, "{} not found", what),
+            Error::INVALID(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::IO(e)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::IO(inner) => inner,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_io_error() {
+        let e: io::Error = sqfs(SQFS_ERROR_SQFS_ERROR_NO_ENTRY, "looking up a").into();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        let e: io::Error = Error::IO(io::Error::new(io::ErrorKind::PermissionDenied, "x")).into();
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        let e: io::Error = Error::INVALID("bad".to_string()).into();
+        assert!(e.get_ref().unwrap().downcast_ref::<Error>().is_some());
+    }
+}
@@ -0,0 +1,486 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{RawFd, FromRawFd};
+use log::{info, debug, warn};
+use nix::errno::Errno;
+use nix::fcntl::{openat, OFlag, AtFlags};
+use nix::sys::stat::{Mode, SFlag, mkdirat, mknodat, fstatat, fchmodat, FchmodatFlags, utimensat, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{Uid, Gid, fchownat, FchownatFlags, symlinkat, linkat, LinkatFlags, unlinkat, UnlinkatFlags};
+
+// what to do with device node when not privileged to create it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SpecialMode {
+    SKIP,
+    SUBSTITUTE,
+}
+
+// what to do with entry failed path check: abort whole extract, or skip it
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PathMode {
+    STRICT,
+    LENIENT,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExtractOptions {
+    pub special: SpecialMode,
+    pub path_mode: PathMode,
+    pub links: LinkTracker,
+}
+
+impl Default for ExtractOptions {
+    fn default() -> Self {
+        Self {
+            special: SpecialMode::SKIP,
+            path_mode: PathMode::STRICT,
+            links: LinkTracker::default(),
+        }
+    }
+}
+
+impl ExtractOptions {
+
+    // in lenient mode entry failed path check is skipped with a warning
+    pub fn skip_unsafe(&self, res: Result<usize, Error>, path: &Path) -> Result<usize, Error> {
+        match res {
+            Err(e) if self.path_mode == PathMode::LENIENT && is_unsafe(&e) => {
+                warn!("skip unsafe entry {}, {}", path.display(), e);
+                Ok(0)
+            },
+            res => res,
+        }
+    }
+}
+
+// first copy of a hard linked inode, kept by its directory fd and name so
+// linking to it never resolves a path again
+#[derive(Clone)]
+struct FirstCopy {
+    dir: Rc<DirFd>,
+    root: PathBuf,
+    name: OsString,
+    path: PathBuf,
+}
+
+// squashfs keeps hardlinks as directory entries of one inode, remember where
+// such inode extracted first, across all extracts sharing the tracker,
+// clones share one map so cloned options still link to earlier copies
+#[derive(Clone, Default)]
+pub struct LinkTracker {
+    links: Rc<RefCell<HashMap<u32, FirstCopy>>>,
+}
+
+impl std::fmt::Debug for LinkTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LinkTracker").field("links", &self.links.borrow().len()).finish()
+    }
+}
+
+impl LinkTracker {
+
+    pub fn record(&self, ino: u32, dir: &SafeDir, name: &OsStr) {
+        self.links.borrow_mut().entry(ino).or_insert(FirstCopy {
+            dir: dir.fd.clone(),
+            root: dir.root.clone(),
+            name: name.to_os_string(),
+            path: dir.child_path(name),
+        });
+    }
+
+    pub fn lookup(&self, ino: u32) -> Option<PathBuf> {
+        self.links.borrow().get(&ino).map(|f| f.path.clone())
+    }
+
+    // recreate name in dir as hard link if inode extracted before, return true if linked
+    pub fn link(&self, ino: u32, dir: &SafeDir, name: &OsStr) -> Result<bool, Error> {
+        let first = match self.links.borrow().get(&ino) {
+            Some(first) if !(Rc::ptr_eq(&first.dir, &dir.fd) && first.name == name) => first.clone(),
+            _ => return Ok(false),
+        };
+        if fstatat(first.dir.0, first.name.as_os_str(), AtFlags::AT_SYMLINK_NOFOLLOW).is_err() {
+            // first copy gone, extract again and link to new one
+            self.links.borrow_mut().remove(&ino);
+            return Ok(false);
+        }
+        debug!("hard link {} to {}", dir.child_path(name).display(), first.path.display());
+        dir.hard_link(&first, name)?;
+        Ok(true)
+    }
+}
+
+// attributes restored on every extracted entry
+pub struct EntryMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: i64,
+    pub xattrs: Option<HashMap<Vec<u8>, Vec<u8>>>,
+    pub symlink: bool,
+}
+
+// squashfs keeps device number in linux new_encode_dev() format
+pub fn decode_devno(devno: u32) -> libc::dev_t {
+    let major = (devno & 0xfff00) >> 8;
+    let minor = (devno & 0xff) | ((devno >> 12) & 0xfff00);
+    libc::makedev(major, minor)
+}
+
+// marks entry refused by path check, apart from other invalid data such as
+// a corrupted block, which must never be skipped silently
+#[derive(Debug)]
+struct UnsafeEntry(String);
+
+impl std::fmt::Display for UnsafeEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UnsafeEntry {}
+
+fn unsafe_entry(s: String) -> Error {
+    Error::new(ErrorKind::InvalidData, UnsafeEntry(s))
+}
+
+// error raised for entry refused by path check, which lenient mode skips
+pub fn is_unsafe(e: &Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<UnsafeEntry>())
+}
+
+// entry name from archive must be a single normal path component
+pub fn check_name(name: &[u8]) -> Result<(), Error> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0) {
+        return Err(unsafe_entry(format!("unsafe entry name {:?}", String::from_utf8_lossy(name))));
+    }
+    Ok(())
+}
+
+// path inside archive, leading slash is fine as it is rooted at archive root
+pub fn check_path(path: &str) -> Result<(), Error> {
+    if path.contains('\0') || path.split('/').any(|c| c == "..") {
+        return Err(unsafe_entry(format!("unsafe archive path {:?}", path)));
+    }
+    Ok(())
+}
+
+// fd of an opened directory, shared by a destination and its sub directories
+struct DirFd(RawFd);
+
+impl Drop for DirFd {
+    fn drop(&mut self) {
+        let _ = nix::unistd::close(self.0);
+    }
+}
+
+// directory under extract destination, every entry is created relative to
+// its fd, a symlinked directory is only followed in lenient mode and then
+// resolved beneath the destination fd, never by path
+pub struct SafeDir {
+    fd: Rc<DirFd>,
+    root_fd: Rc<DirFd>,
+    root: PathBuf,
+    // relative to root, empty for root itself
+    rel: PathBuf,
+    path: PathBuf,
+}
+
+// struct open_how of openat2(2), not in every libc release
+#[repr(C)]
+struct OpenHow {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+const RESOLVE_BENEATH: u64 = 0x08;
+
+// open rel under dirfd, failing with EXDEV rather than leaving it through
+// .. or a symlink
+fn open_beneath(dirfd: RawFd, rel: &Path, flags: OFlag) -> Result<RawFd, Errno> {
+    let rel = std::ffi::CString::new(rel.as_os_str().as_bytes()).map_err(|_| Errno::EINVAL)?;
+    let how = OpenHow {
+        flags: flags.bits() as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS,
+    };
+    let ret = unsafe {
+        libc::syscall(libc::SYS_openat2, dirfd, rel.as_ptr(), &how as *const OpenHow, std::mem::size_of::<OpenHow>())
+    };
+    Errno::result(ret).map(|fd| fd as RawFd)
+}
+
+impl SafeDir {
+
+    // destination itself is given by user and trusted
+    pub fn open_root(path: &Path) -> Result<Self, Error> {
+        std::fs::create_dir_all(path)?;
+        let fd = openat(libc::AT_FDCWD, path, OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC, Mode::empty())?;
+        let fd = Rc::new(DirFd(fd));
+        Ok(Self {
+            fd: fd.clone(),
+            root_fd: fd,
+            root: std::fs::canonicalize(path)?,
+            rel: PathBuf::new(),
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn child_path(&self, name: &OsStr) -> PathBuf {
+        self.path.join(name)
+    }
+
+    fn is_symlink(&self, name: &OsStr) -> bool {
+        fstatat(self.fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW)
+            .map(|st| (st.st_mode & libc::S_IFMT) == libc::S_IFLNK)
+            .unwrap_or(false)
+    }
+
+    // remove whatever non-directory entry left at name by a previous extract,
+    // a symlink is replaced and never written through
+    fn remove_existing(&self, name: &OsStr) -> Result<(), Error> {
+        match fstatat(self.fd(), name, AtFlags::AT_SYMLINK_NOFOLLOW) {
+            Ok(st) if (st.st_mode & libc::S_IFMT) != libc::S_IFDIR => {
+                unlinkat(Some(self.fd()), name, UnlinkatFlags::NoRemoveDir)?;
+                Ok(())
+            },
+            Ok(_) => Ok(()),
+            Err(Errno::ENOENT) => Ok(()),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    fn fd(&self) -> RawFd {
+        self.fd.0
+    }
+
+    // open or create sub directory, in strict mode a symlink in its place is
+    // refused, in lenient mode it is followed as long as it stays inside
+    pub fn subdir(&self, name: &OsStr, mode: PathMode) -> Result<SafeDir, Error> {
+        check_name(name.as_bytes())?;
+        let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let path = self.child_path(name);
+        let rel = self.rel.join(name);
+
+        let fd = match openat(self.fd(), name, flags, Mode::empty()) {
+            Ok(fd) => fd,
+            Err(Errno::ENOENT) => {
+                // owner only until children are written, real mode applied after
+                mkdirat(self.fd(), name, Mode::S_IRWXU)?;
+                openat(self.fd(), name, flags, Mode::empty())?
+            },
+            Err(Errno::ELOOP) | Err(Errno::ENOTDIR) if self.is_symlink(name) => {
+                if mode == PathMode::STRICT {
+                    return Err(unsafe_entry(format!("{} is a symlink, not followed in strict mode", path.display())));
+                }
+                // resolved from destination fd by kernel, a swapped link can not lead out
+                let beneath = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+                match open_beneath(self.root_fd.0, &rel, beneath) {
+                    Ok(fd) => {
+                        debug!("follow symlink {} inside destination", path.display());
+                        fd
+                    },
+                    Err(Errno::EXDEV) => {
+                        return Err(unsafe_entry(format!("{} is a symlink pointing outside of {}", path.display(), self.root.display())));
+                    },
+                    Err(Errno::ENOSYS) => {
+                        return Err(unsafe_entry(format!("{} is a symlink, kernel can not resolve it beneath {}", path.display(), self.root.display())));
+                    },
+                    Err(e) => return Err(Error::from(e)),
+                }
+            },
+            Err(Errno::ENOTDIR) => {
+                self.remove_existing(name)?;
+                mkdirat(self.fd(), name, Mode::S_IRWXU)?;
+                openat(self.fd(), name, flags, Mode::empty())?
+            },
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        Ok(SafeDir {
+            fd: Rc::new(DirFd(fd)),
+            root_fd: self.root_fd.clone(),
+            root: self.root.clone(),
+            rel: rel,
+            path: path,
+        })
+    }
+
+    pub fn create_file(&self, name: &OsStr) -> Result<std::fs::File, Error> {
+        check_name(name.as_bytes())?;
+        self.remove_existing(name)?;
+        let flags = OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+        let fd = openat(self.fd(), name, flags, Mode::S_IRUSR | Mode::S_IWUSR)?;
+        Ok(unsafe { std::fs::File::from_raw_fd(fd) })
+    }
+
+    pub fn symlink(&self, target: &OsStr, name: &OsStr) -> Result<(), Error> {
+        check_name(name.as_bytes())?;
+        self.remove_existing(name)?;
+        symlinkat(target, Some(self.fd()), name)?;
+        Ok(())
+    }
+
+    // link name to first copy, both relative to their directory fds
+    fn hard_link(&self, first: &FirstCopy, name: &OsStr) -> Result<(), Error> {
+        check_name(name.as_bytes())?;
+        check_name(first.name.as_bytes())?;
+        if first.root != self.root {
+            return Err(unsafe_entry(format!("hard link target {} is outside of {}", first.path.display(), self.root.display())));
+        }
+        self.remove_existing(name)?;
+        linkat(Some(first.dir.0), first.name.as_os_str(), Some(self.fd()), name, LinkatFlags::NoSymlinkFollow)?;
+        Ok(())
+    }
+
+    // create fifo, socket or device node, return false if skipped for lack of privilege
+    pub fn mknod(&self, name: &OsStr, kind: SFlag, mode: u32, rdev: libc::dev_t, special: SpecialMode) -> Result<bool, Error> {
+        check_name(name.as_bytes())?;
+        self.remove_existing(name)?;
+        let path = self.child_path(name);
+        let perm = Mode::from_bits_truncate(mode & 0o7777);
+        match mknodat(self.fd(), name, kind, perm, rdev) {
+            Ok(_) => Ok(true),
+            Err(Errno::EPERM) => {
+                if special == SpecialMode::SUBSTITUTE {
+                    warn!("no privilege to create {:?} node {}, substitute with empty file", kind, path.display());
+                    self.create_file(name)?;
+                    return Ok(true);
+                }
+                warn!("no privilege to create {:?} node {}, skipped", kind, path.display());
+                Ok(false)
+            },
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    // set metadata of entry name, or of this directory itself with "."
+    pub fn set_metadata(&self, name: &OsStr, meta: &EntryMeta) -> Result<(), Error> {
+
+        let path = self.child_path(name);
+
+        // set uid/gid first, chown clears setuid/setgid bits
+        let res = fchownat(Some(self.fd()), name,
+                    Some(Uid::from_raw(meta.uid)), Some(Gid::from_raw(meta.gid)),
+                    FchownatFlags::NoFollowSymlink);
+        if let Err(e) = res {
+            // don't fail
+            info!("failed to set owner of {}, error: {}", path.display(), e);
+        }
+
+        // permission of symlink is meaningless and chmod follows it
+        if !meta.symlink {
+            let perm = Mode::from_bits_truncate(meta.mode & 0o7777);
+            fchmodat(Some(self.fd()), name, perm, FchmodatFlags::FollowSymlink)?;
+        }
+
+        if let Some(hashmap) = &meta.xattrs {
+            // no xattr call relative to dirfd, go through fd link in procfs,
+            // final component is still not followed
+            let fdpath = PathBuf::from(format!("/proc/self/fd/{}", self.fd())).join(name);
+            for (k, v) in hashmap.iter() {
+                let key = OsStr::from_bytes(k);
+                if let Err(e) = xattr::set(&fdpath, key, v) {
+                    if !meta.symlink {
+                        return Err(e);
+                    }
+                    // user namespace xattr is not permitted on symlink
+                    info!("failed to set xattr {:?} of symlink {}, error: {}", key, path.display(), e);
+                }
+            }
+        } else {
+            debug!("no xattr found");
+        }
+
+        // set last mod time at last, as any change above may touch it
+        let mtime = TimeSpec::new(meta.mtime as libc::time_t, 0);
+        utimensat(Some(self.fd()), name, &mtime, &mtime, UtimensatFlags::NoFollowSymlink)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name(b"file.txt").is_ok());
+        assert!(check_name(b"..file").is_ok());
+        assert!(check_name(b"").is_err());
+        assert!(check_name(b".").is_err());
+        assert!(check_name(b"..").is_err());
+        assert!(check_name(b"a/b").is_err());
+        assert!(check_name(b"/etc").is_err());
+        assert!(check_name(b"a\0b").is_err());
+    }
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("/usr/share/doc").is_ok());
+        assert!(check_path("").is_ok());
+        assert!(check_path("/usr/../etc").is_err());
+        assert!(check_path("..").is_err());
+        assert!(check_path("/a\0b").is_err());
+    }
+
+    #[test]
+    fn test_is_unsafe() {
+        assert!(is_unsafe(&check_path("/usr/../etc").unwrap_err()));
+        // corrupted data is not a path rejection
+        assert!(!is_unsafe(&Error::new(ErrorKind::InvalidData, "corrupted block")));
+    }
+
+    #[test]
+    fn test_subdir_symlink() {
+        let base = std::env::temp_dir().join(format!("s3archivefs-safedir-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(base.join("root/inside")).unwrap();
+        std::fs::create_dir_all(base.join("outside")).unwrap();
+        std::os::unix::fs::symlink("../outside", base.join("root/escape")).unwrap();
+        std::os::unix::fs::symlink("inside", base.join("root/alias")).unwrap();
+
+        let root = SafeDir::open_root(&base.join("root")).unwrap();
+        let e = root.subdir(OsStr::new("escape"), PathMode::LENIENT).err().unwrap();
+        assert!(is_unsafe(&e));
+        assert!(root.subdir(OsStr::new("alias"), PathMode::LENIENT).is_ok());
+        // strict mode never follows a symlinked directory
+        let e = root.subdir(OsStr::new("alias"), PathMode::STRICT).err().unwrap();
+        assert!(is_unsafe(&e));
+        let inside = root.subdir(OsStr::new("inside"), PathMode::STRICT).unwrap();
+        std::os::unix::fs::symlink("../../outside", base.join("root/inside/up")).unwrap();
+        assert!(is_unsafe(&inside.subdir(OsStr::new("up"), PathMode::LENIENT).err().unwrap()));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_link_tracker_shared() {
+        let base = std::env::temp_dir().join(format!("s3archivefs-links-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        let root = SafeDir::open_root(&base).unwrap();
+        root.create_file(OsStr::new("a")).unwrap();
+
+        let opts = ExtractOptions::default();
+        let cloned = opts.clone();
+        opts.links.record(7, &root, OsStr::new("a"));
+        assert_eq!(cloned.links.lookup(7), Some(base.join("a")));
+        assert!(!cloned.links.link(7, &root, OsStr::new("a")).unwrap());
+
+        // linked by fd and name, even once destination is reached by another path
+        let sub = root.subdir(OsStr::new("sub"), PathMode::STRICT).unwrap();
+        assert!(cloned.links.link(7, &sub, OsStr::new("b")).unwrap());
+        let (a, b) = (std::fs::metadata(base.join("a")).unwrap(), std::fs::metadata(base.join("sub/b")).unwrap());
+        assert_eq!(std::os::unix::fs::MetadataExt::ino(&a), std::os::unix::fs::MetadataExt::ino(&b));
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,287 @@
+use std::rc::Rc;
+use std::ptr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, BufRead};
+use libc::c_void;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use crate::bindings::*;
+use crate::ArchiveFs;
+
+// inode of an opened regular file, owned either alone or by its tree node
+pub struct Inode {
+    ptr: *mut sqfs_inode_generic_t,
+    node: *mut sqfs_tree_node_t,
+}
+
+impl Inode {
+
+    // take over inode allocated by libsquashfs
+    pub(crate) fn from_inode(ptr: *mut sqfs_inode_generic_t) -> Self {
+        Self {
+            ptr: ptr,
+            node: ptr::null_mut(),
+        }
+    }
+
+    // take over tree node, inode lives as long as the node
+    pub(crate) unsafe fn from_node(node: *mut sqfs_tree_node_t) -> Self {
+        Self {
+            ptr: (*node).inode,
+            node: node,
+        }
+    }
+
+    pub(crate) fn as_ptr(&self) -> *mut sqfs_inode_generic_t {
+        self.ptr
+    }
+
+    // tree node owning the inode, null when taken over alone
+    pub(crate) fn as_node(&self) -> *mut sqfs_tree_node_t {
+        self.node
+    }
+}
+
+impl Drop for Inode {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.node.is_null() {
+                sqfs_dir_tree_destroy(self.node);
+            } else if !self.ptr.is_null() {
+                sqfs_free(self.ptr as *mut c_void);
+            }
+        }
+    }
+}
+
+type BlockLoader = Box<dyn Fn(usize) -> Result<Option<Vec<u8>>, Error>>;
+
+// handle of a regular file inside archive, inode is resolved once on open,
+// reads decode one block at a time and keep it for following reads, data
+// missing in local cache is fetched through repo::CONTEXT so handle must be
+// used on thread it was set
+pub struct ArchiveFile {
+    path: String,
+    size: u64,
+    block_size: u64,
+    load: BlockLoader,
+    pos: u64,
+    block: Vec<u8>,
+    block_start: u64,
+}
+
+impl ArchiveFile {
+
+    pub(crate) fn new(path: &str, size: u64, block_size: u64, load: BlockLoader) -> Self {
+        Self {
+            path: path.to_string(),
+            size: size,
+            block_size: block_size,
+            load: load,
+            pos: 0,
+            block: Vec::new(),
+            block_start: 0,
+        }
+    }
+
+    // blocks are loaded from inode kept in the handle, no more path lookups
+    pub(crate) fn from_inode(arcfs: Rc<dyn ArchiveFs>, path: &str, inode: Inode, size: u64) -> Self {
+        let block_size = arcfs.get_sb().block_size as u64;
+        let load = Box::new(move |index: usize| arcfs.read_inode_block(&inode, index));
+        Self::new(path, size, block_size, load)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn block_loaded(&self) -> bool {
+        self.pos >= self.block_start && self.pos < self.block_start + self.block.len() as u64
+    }
+
+    // make sure block holding current position is loaded, unless at end of file
+    fn load_block(&mut self) -> Result<(), Error> {
+        if self.pos >= self.size || self.block_loaded() {
+            return Ok(());
+        }
+        let index = self.pos / self.block_size;
+        debug!("load block #{} of {}", index, self.path);
+        match (self.load)(index as usize)? {
+            Some(block) if !block.is_empty() => {
+                self.block = block;
+                self.block_start = index * self.block_size;
+            },
+            _ => {
+                return Err(Error::new(ErrorKind::UnexpectedEof, format!("block #{} of {} not found", index, self.path)));
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Read for ArchiveFile {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let data = self.fill_buf()?;
+        let n = std::cmp::min(data.len(), buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for ArchiveFile {
+    fn fill_buf(&mut self) -> Result<&[u8], Error> {
+        self.load_block()?;
+        if !self.block_loaded() {
+            return Ok(&[]);
+        }
+        let from = (self.pos - self.block_start) as usize;
+        Ok(&self.block[from..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+impl Seek for ArchiveFile {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let newpos = match pos {
+            SeekFrom::Start(off) => off as i128,
+            SeekFrom::End(off) => self.size as i128 + off as i128,
+            SeekFrom::Current(off) => self.pos as i128 + off as i128,
+        };
+        if newpos < 0 || newpos > u64::MAX as i128 {
+            return Err(Error::new(ErrorKind::InvalidInput, "invalid seek to a negative or overflowing position"));
+        }
+        self.pos = newpos as u64;
+        Ok(self.pos)
+    }
+}
+
+// tokio flavor of ArchiveFile, poll_read blocks the calling thread while a
+// block is decoded or fetched from remote, spawn_blocking is no option as the
+// handle is not Send, so run it on a current thread runtime or LocalSet and
+// keep other latency sensitive tasks off that thread
+pub struct AsyncArchiveFile {
+    inner: ArchiveFile,
+    seek_res: Option<Result<u64, Error>>,
+}
+
+impl AsyncArchiveFile {
+
+    pub fn new(inner: ArchiveFile) -> Self {
+        Self {
+            inner: inner,
+            seek_res: None,
+        }
+    }
+
+    pub fn into_inner(self) -> ArchiveFile {
+        self.inner
+    }
+}
+
+impl AsyncRead for AsyncArchiveFile {
+    fn poll_read(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        let n = this.inner.read(buf.initialize_unfilled())?;
+        buf.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncSeek for AsyncArchiveFile {
+    fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> Result<(), Error> {
+        let this = self.get_mut();
+        this.seek_res = Some(this.inner.seek(position));
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<u64, Error>> {
+        let this = self.get_mut();
+        match this.seek_res.take() {
+            Some(res) => Poll::Ready(res),
+            None => Poll::Ready(Ok(this.inner.pos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 10 bytes in blocks of 4, last block short like a fragment tail
+    fn sample() -> ArchiveFile {
+        let data: Vec<u8> = b"ab\ncdefg\nh".to_vec();
+        let size = data.len() as u64;
+        let load = Box::new(move |i: usize| {
+            let start = i * 4;
+            if start >= data.len() {
+                return Ok(None);
+            }
+            Ok(Some(data[start..std::cmp::min(start + 4, data.len())].to_vec()))
+        });
+        ArchiveFile::new("/sample", size, 4, load)
+    }
+
+    #[test]
+    fn test_read() {
+        let mut f = sample();
+        let mut buf = [0u8; 3];
+        assert_eq!(f.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"ab\n");
+        // stops at block boundary
+        assert_eq!(f.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], b'c');
+        let mut rest = Vec::new();
+        f.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"defg\nh");
+        assert_eq!(f.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_seek() {
+        let mut f = sample();
+        let mut buf = [0u8; 2];
+        assert_eq!(f.seek(SeekFrom::Start(5)).unwrap(), 5);
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ef");
+        assert_eq!(f.seek(SeekFrom::End(-1)).unwrap(), 9);
+        f.read_exact(&mut buf[..1]).unwrap();
+        assert_eq!(buf[0], b'h');
+        assert_eq!(f.seek(SeekFrom::Current(-8)).unwrap(), 2);
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"\nc");
+        assert_eq!(f.seek(SeekFrom::Current(-5)).unwrap_err().kind(), ErrorKind::InvalidInput);
+        // past end reads nothing
+        assert_eq!(f.seek(SeekFrom::End(3)).unwrap(), 13);
+        assert_eq!(f.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_buf_read() {
+        let mut f = sample();
+        assert_eq!(f.fill_buf().unwrap(), b"ab\nc");
+        f.consume(1);
+        assert_eq!(f.fill_buf().unwrap(), b"b\nc");
+        f.consume(3);
+        let mut line = String::new();
+        f.read_line(&mut line).unwrap();
+        assert_eq!(line, "defg\n");
+        let lines: Vec<String> = f.lines().map(|l| l.unwrap()).collect();
+        assert_eq!(lines, vec!["h"]);
+    }
+
+    #[test]
+    fn test_missing_block() {
+        let load = Box::new(|_: usize| Ok(None));
+        let mut f = ArchiveFile::new("/gone", 8, 4, load);
+        assert_eq!(f.read(&mut [0u8; 4]).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,240 @@
+use regex::Regex;
+use crate::entry::{Entry, FileType};
+
+// all set conditions must hold, matched on metadata only so no data
+// block is ever fetched
+#[derive(Debug, Default)]
+pub struct Filter {
+    // glob on file name
+    pub name: Option<String>,
+    // on full path with leading slash
+    pub regex: Option<Regex>,
+    pub file_type: Option<FileType>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // mtime strictly after newer and strictly before older
+    pub newer: Option<i64>,
+    pub older: Option<i64>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // xattr key that must be present
+    pub xattr: Option<String>,
+}
+
+impl Filter {
+
+    pub fn matches(&self, entry: &Entry) -> bool {
+        let m = &entry.metadata;
+        if let Some(name) = &self.name {
+            if !glob_match(name, entry.name()) {
+                return false;
+            }
+        }
+        if let Some(re) = &self.regex {
+            if !re.is_match(&format!("/{}", entry.path)) {
+                return false;
+            }
+        }
+        if self.file_type.map_or(false, |t| t != m.file_type)
+                || self.min_size.map_or(false, |s| m.size < s)
+                || self.max_size.map_or(false, |s| m.size > s)
+                || self.newer.map_or(false, |t| m.mtime <= t)
+                || self.older.map_or(false, |t| m.mtime >= t)
+                || self.uid.map_or(false, |u| m.uid != u)
+                || self.gid.map_or(false, |g| m.gid != g) {
+            return false;
+        }
+        if let Some(key) = &self.xattr {
+            if !m.xattrs.contains_key(key.as_bytes()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+// shell style glob, * ? [a-z] [!a-z] and backslash escape, * matches /
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let n: Vec<char> = name.chars().collect();
+    let (mut pi, mut ni) = (0, 0);
+    // where last * was and what it has consumed so far, to backtrack
+    let mut star: Option<(usize, usize)> = None;
+
+    while ni < n.len() {
+        if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+            continue;
+        }
+        if pi < p.len() {
+            if let Some(next) = glob_step(&p, pi, n[ni]) {
+                pi = next;
+                ni += 1;
+                continue;
+            }
+        }
+        match star {
+            Some((sp, sn)) => {
+                pi = sp + 1;
+                ni = sn + 1;
+                star = Some((sp, sn + 1));
+            },
+            None => return false,
+        }
+    }
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+    pi == p.len()
+}
+
+// position after p[pi] if it matches c
+fn glob_step(p: &[char], pi: usize, c: char) -> Option<usize> {
+    match p[pi] {
+        '?' => Some(pi + 1),
+        '[' => match glob_class(p, pi, c) {
+            Some((true, next)) => Some(next),
+            Some((false, _)) => None,
+            // no closing bracket, a literal [
+            None => (c == '[').then_some(pi + 1),
+        },
+        '\\' if pi + 1 < p.len() => (p[pi + 1] == c).then_some(pi + 2),
+        x => (x == c).then_some(pi + 1),
+    }
+}
+
+fn glob_class(p: &[char], pi: usize, c: char) -> Option<(bool, usize)> {
+    let mut i = pi + 1;
+    let negate = i < p.len() && (p[i] == '!' || p[i] == '^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < p.len() {
+        // ] right after [ is a member
+        if p[i] == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            if p[i] <= c && c <= p[i + 2] {
+                matched = true;
+            }
+            i += 3;
+        } else {
+            if p[i] == c {
+                matched = true;
+            }
+            i += 1;
+        }
+    }
+    None
+}
+
+// bytes with optional k, M, G or T suffix, 1024 based
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let (num, mul) = match s.chars().last() {
+        Some('k') | Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        Some('T') => (&s[..s.len() - 1], 1 << 40),
+        _ => (s, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(mul))
+        .ok_or_else(|| format!("invalid size {}", s))
+}
+
+pub fn parse_type(s: &str) -> Result<FileType, String> {
+    match s {
+        "f" | "file" => Ok(FileType::REGULAR),
+        "d" | "dir" => Ok(FileType::DIRECTORY),
+        "l" | "symlink" => Ok(FileType::SYMLINK),
+        "b" | "blockdev" => Ok(FileType::BLOCKDEV),
+        "c" | "chardev" => Ok(FileType::CHARDEV),
+        "p" | "fifo" => Ok(FileType::FIFO),
+        "s" | "socket" => Ok(FileType::SOCKET),
+        _ => Err(format!("invalid type {}, expect f|d|l|b|c|p|s", s)),
+    }
+}
+
+// seconds since epoch, or UTC date as YYYY-MM-DD with optional HH:MM[:SS]
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    let err = || format!("invalid time {}, expect YYYY-MM-DD[ HH:MM[:SS]] or seconds since epoch", s);
+    let s = s.trim();
+    if let Ok(t) = s.parse::<i64>() {
+        return Ok(t);
+    }
+
+    let (date, time) = match s.split_once(|c: char| c == ' ' || c == 'T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    let d: Vec<i64> = date.split('-').map(|x| x.parse::<i64>()).collect::<Result<_, _>>().map_err(|_| err())?;
+    if d.len() != 3 || !(1..=12).contains(&d[1]) || !(1..=31).contains(&d[2]) {
+        return Err(err());
+    }
+    let mut secs = 0;
+    if let Some(time) = time {
+        let t: Vec<i64> = time.split(':').map(|x| x.parse::<i64>()).collect::<Result<_, _>>().map_err(|_| err())?;
+        if t.len() < 2 || t.len() > 3 || t.iter().any(|x| *x < 0) || t[0] > 23 || t[1] > 59 || t.get(2).map_or(false, |x| *x > 60) {
+            return Err(err());
+        }
+        secs = t[0] * 3600 + t[1] * 60 + t.get(2).unwrap_or(&0);
+    }
+    Ok(days_from_civil(d[0], d[1], d[2]) * 86400 + secs)
+}
+
+// days since epoch of a proleptic gregorian date
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("*.log", "app.log"));
+        assert!(!glob_match("*.log", "app.log.1"));
+        assert!(glob_match("a?c*", "abcdef"));
+        assert!(glob_match("[!x]b[a-c]", "ybc"));
+        assert!(!glob_match("[!x]b[a-c]", "xbc"));
+        assert!(glob_match("*a*b*", "xxaxxbxx"));
+        assert!(glob_match("\\*", "*"));
+        assert!(!glob_match("\\*", "a"));
+        assert!(glob_match("[]]", "]"));
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("100M"), Ok(100 << 20));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("99999999999T").is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("1970-01-01"), Ok(0));
+        assert_eq!(parse_time("2000-02-29 01:01"), Ok(951782400 + 3660));
+        assert_eq!(parse_time("2000-02-29T01:01:01"), Ok(951782400 + 3661));
+        assert_eq!(parse_time("1234"), Ok(1234));
+        assert!(parse_time("2020-13-01").is_err());
+        assert!(parse_time("2020-01-01 25:00").is_err());
+    }
+}
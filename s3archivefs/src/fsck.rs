@@ -0,0 +1,162 @@
+use crate::bindings::sqfs_super_t;
+
+// "hsqs" little endian
+const SQFS_MAGIC: u32 = 0x73717368;
+const MIN_BLOCK_SIZE: u32 = 4096;
+const MAX_BLOCK_SIZE: u32 = 1 << 20;
+// metadata block before compression
+const META_BLOCK_SIZE: u64 = 8192;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Area {
+    SUPERBLOCK,
+    // id, fragment, export or xattr table
+    TABLE,
+    // directory, inode or data of an entry in the tree
+    ENTRY,
+}
+
+impl Area {
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Area::SUPERBLOCK => "superblock",
+            Area::TABLE => "table",
+            Area::ENTRY => "entry",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub area: Area,
+    // table name, or path of entry with leading slash
+    pub what: String,
+    pub detail: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+    pub directories: usize,
+    pub files: usize,
+    pub symlinks: usize,
+    pub others: usize,
+    pub data_blocks: usize,
+    pub sparse_blocks: usize,
+    pub fragment_blocks: usize,
+    // decompressed size of all data and fragment blocks read
+    pub data_bytes: u64,
+}
+
+impl Report {
+
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn push(&mut self, area: Area, what: &str, detail: String) {
+        self.problems.push(Problem {
+            area: area,
+            what: what.to_string(),
+            detail: detail,
+        });
+    }
+}
+
+// sanity of fields libsquashfs takes as is, file_size is size of archive
+pub fn check_superblock(sb: &sqfs_super_t, file_size: u64) -> Vec<String> {
+    let mut errors = Vec::new();
+    if sb.magic != SQFS_MAGIC {
+        errors.push(format!("bad magic {:#x}", sb.magic));
+    }
+    if sb.version_major != 4 || sb.version_minor != 0 {
+        errors.push(format!("unsupported version {}.{}", sb.version_major, sb.version_minor));
+    }
+    if !sb.block_size.is_power_of_two() || sb.block_size < MIN_BLOCK_SIZE || sb.block_size > MAX_BLOCK_SIZE {
+        errors.push(format!("invalid block size {}", sb.block_size));
+    } else if 1u32.checked_shl(sb.block_log as u32) != Some(sb.block_size) {
+        errors.push(format!("block log {} does not match block size {}", sb.block_log, sb.block_size));
+    }
+    if sb.compression_id == 0 || sb.compression_id > 6 {
+        errors.push(format!("unknown compressor {}", sb.compression_id));
+    }
+    if sb.bytes_used > file_size {
+        errors.push(format!("bytes used {} beyond archive size {}", sb.bytes_used, file_size));
+    }
+
+    // tables in the order they are written, optional ones are all ones when absent
+    let tables = [
+        ("inode table", Some(sb.inode_table_start)),
+        ("directory table", Some(sb.directory_table_start)),
+        ("fragment table", Some(sb.fragment_table_start).filter(|s| *s != u64::MAX)),
+        ("export table", Some(sb.export_table_start).filter(|s| *s != u64::MAX)),
+        ("id table", Some(sb.id_table_start)),
+        ("xattr table", Some(sb.xattr_id_table_start).filter(|s| *s != u64::MAX)),
+    ];
+    let mut last: Option<(&str, u64)> = None;
+    for (name, start) in tables.iter() {
+        let start = match start {
+            Some(start) => *start,
+            None => continue,
+        };
+        if start >= sb.bytes_used {
+            errors.push(format!("{} at {} beyond bytes used {}", name, start, sb.bytes_used));
+        }
+        if let Some((prev, prev_start)) = last {
+            if start <= prev_start {
+                errors.push(format!("{} at {} not after {} at {}", name, start, prev, prev_start));
+            }
+        }
+        last = Some((name, start));
+    }
+
+    let root_block = sb.root_inode_ref >> 16;
+    let root_offset = sb.root_inode_ref & 0xffff;
+    if root_offset >= META_BLOCK_SIZE
+            || sb.inode_table_start.saturating_add(root_block) >= sb.directory_table_start {
+        errors.push(format!("root inode reference {:#x} outside inode table", sb.root_inode_ref));
+    }
+    if sb.inode_count == 0 {
+        errors.push("no inode".to_string());
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> sqfs_super_t {
+        let mut sb: sqfs_super_t = unsafe { std::mem::zeroed() };
+        sb.magic = SQFS_MAGIC;
+        sb.version_major = 4;
+        sb.block_size = 131072;
+        sb.block_log = 17;
+        sb.compression_id = 1;
+        sb.inode_count = 3;
+        sb.inode_table_start = 1000;
+        sb.directory_table_start = 1200;
+        sb.fragment_table_start = 1300;
+        sb.export_table_start = u64::MAX;
+        sb.id_table_start = 1400;
+        sb.xattr_id_table_start = u64::MAX;
+        sb.root_inode_ref = 0x20;
+        sb.bytes_used = 1500;
+        sb
+    }
+
+    #[test]
+    fn test_check_superblock() {
+        assert!(check_superblock(&sample(), 4096).is_empty());
+
+        let mut sb = sample();
+        sb.block_log = 16;
+        sb.id_table_start = 1250;
+        let errors = check_superblock(&sb, 1000);
+        assert_eq!(errors.len(), 3);
+        assert!(errors[0].starts_with("block log"));
+        assert!(errors[1].starts_with("bytes used"));
+        assert!(errors[2].starts_with("id table"));
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{Write, BufRead, Error};
+use std::collections::HashSet;
+use log::{info, debug, warn};
+use regex::bytes::Regex;
+use crate::repo::Local;
+use crate::find::Filter;
+
+pub enum Pattern {
+    REGEX(Regex),
+    FIXED(Vec<u8>),
+}
+
+impl Pattern {
+
+    pub fn is_match(&self, line: &[u8]) -> bool {
+        match self {
+            Pattern::REGEX(re) => re.is_match(line),
+            Pattern::FIXED(s) => s.is_empty() || line.windows(s.len()).any(|w| w == s.as_slice()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Output {
+    // path:line, with line number if set
+    LINES(bool),
+    // path of files with a match
+    FILES,
+    // path:count of matching lines
+    COUNT,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Options {
+    pub output: Output,
+    // max compressed bytes to fetch from remote, None for unlimited
+    pub budget: Option<usize>,
+    pub concurrency: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub files: usize,
+    pub matched_files: usize,
+    pub matched_lines: usize,
+    // compressed bytes of candidates not in local cache before search
+    pub fetch_bytes: usize,
+    // candidates left out, fetching them would exceed budget
+    pub skipped: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+// lines of reader matching pattern, f gets line number from 1 and line
+// without newline and stops the scan by returning false
+pub fn grep_reader<R: BufRead>(mut reader: R, pattern: &Pattern, mut f: impl FnMut(usize, &[u8]) -> bool) -> Result<usize, Error> {
+    let mut count = 0;
+    let mut lineno = 0;
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(count);
+        }
+        lineno += 1;
+        let line = buf.strip_suffix(b"\n").unwrap_or(&buf[..]);
+        if pattern.is_match(line) {
+            count += 1;
+            if !f(lineno, line) {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+// search regular files under path chosen by filter, candidates are picked
+// on metadata and their blocks fetched in archive offset order, coalesced,
+// before any is read, budget limits bytes fetched from remote
+pub async fn grep<W: Write>(local: &Local, path: &str, filter: &Filter, pattern: &Pattern,
+        opts: &Options, out: &mut W) -> Result<Summary, Error> {
+
+    let mut summary = Summary::default();
+
+    let mut files = Vec::new();
+    for entry in local.find(path, filter)? {
+        match entry {
+            Ok(entry) if entry.metadata.is_file() => files.push((entry.path, ())),
+            Ok(_) => {},
+            Err(e) => warn!("failed to read entry under {}, {}", path, e),
+        }
+    }
+    let (candidates, unresolved) = local.extents_by_offset(files);
+    for (filepath, e) in unresolved {
+        warn!("failed to resolve blocks of {}, {}", filepath, e);
+        summary.failed.push(filepath);
+    }
+
+    let mut selected = Vec::new();
+    let mut ranges = Vec::new();
+    // fragment blocks are shared by small files, count each extent once
+    let mut counted = HashSet::new();
+    for (filepath, _, extents) in candidates {
+        let extents: Vec<(usize, usize)> = extents.into_iter().filter(|e| !counted.contains(e)).collect();
+        let mut missing = 0;
+        for (offset, size) in extents.iter() {
+            if !local.is_cached(*offset, *size)? {
+                missing += size;
+            }
+        }
+        if opts.budget.map_or(false, |b| summary.fetch_bytes + missing > b) {
+            debug!("skip {}, {} bytes to fetch over budget", filepath, missing);
+            summary.skipped.push(filepath);
+            continue;
+        }
+        summary.fetch_bytes += missing;
+        counted.extend(extents.iter().cloned());
+        ranges.extend(extents);
+        selected.push(filepath);
+    }
+    info!("{} files to search, {} bytes to fetch, {} skipped over budget",
+        selected.len(), summary.fetch_bytes, summary.skipped.len());
+
+    if summary.fetch_bytes > 0 {
+        local.prefetch_ahead(ranges, opts.concurrency).await?;
+    }
+
+    for filepath in selected {
+        summary.files += 1;
+        let file = match local.open(&filepath) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("failed to open {}, {}", filepath, e);
+                summary.failed.push(filepath);
+                continue;
+            },
+        };
+
+        let mut res = Ok(());
+        let mut binary = false;
+        let count = grep_reader(file, pattern, |lineno, line| {
+            match opts.output {
+                Output::LINES(numbered) => {
+                    if line.contains(&0) {
+                        binary = true;
+                        return false;
+                    }
+                    let mut line_out = format!("/{}:", filepath).into_bytes();
+                    if numbered {
+                        line_out.extend_from_slice(format!("{}:", lineno).as_bytes());
+                    }
+                    line_out.extend_from_slice(line);
+                    line_out.push(b'\n');
+                    res = out.write_all(&line_out);
+                    res.is_ok()
+                },
+                // one match is enough to list file
+                Output::FILES => false,
+                Output::COUNT => true,
+            }
+        });
+        res?;
+
+        let count = match count {
+            Ok(count) => count,
+            Err(e) => {
+                warn!("failed to read {}, {}", filepath, e);
+                summary.failed.push(filepath);
+                continue;
+            },
+        };
+        if count > 0 {
+            summary.matched_files += 1;
+            summary.matched_lines += count;
+        }
+        match opts.output {
+            Output::LINES(_) if binary => writeln!(out, "binary file /{} matches", filepath)?,
+            Output::FILES if count > 0 => writeln!(out, "/{}", filepath)?,
+            Output::COUNT => writeln!(out, "/{}:{}", filepath, count)?,
+            _ => {},
+        }
+    }
+    out.flush()?;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grep_reader() {
+        let text = b"foo\nbar\nfoobar\n\nlast foo";
+        let pattern = Pattern::FIXED(b"foo".to_vec());
+        let mut lines = Vec::new();
+        let count = grep_reader(&text[..], &pattern, |n, l| { lines.push((n, l.to_vec())); true }).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(lines, vec![(1, b"foo".to_vec()), (3, b"foobar".to_vec()), (5, b"last foo".to_vec())]);
+
+        let pattern = Pattern::REGEX(Regex::new("^b").unwrap());
+        assert_eq!(grep_reader(&text[..], &pattern, |_, _| false).unwrap(), 1);
+    }
+}
@@ -1,12 +1,12 @@
 use libc::{c_void, c_int, off_t, lseek};
-use log::{debug, error};
+use log::{debug, warn, error};
 use crate::bindings::*;
-use crate::repo::{CONTEXT, HoleDetectMode};
+use crate::repo::{CONTEXT, HoleDetectMode, TRACE_HIT, first_zero_piece};
 
 pub type WriteAtType = unsafe extern "C" fn(*mut sqfs_file_t, sqfs_u64, *const c_void, usize) -> c_int;
 pub type ReadAtType = unsafe extern "C" fn(*mut sqfs_file_t, sqfs_u64, *mut c_void, usize) -> c_int;
 
-fn is_zero(buf: &[u8]) -> bool {
+pub(crate) fn is_zero(buf: &[u8]) -> bool {
     let (prefix, aligned, suffix) = unsafe { buf.align_to::<u128>() };
 
     prefix.iter().all(|&x| x == 0)
@@ -35,11 +35,12 @@ pub extern "C" fn archive_read_at(base: *mut sqfs_file_t, offset: sqfs_u64,
     unsafe {
 
         debug!("read_at offset {}, size {}", offset, size);
-        let (hdmode, meta_area) = CONTEXT.with(move |c| {
+        let (hdmode, meta_area, piece_log) = CONTEXT.with(move |c| {
             let local = c.borrow();
             (
                 local.as_ref().unwrap().hdmode(),
-                local.as_ref().unwrap().is_metadata_area(offset as usize)
+                local.as_ref().unwrap().is_metadata_area(offset as usize),
+                local.as_ref().unwrap().piece_log()
             )
         });
 
@@ -57,12 +58,12 @@ pub extern "C" fn archive_read_at(base: *mut sqfs_file_t, offset: sqfs_u64,
             if ret != 0 {
                 return ret;
             }
+            // any piece all zero is missing, not only whole buffer
             let data = std::slice::from_raw_parts(buffer as *const u8, size as usize);
-            if is_zero(data) {
-                new_offset = offset as off_t;
-            } else {
-                new_offset = offset as off_t + size as off_t;
-            }
+            new_offset = match first_zero_piece(data, offset as usize, piece_log) {
+                Some(start) => start as off_t,
+                None => offset as off_t + size as off_t,
+            };
         } else {
             new_offset = lseek(fd, offset as off_t, libc::SEEK_HOLE);
             if new_offset < 0 {
@@ -91,10 +92,19 @@ pub extern "C" fn archive_read_at(base: *mut sqfs_file_t, offset: sqfs_u64,
                 let local = c.borrow();
                 local.as_ref().unwrap().request_remote_data_task(start_offset, req_size)
             });
-            if res.is_err() {
-                error!("failed to request remote data on S3, err: {:?}", res);
+            if let Err(e) = res {
+                if e.kind() == std::io::ErrorKind::NotFound {
+                    warn!("cache miss at offset {}, size {}: {}", start_offset, req_size, e);
+                } else {
+                    error!("failed to request remote data on S3, err: {:?}", e);
+                }
                 return SQFS_ERROR_SQFS_ERROR_IO;
             }
+        } else {
+            CONTEXT.with(|c| {
+                let local = c.borrow();
+                local.as_ref().unwrap().trace_event(TRACE_HIT, offset as usize, size)
+            });
         }
 
         // it's actually read data
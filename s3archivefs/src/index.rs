@@ -0,0 +1,381 @@
+use std::ptr;
+use std::collections::HashMap;
+use std::time::Instant;
+use libc::c_void;
+use log::{info, debug, warn};
+use crate::bindings::*;
+use crate::error;
+
+const MAGIC: &[u8; 8] = b"S3AFSIDX";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct IndexEntry {
+    // block start relative to inode table in upper bits, offset in lower 16 bits
+    pub inode_ref: u64,
+    pub inode_num: u32,
+    pub inode_type: u16,
+}
+
+impl IndexEntry {
+
+    pub fn is_dir(&self) -> bool {
+        self.inode_type as u32 == SQFS_INODE_TYPE_SQFS_INODE_DIR
+            || self.inode_type as u32 == SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR
+    }
+}
+
+pub enum Lookup {
+    FOUND(IndexEntry),
+    // path is normal but not in archive
+    MISSING,
+    // path has . or .. components, resolve it the slow way
+    UNKNOWN,
+}
+
+// path to inode reference of every entry, keys are paths without leading
+// slash, "" for root, built once from the directory table
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathIndex {
+    entries: HashMap<String, IndexEntry>,
+    // inode number to its smallest path, derived from entries
+    paths: HashMap<u32, String>,
+    bytes_used: u64,
+    mod_time: u32,
+    inode_count: u32,
+}
+
+impl PathIndex {
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn lookup(&self, path: &str) -> Lookup {
+        let mut key = String::with_capacity(path.len());
+        for c in path.split('/').filter(|c| !c.is_empty()) {
+            if c == "." || c == ".." {
+                return Lookup::UNKNOWN;
+            }
+            if !key.is_empty() {
+                key.push('/');
+            }
+            key.push_str(c);
+        }
+        match self.entries.get(&key) {
+            Some(e) => Lookup::FOUND(*e),
+            None => Lookup::MISSING,
+        }
+    }
+
+    // smallest path of an inode number, more than one with hard links
+    pub fn path_of(&self, ino: u32) -> Option<String> {
+        self.paths.get(&ino).cloned()
+    }
+
+    // reverse map of entries, run once they are all in
+    fn index_paths(&mut self) {
+        self.paths.clear();
+        for (path, e) in self.entries.iter() {
+            match self.paths.get(&e.inode_num) {
+                Some(p) if p <= path => {},
+                _ => {
+                    self.paths.insert(e.inode_num, path.clone());
+                },
+            }
+        }
+    }
+
+    // index built from another archive (or another build of it) is stale
+    pub fn matches(&self, sb: &sqfs_super_t) -> bool {
+        self.bytes_used == sb.bytes_used
+            && self.mod_time == sb.modification_time
+            && self.inode_count == sb.inode_count
+    }
+
+    // load index from cache file if it matches the archive, otherwise build
+    // it and try to save it there
+    pub(crate) unsafe fn open(file: *mut sqfs_file_t, cmp: *mut sqfs_compressor_t,
+            sb: *const sqfs_super_t, cache: Option<&str>) -> error::Result<Self> {
+
+        if let Some(cache) = cache {
+            match std::fs::read(cache) {
+                Ok(buf) => match Self::decode(&buf) {
+                    Some(idx) if idx.matches(&*sb) => {
+                        info!("loaded path index {} with {} entries", cache, idx.len());
+                        return Ok(idx);
+                    },
+                    _ => warn!("path index {} is stale or corrupted, rebuild", cache),
+                },
+                Err(e) => debug!("no path index at {}, {}", cache, e),
+            }
+        }
+
+        let idx = Self::build(file, cmp, sb)?;
+
+        if let Some(cache) = cache {
+            if let Err(e) = std::fs::write(cache, idx.encode()) {
+                warn!("failed to save path index {}, {}", cache, e);
+            }
+        }
+        Ok(idx)
+    }
+
+    // walk the directory table from root, reading headers and entries
+    // directly so the inode reference of every entry is kept
+    pub(crate) unsafe fn build(file: *mut sqfs_file_t, cmp: *mut sqfs_compressor_t,
+            sb: *const sqfs_super_t) -> error::Result<Self> {
+
+        let now = Instant::now();
+        let mut idx = Self {
+            entries: HashMap::new(),
+            paths: HashMap::new(),
+            bytes_used: (*sb).bytes_used,
+            mod_time: (*sb).modification_time,
+            inode_count: (*sb).inode_count,
+        };
+
+        let dm = sqfs_meta_reader_create(file, cmp, (*sb).directory_table_start, (*sb).bytes_used);
+        if dm.is_null() {
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating directory table reader"));
+        }
+
+        let root = IndexEntry {
+            inode_ref: (*sb).root_inode_ref,
+            inode_num: 0,
+            inode_type: SQFS_INODE_TYPE_SQFS_INODE_DIR as u16,
+        };
+        idx.entries.insert(String::new(), root);
+
+        let mut stack = vec![(String::new(), root.inode_ref)];
+        let mut ret = Ok(());
+        while let Some((path, inode_ref)) = stack.pop() {
+            if let Err(e) = idx.index_dir(file, cmp, sb, dm, &path, inode_ref, &mut stack) {
+                ret = Err(e);
+                break;
+            }
+        }
+        crate::sqfs_destroy(dm);
+        ret?;
+        idx.index_paths();
+
+        info!("path index built with {} entries, elapsed: {:?}", idx.len(), now.elapsed());
+        Ok(idx)
+    }
+
+    unsafe fn index_dir(&mut self, file: *mut sqfs_file_t, cmp: *mut sqfs_compressor_t,
+            sb: *const sqfs_super_t, dm: *mut sqfs_meta_reader_t, path: &str, inode_ref: u64,
+            stack: &mut Vec<(String, u64)>) -> error::Result<()> {
+
+        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
+        let ret = read_inode(file, cmp, sb, inode_ref, &mut inode);
+        if ret != 0 {
+            return Err(error::sqfs(ret, &format!("reading inode of /{}", path)));
+        }
+        if path.is_empty() {
+            if let Some(root) = self.entries.get_mut(path) {
+                root.inode_num = (*inode).base.inode_number;
+            }
+        }
+
+        let entries = &mut self.entries;
+        let res = dir_entries(dm, sb, inode, &format!("/{}", path), &mut |name, e| {
+            let child = crate::entry::join_path(path, &String::from_utf8_lossy(name));
+            if e.is_dir() {
+                stack.push((child.clone(), e.inode_ref));
+            }
+            entries.insert(child, e);
+            true
+        });
+        sqfs_free(inode as *mut c_void);
+        res
+    }
+
+    // little endian, header then one record per entry
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(32 + self.entries.len() * 48);
+        buf.extend_from_slice(MAGIC);
+        buf.extend_from_slice(&VERSION.to_le_bytes());
+        buf.extend_from_slice(&self.bytes_used.to_le_bytes());
+        buf.extend_from_slice(&self.mod_time.to_le_bytes());
+        buf.extend_from_slice(&self.inode_count.to_le_bytes());
+        buf.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        for (path, e) in self.entries.iter() {
+            buf.extend_from_slice(&e.inode_ref.to_le_bytes());
+            buf.extend_from_slice(&e.inode_num.to_le_bytes());
+            buf.extend_from_slice(&e.inode_type.to_le_bytes());
+            buf.extend_from_slice(&(path.len() as u32).to_le_bytes());
+            buf.extend_from_slice(path.as_bytes());
+        }
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        let mut pos = 0;
+        let mut take = |n: usize| take_bytes(buf, &mut pos, n);
+
+        if take(8)? != MAGIC {
+            return None;
+        }
+        if u32::from_le_bytes(take(4)?.try_into().ok()?) != VERSION {
+            return None;
+        }
+        let bytes_used = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let mod_time = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let inode_count = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let count = u64::from_le_bytes(take(8)?.try_into().ok()?);
+
+        let mut entries = HashMap::new();
+        for _ in 0..count {
+            let inode_ref = u64::from_le_bytes(take(8)?.try_into().ok()?);
+            let inode_num = u32::from_le_bytes(take(4)?.try_into().ok()?);
+            let inode_type = u16::from_le_bytes(take(2)?.try_into().ok()?);
+            let len = u32::from_le_bytes(take(4)?.try_into().ok()?) as usize;
+            let path = String::from_utf8(take(len)?.to_vec()).ok()?;
+            entries.insert(path, IndexEntry {
+                inode_ref: inode_ref,
+                inode_num: inode_num,
+                inode_type: inode_type,
+            });
+        }
+
+        let mut idx = Self {
+            entries: entries,
+            paths: HashMap::new(),
+            bytes_used: bytes_used,
+            mod_time: mod_time,
+            inode_count: inode_count,
+        };
+        idx.index_paths();
+        Some(idx)
+    }
+}
+
+fn take_bytes<'a>(buf: &'a [u8], pos: &mut usize, n: usize) -> Option<&'a [u8]> {
+    let s = buf.get(*pos..*pos + n)?;
+    *pos += n;
+    Some(s)
+}
+
+// walk entries of a directory inode straight from the directory table, inode
+// number of each comes from its header plus the entry diff, so no inode is
+// read, f returns false to stop early
+pub(crate) unsafe fn dir_entries(dm: *mut sqfs_meta_reader_t, sb: *const sqfs_super_t,
+        dir: *const sqfs_inode_generic_t, what: &str,
+        f: &mut dyn FnMut(&[u8], IndexEntry) -> bool) -> error::Result<()> {
+
+    let (start_block, offset, size) = match (*dir).base.type_ as u32 {
+        SQFS_INODE_TYPE_SQFS_INODE_DIR => {
+            let d = (*dir).data.dir;
+            (d.start_block as u64, d.offset as usize, d.size as usize)
+        },
+        SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => {
+            let d = (*dir).data.dir_ext;
+            (d.start_block as u64, d.offset as usize, d.size as usize)
+        },
+        _ => return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_NOT_DIR, &format!("reading {}", what))),
+    };
+
+    // size on disk counts 3 extra bytes for . and ..
+    let mut remain = size.saturating_sub(3);
+    if remain == 0 {
+        return Ok(());
+    }
+
+    let ret = sqfs_meta_reader_seek(dm, (*sb).directory_table_start + start_block, offset);
+    if ret != 0 {
+        return Err(error::sqfs(ret, &format!("seeking directory {}", what)));
+    }
+
+    let hdr_size = std::mem::size_of::<sqfs_dir_header_t>();
+    let ent_size = std::mem::size_of::<sqfs_dir_entry_t>();
+    while remain > hdr_size {
+        let mut hdr: sqfs_dir_header_t = std::mem::zeroed();
+        let ret = sqfs_meta_reader_read_dir_header(dm, &mut hdr);
+        if ret != 0 {
+            return Err(error::sqfs(ret, &format!("reading directory header of {}", what)));
+        }
+        remain -= hdr_size;
+
+        // count is stored minus one
+        for _ in 0..=hdr.count {
+            let mut ent: *mut sqfs_dir_entry_t = ptr::null_mut();
+            let ret = sqfs_meta_reader_read_dir_ent(dm, &mut ent);
+            if ret != 0 {
+                return Err(error::sqfs(ret, &format!("reading directory entry of {}", what)));
+            }
+            let e = IndexEntry {
+                inode_ref: ((hdr.start_block as u64) << 16) | (*ent).offset as u64,
+                inode_num: (hdr.inode_number as i64 + (*ent).inode_diff as i64) as u32,
+                inode_type: (*ent).type_,
+            };
+            remain = remain.saturating_sub(ent_size + (*ent).size as usize + 1);
+            let name = std::slice::from_raw_parts((*ent).name.as_ptr(), (*ent).size as usize + 1);
+            let more = f(name, e);
+            sqfs_free(ent as *mut c_void);
+            if !more {
+                return Ok(());
+            }
+        }
+    }
+    Ok(())
+}
+
+// read inode by reference, caller frees the inode
+pub(crate) unsafe fn read_inode(file: *mut sqfs_file_t, cmp: *mut sqfs_compressor_t,
+        sb: *const sqfs_super_t, inode_ref: u64, out: *mut *mut sqfs_inode_generic_t) -> i32 {
+
+    let ir = sqfs_meta_reader_create(file, cmp, (*sb).inode_table_start, (*sb).directory_table_start);
+    if ir.is_null() {
+        return SQFS_ERROR_SQFS_ERROR_ALLOC;
+    }
+    let ret = sqfs_meta_reader_read_inode(ir, sb, inode_ref >> 16, (inode_ref & 0xffff) as usize, out);
+    crate::sqfs_destroy(ir);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> PathIndex {
+        let mut entries = HashMap::new();
+        entries.insert(String::new(), IndexEntry { inode_ref: 0x20, inode_num: 1, inode_type: 1 });
+        entries.insert("a/b".to_string(), IndexEntry { inode_ref: 0x1_0040, inode_num: 3, inode_type: 2 });
+        entries.insert("a/a".to_string(), IndexEntry { inode_ref: 0x1_0040, inode_num: 3, inode_type: 2 });
+        let mut idx = PathIndex {
+            entries: entries,
+            paths: HashMap::new(),
+            bytes_used: 4096,
+            mod_time: 1700000000,
+            inode_count: 3,
+        };
+        idx.index_paths();
+        idx
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let idx = sample();
+        let buf = idx.encode();
+        assert_eq!(PathIndex::decode(&buf), Some(idx));
+        assert_eq!(PathIndex::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(PathIndex::decode(b"garbage"), None);
+    }
+
+    #[test]
+    fn test_lookup() {
+        let idx = sample();
+        assert!(matches!(idx.lookup("/a//b/"), Lookup::FOUND(e) if e.inode_num == 3));
+        assert!(matches!(idx.lookup("/"), Lookup::FOUND(e) if e.is_dir()));
+        assert!(matches!(idx.lookup("a/c"), Lookup::MISSING));
+        assert!(matches!(idx.lookup("a/../a/b"), Lookup::UNKNOWN));
+        // hard links resolve to the smallest path
+        assert_eq!(idx.path_of(3), Some("a/a".to_string()));
+        assert_eq!(idx.path_of(2), None);
+    }
+}
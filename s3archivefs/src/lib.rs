@@ -4,6 +4,18 @@ pub mod repo;
 pub mod transfer;
 pub mod stats;
 pub mod hook_helper;
+pub mod extract;
+pub mod file;
+pub mod entry;
+pub mod error;
+pub mod index;
+pub mod listing;
+pub mod find;
+pub mod grep;
+pub mod diff;
+pub mod fsck;
+pub mod manifest;
+pub mod batch;
 
 pub mod bindings {
     #![allow(non_camel_case_types)]
@@ -29,9 +41,24 @@ pub trait ArchiveFs {
     fn get_sb(&self) -> sqfs_super_t;
     fn get_archive_file_size(&self) -> usize;
     fn set_hook(&self);
-    fn extract_one(&self, path: &str, outpath: &str) -> Result<usize, std::io::Error>;
+    fn extract_one_at(&self, path: &str, dir: &extract::SafeDir, name: &std::ffi::OsStr, opts: &extract::ExtractOptions) -> Result<usize, std::io::Error>;
+    fn extract_tree(&self, path: &str, outdir: &str, opts: &extract::ExtractOptions) -> Result<usize, std::io::Error>;
+    fn extract_to(&self, path: &str, output: &mut dyn std::io::Write) -> Result<usize, std::io::Error>;
+    fn read_block(&self, path: &str, index: usize) -> Result<Option<Vec<u8>>, std::io::Error>;
+    fn read_inode_block(&self, inode: &file::Inode, index: usize) -> Result<Option<Vec<u8>>, std::io::Error>;
+    fn open(self: std::rc::Rc<Self>, path: &str) -> Result<file::ArchiveFile, std::io::Error>;
+    fn read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, std::io::Error>;
     fn print_list(&self, path: Option<String>);
     fn print_file_stat(&self, filepath: &str);
-    fn file_list(&self, path: Option<String>) -> Vec<(String, libc::stat64)>;
-    fn file_stat(&self, filepath: &str) -> Option<libc::stat64>;
+    fn metadata(&self, path: &str) -> Result<entry::Metadata, std::io::Error>;
+    fn read_dir(&self, path: &str) -> Result<entry::ReadDir, std::io::Error>;
+    fn metadata_bytes(&self, path: &[u8]) -> Result<entry::Metadata, std::io::Error>;
+    fn read_dir_bytes(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, Result<entry::Metadata, std::io::Error>)>, std::io::Error>;
+    fn read_at_bytes(&self, path: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, std::io::Error>;
+    fn file_extents(&self, filepath: &str) -> Result<Vec<(usize, usize)>, std::io::Error>;
+    fn block_layout(&self, filepath: &str) -> Result<entry::BlockLayout, std::io::Error>;
+    fn inode_metadata(&self, ino: u32) -> Result<entry::Metadata, std::io::Error>;
+    fn inode_path(&self, ino: u32) -> Result<String, std::io::Error>;
+    fn build_index(&self, cache: Option<&str>) -> Result<usize, std::io::Error>;
+    fn fsck(&self) -> Result<fsck::Report, std::io::Error>;
 }
@@ -0,0 +1,310 @@
+use std::io::{Write, Error, ErrorKind};
+use std::str::FromStr;
+use serde_json::{json, Value};
+use crate::entry::{Entry, FileType, BlockLayout};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Format {
+    // one array of objects
+    JSON,
+    // one object per line
+    JSONL,
+    CSV,
+    // like ls -l, with full path
+    LS,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::JSON),
+            "jsonl" => Ok(Format::JSONL),
+            "csv" => Ok(Format::CSV),
+            "ls" => Ok(Format::LS),
+            _ => Err(format!("unknown format {}, expect json|jsonl|csv|ls", s)),
+        }
+    }
+}
+
+const CSV_HEADER: &str = "path,type,size,mode,uid,gid,mtime,nlink,ino,link_target,xattrs,start,block_sizes,fragment_index,fragment_offset,fragment_start,fragment_size";
+
+// writes entries as they come, so a listing of a big archive is not
+// collected in memory first, call finish to close a json array
+pub struct ListWriter<W: Write> {
+    out: W,
+    format: Format,
+    count: usize,
+}
+
+impl<W: Write> ListWriter<W> {
+
+    pub fn new(mut out: W, format: Format) -> Result<Self, Error> {
+        match format {
+            Format::JSON => out.write_all(b"[")?,
+            Format::CSV => writeln!(out, "{}", CSV_HEADER)?,
+            _ => {},
+        }
+        Ok(Self {
+            out: out,
+            format: format,
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, entry: &Entry, layout: Option<&BlockLayout>) -> Result<(), Error> {
+        match self.format {
+            Format::JSON => {
+                if self.count > 0 {
+                    self.out.write_all(b",")?;
+                }
+                self.out.write_all(b"\n")?;
+                serde_json::to_writer(&mut self.out, &to_json(entry, layout))
+                    .map_err(|e| Error::new(ErrorKind::Other, e))?;
+            },
+            Format::JSONL => {
+                serde_json::to_writer(&mut self.out, &to_json(entry, layout))
+                    .map_err(|e| Error::new(ErrorKind::Other, e))?;
+                self.out.write_all(b"\n")?;
+            },
+            Format::CSV => writeln!(self.out, "{}", to_csv(entry, layout))?,
+            Format::LS => writeln!(self.out, "{}", to_ls(entry, layout))?,
+        }
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<usize, Error> {
+        if self.format == Format::JSON {
+            self.out.write_all(b"\n]\n")?;
+        }
+        self.out.flush()?;
+        Ok(self.count)
+    }
+}
+
+fn full_path(entry: &Entry) -> String {
+    format!("/{}", entry.path)
+}
+
+// text as is, anything else base64 with 0s prefix as getfattr does
+fn xattr_value(v: &[u8]) -> String {
+    match std::str::from_utf8(v) {
+        Ok(s) => s.to_string(),
+        Err(_) => format!("0s{}", base64::encode(v)),
+    }
+}
+
+fn sorted_xattrs(entry: &Entry) -> Vec<(String, String)> {
+    let mut v: Vec<(String, String)> = entry.metadata.xattrs.iter()
+        .map(|(k, v)| (String::from_utf8_lossy(k).to_string(), xattr_value(v)))
+        .collect();
+    v.sort();
+    v
+}
+
+fn link_target(entry: &Entry) -> Option<String> {
+    entry.metadata.link_target.as_ref().map(|t| String::from_utf8_lossy(t).to_string())
+}
+
+pub fn to_json(entry: &Entry, layout: Option<&BlockLayout>) -> Value {
+    let m = &entry.metadata;
+    let xattrs: serde_json::Map<String, Value> = sorted_xattrs(entry).into_iter()
+        .map(|(k, v)| (k, Value::String(v)))
+        .collect();
+    let mut v = json!({
+        "path": full_path(entry),
+        "type": m.file_type.as_str(),
+        "size": m.size,
+        "mode": format!("{:04o}", m.mode),
+        "uid": m.uid,
+        "gid": m.gid,
+        "mtime": m.mtime,
+        "nlink": m.nlink,
+        "ino": m.ino,
+        "link_target": link_target(entry),
+        "xattrs": xattrs,
+    });
+    if let Some(l) = layout {
+        v["blocks"] = json!({
+            "start": l.start,
+            "sizes": l.sizes,
+            "fragment": l.fragment.map(|f| json!({
+                "index": f.index,
+                "offset": f.offset,
+                "start": f.start,
+                "size": f.size,
+            })),
+        });
+    }
+    v
+}
+
+fn csv_field(s: &str) -> String {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn to_csv(entry: &Entry, layout: Option<&BlockLayout>) -> String {
+    let m = &entry.metadata;
+    let xattrs = sorted_xattrs(entry).iter()
+        .map(|(k, v)| format!("{}={}", k, v))
+        .collect::<Vec<_>>()
+        .join(";");
+    let mut fields = vec![
+        full_path(entry),
+        m.file_type.as_str().to_string(),
+        m.size.to_string(),
+        format!("{:04o}", m.mode),
+        m.uid.to_string(),
+        m.gid.to_string(),
+        m.mtime.to_string(),
+        m.nlink.to_string(),
+        m.ino.to_string(),
+        link_target(entry).unwrap_or_default(),
+        xattrs,
+    ];
+    match layout {
+        Some(l) => {
+            fields.push(l.start.to_string());
+            fields.push(l.sizes.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(" "));
+            match l.fragment {
+                Some(f) => fields.extend([f.index, f.offset].iter().map(|x| x.to_string())
+                                .chain([f.start.to_string(), f.size.to_string()])),
+                None => fields.extend(std::iter::repeat(String::new()).take(4)),
+            }
+        },
+        None => fields.extend(std::iter::repeat(String::new()).take(6)),
+    }
+    fields.iter().map(|f| csv_field(f)).collect::<Vec<_>>().join(",")
+}
+
+pub fn mode_string(file_type: FileType, mode: u32) -> String {
+    let mut s = String::with_capacity(10);
+    s.push(match file_type {
+        FileType::REGULAR => '-',
+        FileType::DIRECTORY => 'd',
+        FileType::SYMLINK => 'l',
+        FileType::BLOCKDEV => 'b',
+        FileType::CHARDEV => 'c',
+        FileType::FIFO => 'p',
+        FileType::SOCKET => 's',
+    });
+    for (shift, special, ch) in [(6, libc::S_ISUID, 's'), (3, libc::S_ISGID, 's'), (0, libc::S_ISVTX, 't')] {
+        let bits = (mode >> shift) & 7;
+        s.push(if bits & 4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 2 != 0 { 'w' } else { '-' });
+        s.push(match (bits & 1 != 0, mode & special != 0) {
+            (true, true) => ch,
+            (false, true) => ch.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+// seconds since epoch as UTC date and minute, no time zone database needed
+pub fn format_time(t: i64) -> String {
+    let days = t.div_euclid(86400);
+    let secs = t.rem_euclid(86400);
+
+    // civil from days, era of 400 years starting on 0000-03-01
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", y, m, d, secs / 3600, secs % 3600 / 60)
+}
+
+pub fn to_ls(entry: &Entry, layout: Option<&BlockLayout>) -> String {
+    let m = &entry.metadata;
+    let mut s = format!("{} {:>3} {:>5} {:>5} {:>12} {} {}",
+        mode_string(m.file_type, m.mode), m.nlink, m.uid, m.gid, m.size,
+        format_time(m.mtime), full_path(entry));
+    if let Some(target) = link_target(entry) {
+        s.push_str(" -> ");
+        s.push_str(&target);
+    }
+    if let Some(l) = layout {
+        s.push_str(&format!(" [start {}, blocks {:?}", l.start, l.sizes));
+        if let Some(f) = l.fragment {
+            s.push_str(&format!(", fragment {} +{}", f.index, f.offset));
+        }
+        s.push(']');
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::entry::{Metadata, Fragment};
+
+    fn sample() -> Entry {
+        let mut xattrs = HashMap::new();
+        xattrs.insert(b"user.note".to_vec(), b"a,b".to_vec());
+        xattrs.insert(b"user.bin".to_vec(), vec![0xff, 0x00]);
+        Entry {
+            path: "data/x.log".to_string(),
+            metadata: Metadata {
+                file_type: FileType::REGULAR,
+                size: 10,
+                mode: 0o4755,
+                uid: 0,
+                gid: 1,
+                mtime: 951782400,
+                nlink: 1,
+                rdev: 0,
+                ino: 7,
+                xattrs: xattrs,
+                link_target: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00");
+        assert_eq!(format_time(951782400 + 3661), "2000-02-29 01:01");
+        assert_eq!(format_time(-60), "1969-12-31 23:59");
+    }
+
+    #[test]
+    fn test_mode_string() {
+        assert_eq!(mode_string(FileType::REGULAR, 0o4755), "-rwsr-xr-x");
+        assert_eq!(mode_string(FileType::DIRECTORY, 0o1770), "drwxrwx--T");
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let layout = BlockLayout {
+            start: 96,
+            sizes: vec![10, 0],
+            fragment: Some(Fragment { index: 2, offset: 8, start: 500, size: 20 }),
+        };
+        assert_eq!(to_csv(&sample(), Some(&layout)),
+            "/data/x.log,file,10,4755,0,1,951782400,1,7,,\"user.bin=0s/wA=;user.note=a,b\",96,10 0,2,8,500,20");
+        assert!(to_csv(&sample(), None).ends_with("\",,,,,,"));
+    }
+
+    #[test]
+    fn test_to_json() {
+        let v = to_json(&sample(), None);
+        assert_eq!(v["path"], "/data/x.log");
+        assert_eq!(v["mode"], "4755");
+        assert_eq!(v["xattrs"]["user.bin"], "0s/wA=");
+        assert!(v.get("blocks").is_none());
+    }
+}
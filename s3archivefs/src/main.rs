@@ -1,8 +1,17 @@
+use std::io::Write;
 use structopt::StructOpt;
-use log::{info, error};
+use log::{info, warn, error};
 use aws_config::meta::region::RegionProviderChain;
-use s3archivefs::repo::{Remote, Local, HoleDetectMode};
+use s3archivefs::repo::{Remote, Local, HoleDetectMode, CacheState, FetchMode};
 use s3archivefs::repo::CONTEXT;
+use s3archivefs::extract::{ExtractOptions, SpecialMode, PathMode, SafeDir};
+use s3archivefs::entry::{Entry, FileType};
+use s3archivefs::find::{self, Filter};
+use s3archivefs::grep::{self, Pattern, Output};
+use s3archivefs::diff::{self, Change};
+use s3archivefs::manifest::{self, Manifest, Check, MANIFEST_SUFFIX};
+use s3archivefs::batch;
+use s3archivefs::listing::{Format, ListWriter};
 
 #[derive(Debug, StructOpt)]
 enum Cmd {
@@ -21,18 +30,29 @@ enum Cmd {
         file: String,
     },
     Extract {
-        #[structopt(short, display_order = 1, help = "region")]
+        // -r is taken by recursive here
+        #[structopt(long, display_order = 1, help = "region")]
         region: Option<String>,
-        #[structopt(short, display_order = 2, help = "bucket")]
-        bucket: String,
-        #[structopt(short, display_order = 3, help = "key")]
-        key: String,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
         #[structopt(short, display_order = 4, help = "local archivefs cache")]
         cachefile: String,
         #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
         chunk_size: Option<usize>,
-        #[structopt(short="t", display_order = 6, help = "file to extract")]
-        filepath: String,
+        #[structopt(short="t", display_order = 6, required_unless_one = &["recursive", "from-list"], help = "file to extract, can be repeated")]
+        filepath: Vec<String>,
+        #[structopt(short="r", long, display_order = 14, help = "directory to extract recursively, preserving the tree, can be repeated")]
+        recursive: Vec<String>,
+        #[structopt(long, display_order = 15, help = "substitute device node with empty file when not privileged, default skip")]
+        substitute_special: bool,
+        #[structopt(long, display_order = 16, help = "skip entry with unsafe path with a warning, default abort")]
+        lenient: bool,
+        #[structopt(long, display_order = 17, conflicts_with = "recursive", help = "start offset in file of range to extract")]
+        offset: Option<usize>,
+        #[structopt(long, display_order = 18, conflicts_with = "recursive", help = "length of range to extract, default to end of file")]
+        length: Option<usize>,
         #[structopt(short, display_order = 7, default_value = "/tmp", help = "local directory to save extract file")]
         localdir: String,
         #[structopt(short, display_order = 8, help = "hole detect with test all zeros")]
@@ -41,14 +61,28 @@ enum Cmd {
         force: bool,
         #[structopt(short="i", display_order = 10, help = "init root hierarchy")]
         init_root: bool,
+        #[structopt(long, display_order = 11, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 12, help = "record chunk fetches and cache hits to trace file")]
+        trace: Option<String>,
+        #[structopt(long, display_order = 13, help = "fetch exactly compressed extents needed instead of whole chunks")]
+        exact: bool,
+        #[structopt(long, display_order = 19, help = "check extracted files against sha256 manifest of archive")]
+        verify: bool,
+        #[structopt(long, display_order = 20, requires = "verify", help = "manifest file, default sidecar of local cache, fetched from S3 if missing")]
+        manifest: Option<String>,
+        #[structopt(long, display_order = 21, conflicts_with_all = &["offset", "length"], help = "file with one path per line, or a manifest, to extract into the tree under localdir")]
+        from_list: Option<String>,
+        #[structopt(long, display_order = 22, default_value = "8", help = "max concurrent GET requests prefetching blocks of from-list")]
+        concurrency: usize,
     },
     List {
         #[structopt(short, display_order = 1, help = "region")]
         region: Option<String>,
-        #[structopt(short, display_order = 2, help = "bucket")]
-        bucket: String,
-        #[structopt(short, display_order = 3, help = "key")]
-        key: String,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
         #[structopt(short, display_order = 4, help = "hole detect with test all zeros")]
         zero: bool,
         #[structopt(short, display_order = 5, help = "force to use remote archive file")]
@@ -57,16 +91,22 @@ enum Cmd {
         cachefile: String,
         #[structopt(short="s", display_order = 7, help = "chunk size of local cache")]
         chunk_size: Option<usize>,
-        #[structopt(display_order = 8, help = "path of start point")]
+        #[structopt(long, display_order = 8, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(display_order = 9, help = "path of start point")]
         path: Option<String>,
+        #[structopt(long, display_order = 10, help = "output format json|jsonl|csv|ls, default tree")]
+        format: Option<Format>,
+        #[structopt(long, display_order = 11, requires = "format", help = "include block layout of regular files")]
+        blocks: bool,
     },
     Stat {
         #[structopt(short, display_order = 1, help = "region")]
         region: Option<String>,
-        #[structopt(short, display_order = 2, help = "bucket")]
-        bucket: String,
-        #[structopt(short, display_order = 3, help = "key")]
-        key: String,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
         #[structopt(short, display_order = 4, help = "hole detect with test all zeros")]
         zero: bool,
         #[structopt(short, display_order = 5, help = "force to use remote archive file")]
@@ -75,11 +115,470 @@ enum Cmd {
         cachefile: String,
         #[structopt(short="s", display_order = 7, help = "chunk size of local cache")]
         chunk_size: Option<usize>,
-        #[structopt(short="t", display_order = 8, help = "file to stat")]
-        filepath: String,
+        #[structopt(short="t", display_order = 8, required_unless = "inode", help = "file to stat")]
+        filepath: Option<String>,
+        #[structopt(long, display_order = 9, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 10, conflicts_with = "filepath", help = "inode number to stat, needs export table")]
+        inode: Option<u32>,
+        #[structopt(long, display_order = 11, help = "output format json|jsonl|csv|ls")]
+        format: Option<Format>,
+        #[structopt(long, display_order = 12, requires = "format", help = "include block layout of regular file")]
+        blocks: bool,
+    },
+    Find {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 8, help = "glob on file name, e.g. '*.log'")]
+        name: Option<String>,
+        #[structopt(long, display_order = 9, help = "regular expression on full path")]
+        regex: Option<regex::Regex>,
+        #[structopt(long="type", display_order = 10, parse(try_from_str = find::parse_type), help = "file type f|d|l|b|c|p|s")]
+        file_type: Option<FileType>,
+        #[structopt(long, display_order = 11, parse(try_from_str = find::parse_size), help = "min size in bytes, k, M, G or T suffix")]
+        min_size: Option<u64>,
+        #[structopt(long, display_order = 12, parse(try_from_str = find::parse_size), help = "max size in bytes, k, M, G or T suffix")]
+        max_size: Option<u64>,
+        #[structopt(long, display_order = 13, parse(try_from_str = find::parse_time), help = "modified after, YYYY-MM-DD[ HH:MM[:SS]] UTC or seconds since epoch")]
+        newer: Option<i64>,
+        #[structopt(long, display_order = 14, parse(try_from_str = find::parse_time), help = "modified before, YYYY-MM-DD[ HH:MM[:SS]] UTC or seconds since epoch")]
+        older: Option<i64>,
+        #[structopt(long, display_order = 15, help = "owner uid")]
+        uid: Option<u32>,
+        #[structopt(long, display_order = 16, help = "owner gid")]
+        gid: Option<u32>,
+        #[structopt(long, display_order = 17, help = "has xattr with this key, e.g. user.checksum")]
+        xattr: Option<String>,
+        #[structopt(long, display_order = 18, conflicts_with = "format", help = "separate paths with NUL instead of newline")]
+        print0: bool,
+        #[structopt(long, display_order = 19, help = "output format json|jsonl|csv|ls, default path only")]
+        format: Option<Format>,
+        #[structopt(display_order = 20, default_value = "/", help = "path of start point")]
+        path: String,
+    },
+    Grep {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(long, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(short="F", long, display_order = 8, help = "pattern is a fixed string, not a regular expression")]
+        fixed_strings: bool,
+        #[structopt(short="l", long, display_order = 9, help = "print only paths of files with a match")]
+        files_with_matches: bool,
+        #[structopt(short="c", long, display_order = 10, conflicts_with = "files-with-matches", help = "print count of matching lines per file")]
+        count: bool,
+        #[structopt(short="n", long, display_order = 11, help = "print line number of each match")]
+        line_number: bool,
+        #[structopt(long, display_order = 12, help = "glob on file name of candidates, e.g. '*.rs'")]
+        name: Option<String>,
+        #[structopt(long, display_order = 13, parse(try_from_str = find::parse_size), help = "min size of candidates, k, M, G or T suffix")]
+        min_size: Option<u64>,
+        #[structopt(long, display_order = 14, parse(try_from_str = find::parse_size), help = "max size of candidates, k, M, G or T suffix")]
+        max_size: Option<u64>,
+        #[structopt(long, display_order = 15, parse(try_from_str = find::parse_size), help = "max compressed bytes to fetch, files beyond it are skipped")]
+        budget: Option<u64>,
+        #[structopt(long, display_order = 16, default_value = "8", help = "max concurrent GET requests")]
+        concurrency: usize,
+        #[structopt(display_order = 17, help = "pattern to search")]
+        pattern: String,
+        #[structopt(display_order = 18, default_value = "/", help = "path of start point")]
+        path: String,
+    },
+    Diff {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short="s", display_order = 2, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 3, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 4, default_value = "/tmp/s3archivefs", help = "directory of local caches for s3:// archives")]
+        cache_dir: String,
+        #[structopt(long, display_order = 5, help = "compare data of regular files alike on metadata")]
+        content: bool,
+        #[structopt(display_order = 6, help = "first archive, s3://bucket/key or local archivefs file")]
+        archive_a: String,
+        #[structopt(display_order = 7, help = "second archive, s3://bucket/key or local archivefs file")]
+        archive_b: String,
+        #[structopt(display_order = 8, default_value = "/", help = "path of start point")]
+        path: String,
+    },
+    Fsck {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache, or a full local image with --offline")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+    },
+    Manifest {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 8, help = "manifest file to write, default sidecar of local cache")]
+        output: Option<String>,
+        #[structopt(long, display_order = 9, conflicts_with = "offline", help = "store manifest next to archive as <key>.manifest")]
+        upload: bool,
+        #[structopt(long, display_order = 10, default_value = "8", help = "max concurrent GET requests")]
+        concurrency: usize,
+    },
+    Verify {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 8, help = "manifest file, default sidecar of local cache, fetched from S3 if missing")]
+        manifest: Option<String>,
+        #[structopt(long, display_order = 9, default_value = "8", help = "max concurrent GET requests")]
+        concurrency: usize,
+        #[structopt(display_order = 10, default_value = "/", help = "path of start point")]
+        path: String,
+    },
+    Plan {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 8, help = "file with one path per line, in addition to paths given")]
+        from_list: Option<String>,
+        #[structopt(long, display_order = 9, help = "print plan as json with extents and ranges")]
+        json: bool,
+        #[structopt(display_order = 10, required_unless = "from-list", help = "files or directories to read")]
+        paths: Vec<String>,
+    },
+    Cat {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, required_unless = "offline", help = "bucket")]
+        bucket: Option<String>,
+        #[structopt(short, display_order = 3, required_unless = "offline", help = "key")]
+        key: Option<String>,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "serve from local cache only, never access S3")]
+        offline: bool,
+        #[structopt(long, display_order = 8, help = "fetch exactly compressed extents needed instead of whole chunks")]
+        exact: bool,
+        #[structopt(display_order = 9, help = "file to write to stdout")]
+        path: String,
+    },
+    Cache(CacheCmd),
+    Hydrate {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, help = "bucket")]
+        bucket: String,
+        #[structopt(short, display_order = 3, help = "key")]
+        key: String,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "max download rate in bytes per second, default unlimited")]
+        rate: Option<usize>,
+    },
+    Prefetch {
+        #[structopt(short, display_order = 1, help = "region")]
+        region: Option<String>,
+        #[structopt(short, display_order = 2, help = "bucket")]
+        bucket: String,
+        #[structopt(short, display_order = 3, help = "key")]
+        key: String,
+        #[structopt(short, display_order = 4, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short="s", display_order = 5, help = "chunk size of local cache")]
+        chunk_size: Option<usize>,
+        #[structopt(short, display_order = 6, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(long, display_order = 7, help = "trace file recorded by --trace")]
+        trace: String,
+        #[structopt(long, display_order = 8, default_value = "8", help = "max concurrent GET requests")]
+        concurrency: usize,
     },
 }
 
+#[derive(Debug, StructOpt)]
+enum CacheCmd {
+    Status {
+        #[structopt(short, display_order = 1, help = "local archivefs cache")]
+        cachefile: String,
+        #[structopt(short, display_order = 2, help = "hole detect with test all zeros")]
+        zero: bool,
+        #[structopt(display_order = 3, help = "path of start point")]
+        path: Option<String>,
+    },
+}
+
+async fn new_remote(region: Option<String>, default_region: Option<String>,
+        bucket: Option<String>, key: Option<String>, offline: bool) -> Option<Remote> {
+
+    if offline {
+        info!("offline mode, serve from local cache only");
+        return None;
+    }
+
+    let remote = Remote::new(region
+                    .or(default_region)
+                    .expect("no region config found in cli or profile")
+                    .as_str(), &bucket.unwrap(), &key.unwrap()).await;
+    Some(remote)
+}
+
+// read at most this much per read_at call, so memory stays bounded
+const RANGE_CHUNK: u64 = 0x10_0000;
+
+// write only a byte range of file, read without decoding blocks out of range
+// stream range to output in bounded pieces, without length runs to end of file
+fn extract_range(local: &Local, filepath: &str, offset: u64, length: Option<u64>, localdir: &str, filename: &str) -> Result<u64, std::io::Error> {
+    let dir = SafeDir::open_root(std::path::Path::new(localdir))?;
+    let mut output = dir.create_file(std::ffi::OsStr::new(filename))?;
+    let end = length.map(|l| offset.saturating_add(l));
+    let mut copied = 0;
+    loop {
+        let pos = offset + copied;
+        let want = match end {
+            Some(end) if pos >= end => break,
+            Some(end) => std::cmp::min(RANGE_CHUNK, end - pos),
+            None => RANGE_CHUNK,
+        };
+        let data = local.read_at(filepath, pos as usize, want as usize)?;
+        // end of file
+        if data.is_empty() {
+            break;
+        }
+        output.write_all(&data)?;
+        copied += data.len() as u64;
+    }
+    output.flush()?;
+    Ok(copied)
+}
+
+// s3://bucket/key is cached under cache_dir/bucket/key, anything else is a
+// local archivefs file used offline
+async fn open_archive(spec: &str, cache_dir: &str, region: Option<String>,
+        chunk_size: Option<usize>, hdmode: HoleDetectMode) -> Result<Local, String> {
+
+    let (cachefile, remote) = match spec.strip_prefix("s3://").and_then(|s| s.split_once('/')) {
+        Some((bucket, key)) => {
+            let cachefile = std::path::Path::new(cache_dir).join(bucket).join(key);
+            if let Some(parent) = cachefile.parent() {
+                std::fs::create_dir_all(parent)
+                    .map_err(|e| format!("failed to create cache dir {}, {}", parent.display(), e))?;
+            }
+            let remote = new_remote(region, None, Some(bucket.to_string()), Some(key.to_string()), false).await;
+            (cachefile.display().to_string(), remote)
+        },
+        None => (spec.to_string(), None),
+    };
+    let offline = remote.is_none();
+    Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await
+        .map_err(|e| format!("failed to open archive {}, {}", spec, e))
+}
+
+// one archive path per line, blank lines and lines starting with # skipped
+fn read_path_list(listfile: &str) -> Result<Vec<String>, std::io::Error> {
+    Ok(parse_path_list(&std::fs::read_to_string(listfile)?))
+}
+
+fn parse_path_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.trim().is_empty() && !l.starts_with('#'))
+        .map(|l| l.to_string())
+        .collect()
+}
+
+// paths of a plain list, or every file of a manifest, detected by its
+// json header line, together with the manifest
+fn read_extract_list(listfile: &str) -> Result<(Vec<String>, Option<Manifest>), std::io::Error> {
+    let text = std::fs::read_to_string(listfile)?;
+    if text.trim_start().starts_with('{') {
+        let manifest = Manifest::read(text.as_bytes())?;
+        let paths = manifest.iter().map(|(path, _)| format!("/{}", path)).collect();
+        return Ok((paths, Some(manifest)));
+    }
+    Ok((parse_path_list(&text), None))
+}
+
+// log result of checking an extracted file, return true if it matches
+fn report_check(filepath: &str, check: Check) -> bool {
+    match check {
+        Check::OK => {
+            info!("{} verified", filepath);
+            true
+        },
+        Check::MISMATCH(what) => {
+            error!("{} does not match manifest, {}", filepath, what);
+            false
+        },
+        Check::UNLISTED => {
+            error!("{} not in manifest", filepath);
+            false
+        },
+    }
+}
+
+// regular files of a tree extract_tree wrote, under localdir/<name of dirpath>
+// or localdir itself for root of archive
+fn verify_extracted_tree(local: &Local, manifest: &Manifest, dirpath: &str, localdir: &str) {
+    let top = dirpath.trim_matches('/');
+    let name = top.rsplit('/').next().unwrap_or("");
+    let base = std::path::Path::new(localdir).join(name);
+    let filter = Filter {
+        file_type: Some(FileType::REGULAR),
+        ..Default::default()
+    };
+    let found = match local.find(dirpath, &filter) {
+        Ok(found) => found,
+        Err(e) => {
+            error!("failed to walk {} for verify, error: {}", dirpath, e);
+            return;
+        },
+    };
+    let (mut checked, mut failed) = (0, 0);
+    for entry in found {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                error!("failed to read entry under {}, error: {}", dirpath, e);
+                failed += 1;
+                continue;
+            },
+        };
+        let rel = entry.path[top.len()..].trim_start_matches('/');
+        let local_path = if rel.is_empty() { base.clone() } else { base.join(rel) };
+        checked += 1;
+        if !report_check(&entry.path, manifest::check_file(manifest, &entry.path, &local_path)) {
+            failed += 1;
+        }
+    }
+    info!("{} of {} files under {} verified", checked - failed, checked, dirpath);
+}
+
+// path itself and, with recursive, every entry below it
+fn write_listing(local: &Local, path: &str, format: Format, blocks: bool, recursive: bool) -> Result<usize, std::io::Error> {
+    let stdout = std::io::stdout();
+    let mut output = ListWriter::new(std::io::BufWriter::new(stdout.lock()), format)?;
+
+    let top = Entry {
+        path: path.trim_matches('/').to_string(),
+        metadata: local.metadata(path)?,
+    };
+    write_entry(local, &mut output, &top, blocks)?;
+    if recursive && top.metadata.is_dir() {
+        for entry in local.walk(path)? {
+            match entry {
+                Ok(entry) => write_entry(local, &mut output, &entry, blocks)?,
+                Err(e) => error!("failed to list entry under {}, error: {}", path, e),
+            }
+        }
+    }
+    output.finish()
+}
+
+fn write_entry<W: Write>(local: &Local, output: &mut ListWriter<W>, entry: &Entry, blocks: bool) -> Result<(), std::io::Error> {
+    let mut layout = None;
+    if blocks && entry.metadata.is_file() {
+        layout = Some(local.block_layout(&entry.path)?);
+    }
+    output.write(entry, layout.as_ref())
+}
+
+fn write_found(local: &Local, path: &str, filter: &Filter, format: Option<Format>, print0: bool) -> Result<(), std::io::Error> {
+    let stdout = std::io::stdout();
+    let mut output = std::io::BufWriter::new(stdout.lock());
+    let found = local.find(path, filter)?.filter_map(|entry| match entry {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            error!("failed to read entry under {}, error: {}", path, e);
+            None
+        },
+    });
+    match format {
+        Some(format) => {
+            let mut writer = ListWriter::new(output, format)?;
+            for entry in found {
+                writer.write(&entry, None)?;
+            }
+            writer.finish()?;
+        },
+        None => {
+            let sep = if print0 { '\0' } else { '\n' };
+            for entry in found {
+                write!(output, "/{}{}", entry.path, sep)?;
+            }
+            output.flush()?;
+        },
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
 
@@ -94,7 +593,13 @@ async fn main() {
     let opt = Cmd::from_args();
     match opt {
         Cmd::Meta {file} => {
-            let local = Local::new(&file, None, HoleDetectMode::ALLZERO, false, false, None, false).await;
+            let local = match Local::new(&file, None, HoleDetectMode::ALLZERO, false, false, None, false, true).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
             local.print_superblock()
         }
         Cmd::Install {region, bucket, key, file} => {
@@ -114,13 +619,19 @@ async fn main() {
                 Ok(_) => {},
             }
         },
-        Cmd::Extract {region, bucket, key, cachefile, chunk_size, filepath, localdir, zero, force, init_root} => {
-            let remote = Remote::new(region
-                            .or(default_region
-                                .map(|r| r.as_ref().to_string())
-                            )
-                            .expect("no region config found in cli or profile")
-                            .as_str(), &bucket, &key).await;
+        Cmd::Extract {region, bucket, key, cachefile, chunk_size, filepath, recursive, substitute_special, lenient, offset, length, localdir, zero, force, init_root, offline, trace, exact, verify, manifest, from_list, concurrency} => {
+            let (list, list_manifest) = match from_list.as_ref() {
+                Some(listfile) => match read_extract_list(listfile) {
+                    Ok((list, list_manifest)) => (list, list_manifest),
+                    Err(e) => {
+                        error!("failed to read list {}, error: {}", listfile, e);
+                        return;
+                    },
+                },
+                None => (Vec::new(), None),
+            };
+
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
             let hdmode;
             if zero {
                 hdmode = HoleDetectMode::ALLZERO;
@@ -128,32 +639,203 @@ async fn main() {
                 hdmode = HoleDetectMode::LSEEK;
             }
 
-            let local = Local::new(&cachefile, chunk_size, hdmode, force, init_root, Some(remote.clone()), false).await;
+            let mut local = match Local::new(&cachefile, chunk_size, hdmode, force, init_root, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            if exact {
+                local.set_fetch_mode(FetchMode::EXTENT);
+            }
+            if let Some(tracefile) = trace {
+                if let Err(e) = local.set_trace(&tracefile) {
+                    error!("failed to open trace file {}, error: {}", &tracefile, e);
+                    return;
+                }
+            }
             let _l = local.clone();
             CONTEXT.with(|c| *c.borrow_mut() = Some(local));
 
-            let filename = filepath.split("/").last();
-            if filename.is_none() {
-                error!("invalid file path {}", &filepath);
+            let manifest = if verify && manifest.is_none() && list_manifest.is_some() {
+                // list given as manifest is checked against too
+                let list_manifest = list_manifest.unwrap();
+                if !list_manifest.matches(&_l.superblock()) {
+                    error!("manifest {} is not made from this archive", from_list.as_deref().unwrap_or(""));
+                    return;
+                }
+                Some(list_manifest)
+            } else if verify {
+                match _l.manifest(manifest.as_deref()).await {
+                    Ok(manifest) => Some(manifest),
+                    Err(e) => {
+                        error!("failed to load manifest, error: {}", e);
+                        return;
+                    },
+                }
+            } else {
+                None
+            };
+
+            let mut opts = ExtractOptions::default();
+            if substitute_special {
+                opts.special = SpecialMode::SUBSTITUTE;
             }
-            let output_path = localdir + "/" + filename.unwrap();
-            info!("extract {} from archive to {}", &filepath, &output_path);
-            let res = _l.extract_one(&filepath, &output_path);
-            match res {
+            if lenient {
+                opts.path_mode = PathMode::LENIENT;
+            }
+
+            // same options across all paths, so files linked to one inode
+            // come out as hard links of the first one extracted
+            if from_list.is_some() {
+                info!("extract {} listed paths from archive to {}", list.len(), &localdir);
+                match batch::extract_list(&_l, &list, &localdir, &opts, concurrency).await {
+                    Err(e) => {
+                        error!("failed to extract list, error: {}", e);
+                    },
+                    Ok(res) => {
+                        let mut failed = res.failed.len();
+                        if let Some(manifest) = manifest.as_ref() {
+                            for filepath in res.extracted.iter() {
+                                let local_path = std::path::Path::new(&localdir).join(filepath);
+                                if !report_check(filepath, manifest::check_file(manifest, filepath, &local_path)) {
+                                    failed += 1;
+                                }
+                            }
+                        }
+                        for path in res.missing.iter() {
+                            println!("MISSING {}", path);
+                        }
+                        for (path, what) in res.failed.iter() {
+                            println!("FAILED {}: {}", path, what);
+                        }
+                        println!("{} files ({} bytes) and {} other entries extracted, {} fragment blocks shared, {} missing, {} failed",
+                            res.extracted.len(), res.bytes, res.others, res.fragments, res.missing.len(), failed);
+                    },
+                }
+            }
+
+            for dirpath in recursive {
+                info!("extract {} recursively from archive to {}", &dirpath, &localdir);
+                match _l.extract_tree(&dirpath, &localdir, &opts) {
+                    Err(e) => {
+                        error!("failed to extract directory {}, error: {}", &dirpath, e);
+                    },
+                    Ok(count) => {
+                        println!("{} entries extracted from {}", count, &dirpath);
+                        if let Some(manifest) = manifest.as_ref() {
+                            verify_extracted_tree(&_l, manifest, &dirpath, &localdir);
+                        }
+                    },
+                }
+            }
+
+            for filepath in filepath {
+                let filename = filepath.split("/").last();
+                if filename.is_none() {
+                    error!("invalid file path {}", &filepath);
+                    continue;
+                }
+                let output_path = localdir.clone() + "/" + filename.unwrap();
+                if offset.is_some() || length.is_some() {
+                    let offset = offset.unwrap_or(0) as u64;
+                    let length = length.map(|l| l as u64);
+                    info!("extract {} range {}+{:?} from archive to {}", &filepath, offset, length, &output_path);
+                    match extract_range(&_l, &filepath, offset, length, &localdir, filename.unwrap()) {
+                        Err(e) => error!("failed to extract range of file {}, error: {}", &filepath, e),
+                        Ok(n) => info!("{} bytes of {} extracted", n, &filepath),
+                    }
+                    if manifest.is_some() {
+                        warn!("manifest covers whole files, range of {} not verified", &filepath);
+                    }
+                    continue;
+                }
+                info!("extract {} from archive to {}", &filepath, &output_path);
+                let res = SafeDir::open_root(std::path::Path::new(&localdir))
+                    .and_then(|dir| _l.extract_one_at(&filepath, &dir, std::ffi::OsStr::new(filename.unwrap()), &opts));
+                match res {
+                    Err(e) => {
+                        error!("failed to extract file {}, error: {}", &filepath, e);
+                    },
+                    Ok(_) => {
+                        if let Some(manifest) = manifest.as_ref() {
+                            report_check(&filepath, manifest::check_file(manifest, &filepath, std::path::Path::new(&output_path)));
+                        }
+                    },
+                }
+            }
+        },
+        Cmd::List {region, bucket, key, zero, force, cachefile, chunk_size, offline, path, format, blocks} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, force, true, remote, false, offline).await {
+                Ok(local) => local,
                 Err(e) => {
-                    error!("failed to extract file {}, error: {}", &filepath, e);
+                    error!("failed to open archive, {}", e);
                     return;
                 },
-                Ok(_) => {},
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+            match format {
+                None => _l.print_list(path),
+                Some(format) => {
+                    let path = path.unwrap_or("/".to_string());
+                    if let Err(e) = write_listing(&_l, &path, format, blocks, true) {
+                        if e.kind() != std::io::ErrorKind::BrokenPipe {
+                            error!("failed to list {}, error: {}", &path, e);
+                        }
+                    }
+                },
             }
         },
-        Cmd::List {region, bucket, key, zero, force, cachefile, chunk_size, path} => {
-            let remote = Remote::new(region
-                            .or(default_region
-                                .map(|r| r.as_ref().to_string())
-                            )
-                            .expect("no region config found in cli or profile")
-                            .as_str(), &bucket, &key).await;
+        Cmd::Stat {region, bucket, key, zero, force, cachefile, chunk_size, filepath, offline, inode, format, blocks} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, force, true, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+            let filepath = match (filepath, inode) {
+                (Some(filepath), _) => filepath,
+                (None, Some(ino)) => match _l.inode_path(ino) {
+                    Ok(path) => format!("/{}", path),
+                    Err(e) => {
+                        error!("failed to resolve inode {}, error: {}", ino, e);
+                        return;
+                    },
+                },
+                (None, None) => return,
+            };
+            match format {
+                None => _l.print_stat(&filepath),
+                Some(format) => {
+                    if let Err(e) = write_listing(&_l, &filepath, format, blocks, false) {
+                        error!("failed to stat {}, error: {}", &filepath, e);
+                    }
+                },
+            }
+        },
+        Cmd::Find {region, bucket, key, cachefile, chunk_size, zero, offline, name, regex, file_type, min_size, max_size, newer, older, uid, gid, xattr, print0, format, path} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
             let hdmode;
             if zero {
                 hdmode = HoleDetectMode::ALLZERO;
@@ -161,18 +843,152 @@ async fn main() {
                 hdmode = HoleDetectMode::LSEEK;
             }
 
-            let local = Local::new(&cachefile, chunk_size, hdmode, force, true, Some(remote.clone()), false).await;
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
             let _l = local.clone();
             CONTEXT.with(|c| *c.borrow_mut() = Some(local));
-            _l.print_list(path);
+
+            let filter = Filter {
+                name: name,
+                regex: regex,
+                file_type: file_type,
+                min_size: min_size,
+                max_size: max_size,
+                newer: newer,
+                older: older,
+                uid: uid,
+                gid: gid,
+                xattr: xattr,
+            };
+            if let Err(e) = write_found(&_l, &path, &filter, format, print0) {
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    error!("failed to find under {}, error: {}", &path, e);
+                }
+            }
         },
-        Cmd::Stat {region, bucket, key, zero, force, cachefile, chunk_size, filepath} => {
-            let remote = Remote::new(region
-                            .or(default_region
-                                .map(|r| r.as_ref().to_string())
-                            )
-                            .expect("no region config found in cli or profile")
-                            .as_str(), &bucket, &key).await;
+        Cmd::Grep {region, bucket, key, cachefile, chunk_size, zero, offline, fixed_strings, files_with_matches, count, line_number, name, min_size, max_size, budget, concurrency, pattern, path} => {
+            let pattern = if fixed_strings {
+                Pattern::FIXED(pattern.into_bytes())
+            } else {
+                match regex::bytes::Regex::new(&pattern) {
+                    Ok(re) => Pattern::REGEX(re),
+                    Err(e) => {
+                        error!("invalid pattern, {}", e);
+                        return;
+                    },
+                }
+            };
+            let output = if files_with_matches {
+                Output::FILES
+            } else if count {
+                Output::COUNT
+            } else {
+                Output::LINES(line_number)
+            };
+
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let filter = Filter {
+                name: name,
+                min_size: min_size,
+                max_size: max_size,
+                ..Default::default()
+            };
+            let stdout = std::io::stdout();
+            let mut out = std::io::BufWriter::new(stdout.lock());
+            let opts = grep::Options {
+                output: output,
+                budget: budget.map(|b| b as usize),
+                concurrency: concurrency,
+            };
+            match grep::grep(&_l, &path, &filter, &pattern, &opts, &mut out).await {
+                Err(e) => {
+                    if e.kind() != std::io::ErrorKind::BrokenPipe {
+                        error!("failed to grep under {}, error: {}", &path, e);
+                    }
+                },
+                Ok(summary) => {
+                    info!("{} of {} files matched, {} lines, {} bytes fetched",
+                        summary.matched_files, summary.files, summary.matched_lines, summary.fetch_bytes);
+                    for filepath in summary.skipped.iter() {
+                        info!("skipped over budget: /{}", filepath);
+                    }
+                    for filepath in summary.failed.iter() {
+                        error!("failed to search /{}", filepath);
+                    }
+                },
+            }
+        },
+        Cmd::Diff {region, chunk_size, zero, cache_dir, content, archive_a, archive_b, path} => {
+            let region = region.or(default_region.map(|r| r.as_ref().to_string()));
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let mut archives = Vec::new();
+            for spec in [&archive_a, &archive_b] {
+                match open_archive(spec, &cache_dir, region.clone(), chunk_size, hdmode).await {
+                    Ok(local) => archives.push(local),
+                    Err(e) => {
+                        error!("{}", e);
+                        return;
+                    },
+                }
+            }
+
+            // diff switches read hook between both archives on its own
+            let diffs = match diff::diff(&archives[0], &archives[1], &path, content) {
+                Ok(diffs) => diffs,
+                Err(e) => {
+                    error!("failed to diff {}, error: {}", &path, e);
+                    return;
+                },
+            };
+            let stdout = std::io::stdout();
+            let mut output = std::io::BufWriter::new(stdout.lock());
+            let (mut added, mut removed, mut modified) = (0, 0, 0);
+            for d in diffs.iter() {
+                let res = match d.change {
+                    Change::ADDED => { added += 1; writeln!(output, "+ /{}", d.path) },
+                    Change::REMOVED => { removed += 1; writeln!(output, "- /{}", d.path) },
+                    Change::MODIFIED => { modified += 1; writeln!(output, "M /{} ({})", d.path, d.fields.join(", ")) },
+                };
+                if let Err(e) = res {
+                    if e.kind() != std::io::ErrorKind::BrokenPipe {
+                        error!("failed to write diff, error: {}", e);
+                    }
+                    return;
+                }
+            }
+            let _ = output.flush();
+            info!("{} added, {} removed, {} modified", added, removed, modified);
+        },
+        Cmd::Fsck {region, bucket, key, cachefile, chunk_size, zero, offline} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
             let hdmode;
             if zero {
                 hdmode = HoleDetectMode::ALLZERO;
@@ -180,10 +996,326 @@ async fn main() {
                 hdmode = HoleDetectMode::LSEEK;
             }
 
-            let local = Local::new(&cachefile, chunk_size, hdmode, force, true, Some(remote.clone()), false).await;
+            // broken superblock or table the archive can not open without
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    std::process::exit(1);
+                },
+            };
             let _l = local.clone();
             CONTEXT.with(|c| *c.borrow_mut() = Some(local));
-            _l.print_stat(&filepath);
+
+            let report = match _l.fsck() {
+                Ok(report) => report,
+                Err(e) => {
+                    error!("failed to check archive, error: {}", e);
+                    std::process::exit(1);
+                },
+            };
+            for p in report.problems.iter() {
+                println!("{} {}: {}", p.area.as_str(), p.what, p.detail);
+            }
+            println!("{} directories, {} files, {} symlinks, {} others", report.directories, report.files, report.symlinks, report.others);
+            println!("{} data blocks, {} sparse blocks, {} fragment blocks, {} bytes decompressed",
+                report.data_blocks, report.sparse_blocks, report.fragment_blocks, report.data_bytes);
+            if !report.is_clean() {
+                println!("{} problems found", report.problems.len());
+                std::process::exit(1);
+            }
+            println!("archive is clean");
+        },
+        Cmd::Manifest {region, bucket, key, cachefile, chunk_size, zero, offline, output, upload, concurrency} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let (manifest, failed) = match manifest::generate(&_l, "/", concurrency).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("failed to generate manifest, error: {}", e);
+                    return;
+                },
+            };
+            // a manifest missing files would fail every later verify
+            if !failed.is_empty() {
+                for filepath in failed.iter() {
+                    error!("failed to read /{}", filepath);
+                }
+                error!("{} files unreadable, manifest not written", failed.len());
+                return;
+            }
+            let output = output.unwrap_or(format!("{}{}", cachefile, MANIFEST_SUFFIX));
+            if let Err(e) = manifest.save(&output) {
+                error!("failed to write manifest {}, error: {}", &output, e);
+                return;
+            }
+            info!("manifest of {} files written to {}", manifest.len(), &output);
+            if upload {
+                if let Err(e) = _l.upload_manifest(&output).await {
+                    error!("failed to upload manifest, error: {}", e);
+                }
+            }
+        },
+        Cmd::Verify {region, bucket, key, cachefile, chunk_size, zero, offline, manifest, concurrency, path} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    std::process::exit(1);
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let manifest = match _l.manifest(manifest.as_deref()).await {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    error!("failed to load manifest, error: {}", e);
+                    std::process::exit(1);
+                },
+            };
+            let res = match manifest::verify(&_l, &manifest, &path, concurrency).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("failed to verify {}, error: {}", &path, e);
+                    std::process::exit(1);
+                },
+            };
+            for (filepath, what) in res.failed.iter() {
+                println!("FAILED /{}: {}", filepath, what);
+            }
+            for filepath in res.missing.iter() {
+                println!("MISSING /{}", filepath);
+            }
+            for filepath in res.unlisted.iter() {
+                println!("UNLISTED /{}", filepath);
+            }
+            println!("{} files checked, {} failed, {} missing, {} not in manifest",
+                res.checked, res.failed.len(), res.missing.len(), res.unlisted.len());
+            if !res.is_ok() {
+                std::process::exit(1);
+            }
+        },
+        Cmd::Plan {region, bucket, key, cachefile, chunk_size, zero, offline, from_list, json, mut paths} => {
+            if let Some(listfile) = from_list {
+                match read_path_list(&listfile) {
+                    Ok(list) => paths.extend(list),
+                    Err(e) => {
+                        error!("failed to read list {}, error: {}", &listfile, e);
+                        return;
+                    },
+                }
+            }
+
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let plan = match _l.plan(&paths) {
+                Ok(plan) => plan,
+                Err(e) => {
+                    error!("failed to plan reads, error: {}", e);
+                    return;
+                },
+            };
+            for path in plan.missing.iter() {
+                error!("{} not found in archive", path);
+            }
+            if json {
+                let v = serde_json::json!({
+                    "files": plan.files,
+                    "missing": plan.missing,
+                    "chunk_size": _l.chunk_size(),
+                    "extents": plan.extents,
+                    "bytes": plan.bytes,
+                    "cached_bytes": plan.cached_bytes,
+                    "ranges": plan.ranges,
+                    "fetch_bytes": plan.fetch_bytes,
+                    "requests": plan.requests,
+                });
+                println!("{}", v);
+            } else {
+                println!("{} files, {} extents, {} bytes compressed", plan.files, plan.extents.len(), plan.bytes);
+                println!("{} bytes cached, {} bytes in {} ranges to fetch at chunk size {}",
+                    plan.cached_bytes, plan.fetch_bytes, plan.ranges.len(), _l.chunk_size());
+                println!("{} GET requests", plan.requests);
+            }
+        },
+        Cmd::Cat {region, bucket, key, cachefile, chunk_size, zero, offline, exact, path} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), bucket, key, offline).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let mut local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, offline).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            if exact {
+                local.set_fetch_mode(FetchMode::EXTENT);
+            }
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let stdout = std::io::stdout();
+            let mut output = std::io::BufWriter::new(stdout.lock());
+            if let Err(e) = _l.extract_to(&path, &mut output) {
+                // reader closed pipe early, e.g. piped into head
+                if e.kind() != std::io::ErrorKind::BrokenPipe {
+                    error!("failed to write file {} to stdout, error: {}", &path, e);
+                }
+            }
+        },
+        Cmd::Hydrate {region, bucket, key, cachefile, chunk_size, zero, rate} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), Some(bucket), Some(key), false).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, false).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let hydrator = match local.hydrate(rate) {
+                Err(e) => {
+                    error!("failed to start hydrate, error: {}", e);
+                    return;
+                },
+                Ok(h) => h,
+            };
+
+            while !hydrator.is_finished() {
+                info!("hydrate progress {:.2}%", hydrator.progress());
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            let progress = hydrator.progress();
+            match hydrator.join() {
+                Err(e) => {
+                    error!("hydrate stopped at {:.2}%, error: {}", progress, e);
+                    return;
+                },
+                Ok(_) => {
+                    info!("hydrate completed {:.2}%", progress);
+                },
+            }
+        },
+        Cmd::Prefetch {region, bucket, key, cachefile, chunk_size, zero, trace, concurrency} => {
+            let remote = new_remote(region, default_region.map(|r| r.as_ref().to_string()), Some(bucket), Some(key), false).await;
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, chunk_size, hdmode, false, false, remote, false, false).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let res = local.prefetch_trace(&trace, concurrency).await;
+            match res {
+                Err(e) => {
+                    error!("failed to prefetch with trace {}, error: {}", &trace, e);
+                    return;
+                },
+                Ok((bytes, count)) => {
+                    info!("prefetch {} bytes with {} GET requests", bytes, count);
+                },
+            }
+        },
+        Cmd::Cache(CacheCmd::Status {cachefile, zero, path}) => {
+            let hdmode;
+            if zero {
+                hdmode = HoleDetectMode::ALLZERO;
+            } else {
+                hdmode = HoleDetectMode::LSEEK;
+            }
+
+            let local = match Local::new(&cachefile, None, hdmode, false, false, None, false, true).await {
+                Ok(local) => local,
+                Err(e) => {
+                    error!("failed to open archive, {}", e);
+                    return;
+                },
+            };
+            let _l = local.clone();
+            CONTEXT.with(|c| *c.borrow_mut() = Some(local));
+
+            let res = _l.cache_status(path);
+            match res {
+                Err(e) => {
+                    error!("failed to check cache status, error: {}", e);
+                    return;
+                },
+                Ok(v) => {
+                    let mut full = 0;
+                    for (filepath, state, cached, total) in &v {
+                        let s = match state {
+                            CacheState::FULL => "cached",
+                            CacheState::PARTIAL => "partial",
+                            CacheState::NONE => "missing",
+                        };
+                        if *state == CacheState::FULL {
+                            full += 1;
+                        }
+                        println!("{:<8} {:>12}/{:<12} {}", s, cached, total, filepath);
+                    }
+                    println!("{} of {} files fully cached", full, v.len());
+                },
+            }
         },
     }
 }
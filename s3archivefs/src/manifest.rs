@@ -0,0 +1,352 @@
+use std::io::{Read, Write, BufRead, BufReader, BufWriter, Error, ErrorKind};
+use std::collections::{BTreeMap, HashSet};
+use serde_json::{json, Value};
+use sha2::{Sha256, Digest};
+use log::warn;
+use crate::bindings::sqfs_super_t;
+use crate::repo::Local;
+use crate::entry::FileType;
+use crate::find::Filter;
+
+// sidecar next to archive, local cache file or S3 key with this suffix
+pub const MANIFEST_SUFFIX: &str = ".manifest";
+const VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDigest {
+    pub size: u64,
+    pub mtime: i64,
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Check {
+    OK,
+    // what differs
+    MISMATCH(String),
+    // path not in manifest
+    UNLISTED,
+}
+
+// checksum of every regular file, json lines: a header with archive
+// identity, then one object per file in path order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    bytes_used: u64,
+    mod_time: u32,
+    // path without leading slash
+    files: BTreeMap<String, FileDigest>,
+}
+
+impl Manifest {
+
+    pub fn new(sb: &sqfs_super_t) -> Self {
+        Self {
+            bytes_used: sb.bytes_used,
+            mod_time: sb.modification_time,
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn insert(&mut self, path: &str, digest: FileDigest) {
+        self.files.insert(path.trim_matches('/').to_string(), digest);
+    }
+
+    pub fn get(&self, path: &str) -> Option<&FileDigest> {
+        self.files.get(path.trim_matches('/'))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &FileDigest)> {
+        self.files.iter()
+    }
+
+    // made from this archive, not from another version of it
+    pub fn matches(&self, sb: &sqfs_super_t) -> bool {
+        self.bytes_used == sb.bytes_used && self.mod_time == sb.modification_time
+    }
+
+    pub fn check(&self, path: &str, size: u64, sha256: &[u8; 32]) -> Check {
+        match self.get(path) {
+            None => Check::UNLISTED,
+            Some(d) if d.size != size => Check::MISMATCH(format!("size {}, expect {}", size, d.size)),
+            Some(d) if &d.sha256 != sha256 => Check::MISMATCH(format!("sha256 {}, expect {}", to_hex(sha256), to_hex(&d.sha256))),
+            Some(_) => Check::OK,
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        let header = json!({
+            "version": VERSION,
+            "algorithm": "sha256",
+            "bytes_used": self.bytes_used,
+            "mod_time": self.mod_time,
+            "files": self.files.len(),
+        });
+        writeln!(out, "{}", header)?;
+        for (path, d) in self.files.iter() {
+            let v = json!({
+                "path": format!("/{}", path),
+                "size": d.size,
+                "mtime": d.mtime,
+                "sha256": to_hex(&d.sha256),
+            });
+            writeln!(out, "{}", v)?;
+        }
+        out.flush()
+    }
+
+    pub fn read<R: BufRead>(input: R) -> Result<Self, Error> {
+        let invalid = |msg: String| Error::new(ErrorKind::InvalidData, msg);
+        let mut lines = input.lines();
+
+        let header: Value = match lines.next() {
+            Some(line) => serde_json::from_str(&line?).map_err(|e| invalid(format!("bad manifest header, {}", e)))?,
+            None => return Err(invalid("empty manifest".to_string())),
+        };
+        if header["version"].as_u64() != Some(VERSION) || header["algorithm"] != "sha256" {
+            return Err(invalid(format!("unsupported manifest {}", header)));
+        }
+        let mut manifest = Self {
+            bytes_used: header["bytes_used"].as_u64().ok_or_else(|| invalid("no bytes_used in header".to_string()))?,
+            mod_time: header["mod_time"].as_u64().ok_or_else(|| invalid("no mod_time in header".to_string()))? as u32,
+            files: BTreeMap::new(),
+        };
+
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            if line.is_empty() {
+                continue;
+            }
+            let bad = || invalid(format!("bad manifest line {}", i + 2));
+            let v: Value = serde_json::from_str(&line).map_err(|_| bad())?;
+            let path = v["path"].as_str().ok_or_else(bad)?;
+            let digest = FileDigest {
+                size: v["size"].as_u64().ok_or_else(bad)?,
+                mtime: v["mtime"].as_i64().ok_or_else(bad)?,
+                sha256: v["sha256"].as_str().and_then(from_hex).ok_or_else(bad)?,
+            };
+            manifest.insert(path, digest);
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Self::read(BufReader::new(std::fs::File::open(path)?))
+    }
+
+    // through a temp file, so a reader never sees half of it
+    pub fn save(&self, path: &str) -> Result<(), Error> {
+        let tmp = format!("{}.tmp", path);
+        self.write(BufWriter::new(std::fs::File::create(&tmp)?))?;
+        std::fs::rename(&tmp, path)
+    }
+}
+
+pub fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+// bytes read and their sha256
+pub fn hash_reader<R: Read>(mut reader: R) -> Result<(u64, [u8; 32]), Error> {
+    let mut hash = StreamHash::default();
+    let mut buf = vec![0u8; 1 << 16];
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        hash.update(&buf[..n]);
+    }
+    Ok(hash.finish())
+}
+
+// sha256 of content fed piece by piece, for content never held whole
+#[derive(Default)]
+pub struct StreamHash {
+    hasher: Sha256,
+    size: u64,
+}
+
+impl StreamHash {
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.hasher.update(data);
+        self.size += data.len() as u64;
+    }
+
+    // bytes fed and their sha256
+    pub fn finish(self) -> (u64, [u8; 32]) {
+        (self.size, self.hasher.finalize().into())
+    }
+}
+
+// check a file already written out, e.g. by extract
+pub fn check_file(manifest: &Manifest, archive_path: &str, local_path: &std::path::Path) -> Check {
+    match std::fs::File::open(local_path).and_then(hash_reader) {
+        Ok((size, sha256)) => manifest.check(archive_path, size, &sha256),
+        Err(e) => Check::MISMATCH(format!("failed to read {}, {}", local_path.display(), e)),
+    }
+}
+
+// regular files under path, in archive offset order so reads go forward,
+// with their blocks fetched ahead in coalesced concurrent GETs, files whose
+// blocks fail to resolve are returned apart
+async fn regular_files(local: &Local, path: &str, concurrency: usize) -> Result<(Vec<(String, i64)>, Vec<String>), Error> {
+    let filter = Filter {
+        file_type: Some(FileType::REGULAR),
+        ..Default::default()
+    };
+    let mut files = Vec::new();
+    for entry in local.find(path, &filter)? {
+        match entry {
+            Ok(entry) => files.push((entry.path, entry.metadata.mtime)),
+            Err(e) => warn!("failed to read entry under {}, {}", path, e),
+        }
+    }
+    let (files, unresolved) = local.extents_by_offset(files);
+    let mut failed = Vec::new();
+    for (filepath, e) in unresolved {
+        warn!("failed to resolve blocks of {}, {}", filepath, e);
+        failed.push(filepath);
+    }
+
+    let mut ranges = Vec::new();
+    let mut order = Vec::new();
+    for (filepath, mtime, extents) in files {
+        ranges.extend(extents);
+        order.push((filepath, mtime));
+    }
+    local.prefetch_ahead(ranges, concurrency).await?;
+    Ok((order, failed))
+}
+
+// hash every regular file under path, files failed to read are returned
+// apart and left out of manifest
+pub async fn generate(local: &Local, path: &str, concurrency: usize) -> Result<(Manifest, Vec<String>), Error> {
+    let mut manifest = Manifest::new(&local.superblock());
+    let (files, mut failed) = regular_files(local, path, concurrency).await?;
+    for (filepath, mtime) in files {
+        match local.open(&filepath).and_then(hash_reader) {
+            Ok((size, sha256)) => manifest.insert(&filepath, FileDigest {
+                size: size,
+                mtime: mtime,
+                sha256: sha256,
+            }),
+            Err(e) => {
+                warn!("failed to hash {}, {}", filepath, e);
+                failed.push(filepath);
+            },
+        }
+    }
+    Ok((manifest, failed))
+}
+
+#[derive(Debug, Default)]
+pub struct Verification {
+    pub checked: usize,
+    // path and what is wrong
+    pub failed: Vec<(String, String)>,
+    // in manifest, not in archive
+    pub missing: Vec<String>,
+    // in archive, not in manifest
+    pub unlisted: Vec<String>,
+}
+
+impl Verification {
+
+    pub fn is_ok(&self) -> bool {
+        self.failed.is_empty() && self.missing.is_empty() && self.unlisted.is_empty()
+    }
+}
+
+// read every regular file under path and check it against manifest
+pub async fn verify(local: &Local, manifest: &Manifest, path: &str, concurrency: usize) -> Result<Verification, Error> {
+    let mut res = Verification::default();
+    let mut seen = HashSet::new();
+    for (filepath, mtime) in regular_files(local, path, concurrency).await? {
+        res.checked += 1;
+        let check = match local.open(&filepath).and_then(hash_reader) {
+            Ok((size, sha256)) => manifest.check(&filepath, size, &sha256),
+            Err(e) => Check::MISMATCH(format!("failed to read, {}", e)),
+        };
+        match check {
+            Check::OK => {
+                if manifest.get(&filepath).map_or(false, |d| d.mtime != mtime) {
+                    res.failed.push((filepath.clone(), "mtime differs".to_string()));
+                }
+            },
+            Check::MISMATCH(what) => res.failed.push((filepath.clone(), what)),
+            Check::UNLISTED => res.unlisted.push(filepath.clone()),
+        }
+        seen.insert(filepath);
+    }
+
+    let top = path.trim_matches('/');
+    for (filepath, _) in manifest.iter() {
+        let under = top.is_empty() || filepath == top || filepath.starts_with(&format!("{}/", top));
+        if under && !seen.contains(filepath) {
+            res.missing.push(filepath.clone());
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        let (size, sha256) = hash_reader(&b"abc"[..]).unwrap();
+        assert_eq!(size, 3);
+        let hex = to_hex(&sha256);
+        assert_eq!(hex, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(from_hex(&hex), Some(sha256));
+        assert_eq!(from_hex("ba78"), None);
+
+        let mut hash = StreamHash::default();
+        hash.update(b"a");
+        hash.update(b"bc");
+        assert_eq!(hash.finish(), (size, sha256));
+    }
+
+    #[test]
+    fn test_read_write() {
+        let mut sb: sqfs_super_t = unsafe { std::mem::zeroed() };
+        sb.bytes_used = 4096;
+        sb.modification_time = 77;
+        let mut manifest = Manifest::new(&sb);
+        let (_, sha256) = hash_reader(&b"abc"[..]).unwrap();
+        manifest.insert("/a/b", FileDigest { size: 3, mtime: 5, sha256: sha256 });
+
+        let mut buf = Vec::new();
+        manifest.write(&mut buf).unwrap();
+        let decoded = Manifest::read(&buf[..]).unwrap();
+        assert_eq!(decoded, manifest);
+        assert!(decoded.matches(&sb));
+        assert_eq!(decoded.check("a/b", 3, &sha256), Check::OK);
+        assert!(matches!(decoded.check("/a/b", 4, &sha256), Check::MISMATCH(_)));
+        assert_eq!(decoded.check("/a/c", 3, &sha256), Check::UNLISTED);
+    }
+}
@@ -1,25 +1,38 @@
 use std::path::Path;
 use std::rc::Rc;
-use std::io::{Error, ErrorKind};
-use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{Error, ErrorKind, Write};
+use std::collections::{HashMap, HashSet};
 use std::time::{Duration, Instant};
 use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::pin::Pin;
 use std::task::Poll;
 use std::future::Future;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
 use tokio::io::AsyncReadExt;
 use tokio::io::SeekFrom;
 use tokio::fs::File;
 use tokio::io::AsyncSeekExt;
 use tokio::io::AsyncWriteExt;
-use log::{debug, warn, error};
+use log::{debug, info, warn, error};
 use aws_smithy_http::byte_stream::ByteStream;
 use fs4::tokio::AsyncFileExt;
 use crate::transfer::TransferManager;
 use crate::bindings::sqfs_super_t;
 use crate::squashfs_v1;
 use crate::squashfs;
+use crate::hook_helper::is_zero;
 use crate::ArchiveFs;
+use crate::extract::{ExtractOptions, SafeDir};
+use crate::file::ArchiveFile;
+use crate::entry::{Metadata, Entry, ReadDir, WalkDir, BlockLayout, FileType};
+use crate::find::Filter;
+use crate::fsck::Report;
+use crate::manifest::{Manifest, MANIFEST_SUFFIX};
+use crate::error;
 
 thread_local! {
     pub static CONTEXT: RefCell<Option<Local>> = RefCell::new(None);
@@ -43,24 +56,21 @@ impl Remote {
     }
 
     // get superblock from object metadata
-    pub async fn get_metadata(&self) -> Result<(Vec<u8>, i64), Error> {
+    pub async fn get_metadata(&self) -> Result<(Vec<u8>, i64), error::Error> {
         let meta = self.tm.head_object(&self.bucket, &self.key).await?;
         let filesize = meta.content_length();
-        let null = String::from("");
-        let encoded: Option<&String> = meta.metadata().map(|m| {
-                m.get("s3archivefs-superblock").unwrap_or(&null)
-            });
-        let sb_bin = encoded
-                    .map(|s| base64::decode(s)
-                            .map_err(|e| {
-                                error!("failed to get superblock bin from metadata, error: {}", e);
-                                Error::new(ErrorKind::NotFound, "failed to get superblock bin from metadata")
-                            })
-                    ).unwrap()?;
-                    
+        let encoded = meta.metadata()
+                        .and_then(|m| m.get("s3archivefs-superblock"))
+                        .ok_or_else(|| error::Error::NOTFOUND(format!("superblock in metadata of s3://{}/{}", self.bucket, self.key)))?;
+        let sb_bin = base64::decode(encoded)
+                        .map_err(|e| {
+                            error!("failed to get superblock bin from metadata, error: {}", e);
+                            error::Error::INVALID(format!("failed to decode superblock from metadata, {}", e))
+                        })?;
+
         if sb_bin.len() != std::mem::size_of::<sqfs_super_t>() {
             error!("size of decoded super block vec {}, sqfs_super_t size {}", sb_bin.len(), std::mem::size_of::<sqfs_super_t>());
-            return Err(Error::new(ErrorKind::InvalidData, "incorrect superblock size"));
+            return Err(error::Error::INVALID("incorrect superblock size".to_string()));
         }
 
         Ok((sb_bin, filesize))
@@ -68,13 +78,39 @@ impl Remote {
     }
 
     // get a range
-    pub async fn get_range(&self, start: usize, end: usize) -> Result<ByteStream, Error> {
+    pub async fn get_range(&self, start: usize, end: usize) -> Result<ByteStream, error::Error> {
         let range = Some(format!("bytes={}-{}", start, end));
         debug!("range to get: {:?}", range.as_ref().unwrap());
         self.tm.download_object(&self.bucket, &self.key, range).await
     }
 
-    pub async fn intall_archivefs(&self, from: &str) -> Result<(), Error> {
+    // whole object next to archive, key of archive with suffix, into a local file
+    pub async fn get_sidecar(&self, suffix: &str, to: &str) -> Result<(), error::Error> {
+        let key = format!("{}{}", self.key, suffix);
+        let stream = self.tm.download_object(&self.bucket, &key, None).await?;
+        let tmp = format!("{}.tmp", to);
+        let mut file = File::create(&tmp).await?;
+        let mut reader = tokio::io::BufReader::new(stream.into_async_read());
+        tokio::io::copy(&mut reader, &mut file).await?;
+        file.flush().await?;
+        tokio::fs::rename(&tmp, to).await?;
+        Ok(())
+    }
+
+    pub async fn put_sidecar(&self, suffix: &str, from: &str) -> Result<(), error::Error> {
+        let key = format!("{}{}", self.key, suffix);
+        self.tm.put_object(from, &self.bucket, &key, None).await
+    }
+
+    pub fn part_size(&self) -> usize {
+        self.tm.get_part_size() as usize
+    }
+
+    pub fn concurrency(&self) -> usize {
+        self.tm.get_concurrency()
+    }
+
+    pub async fn intall_archivefs(&self, from: &str) -> Result<(), error::Error> {
         let mut file = File::open(from).await?;
         let mut buf = vec![0; std::mem::size_of::<sqfs_super_t>()];
         file.read_exact(&mut buf).await?;
@@ -91,6 +127,39 @@ pub enum HoleDetectMode {
     LSEEK,
 }
 
+// CHUNK: round every miss to chunk boundary
+// EXTENT: fetch exactly compressed extents read path needs, page aligned
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FetchMode {
+    CHUNK,
+    EXTENT,
+}
+
+// what reading a set of files takes from remote archive
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Plan {
+    pub files: usize,
+    // paths not in archive
+    pub missing: Vec<String>,
+    // compressed extents of all files merged, a shared fragment block once
+    pub extents: Vec<(usize, usize)>,
+    pub bytes: usize,
+    // of bytes, those already in local cache
+    pub cached_bytes: usize,
+    // chunk aligned ranges still to fetch, coalesced as prefetch does
+    pub ranges: Vec<(usize, usize)>,
+    pub fetch_bytes: usize,
+    // GET requests of ranges, a range over part size is split in parts
+    pub requests: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CacheState {
+    FULL,
+    PARTIAL,
+    NONE,
+}
+
 #[derive(Clone)]
 pub struct Local {
     remote: Option<Remote>,
@@ -99,16 +168,31 @@ pub struct Local {
     sb: sqfs_super_t,
     hdmode: HoleDetectMode,
     chunk_log: usize,
+    offline: bool,
+    foreground: Arc<AtomicUsize>,
+    trace: Option<Rc<std::fs::File>>,
+    fetch_mode: FetchMode,
 }
 
 unsafe impl Send for Local {}
 unsafe impl Sync for Local {}
 
 const MAX_CHUNK_SIZE: usize = 0x1_0000_0000;
+// hole granularity of local cache file, fetch in extent mode align to it
+// so that no partial written page could be taken as cached
+const PAGE_LOG: usize = 12;
+// upper bound of a coalesced range in one GET request
+const MAX_COALESCE_SIZE: usize = 0x400_0000;
+// bytes of a GET response buffered before each write to local cache
+const WRITE_BUF_SIZE: usize = 0x10_0000;
+
+// trace line format: <timestamp in ms> <event> <offset> <size>
+pub const TRACE_HIT: &str = "hit";
+pub const TRACE_FETCH: &str = "fetch";
 
 impl Local {
 
-    pub async fn new(filepath: &str, opt_chunk_size: Option<usize>, hdmode: HoleDetectMode, force: bool, init_root: bool, remote: Option<Remote>, new_ver: bool) -> Self {
+    pub async fn new(filepath: &str, opt_chunk_size: Option<usize>, hdmode: HoleDetectMode, force: bool, init_root: bool, remote: Option<Remote>, new_ver: bool, offline: bool) -> Result<Self, error::Error> {
 
         let path = Path::new(filepath);
 
@@ -121,28 +205,31 @@ impl Local {
                                 }
                             ).unwrap_or_default();
 
-        let exists = path.try_exists().expect("failed to check existance");
+        let exists = path.try_exists()?;
         debug!("local cache {} exists {}", filepath, exists);
+        if offline && (!exists || force) {
+            return Err(error::Error::NOTFOUND(format!("local cache {}, offline mode requires an existing one", filepath)));
+        }
         if !exists || force {
             if remote.is_none() {
-                panic!("both local and remote repo not exist, can not continue");
+                return Err(error::Error::NOTFOUND(format!("local cache {} and remote archive", filepath)));
             }
-            let (sb_bin, filesize) = remote.as_ref().unwrap().get_metadata().await.expect("unable to read superblock from remote");
+            let (sb_bin, filesize) = remote.as_ref().unwrap().get_metadata().await?;
 
             let mut file = tokio::fs::OpenOptions::new()
                             .read(true)
                             .write(true)
                             .create(true)
                             .truncate(force)
-                            .open(path).await.expect("failed to create local repo sparse file");
+                            .open(path).await?;
 
             // create sparse file based on file size
-            file.seek(SeekFrom::Start((filesize - 1) as u64)).await.expect("failed to seek file");
-            file.write_all(&[0]).await.expect("failed to write sparse file");
+            file.seek(SeekFrom::Start((filesize - 1) as u64)).await?;
+            file.write_all(&[0]).await?;
 
             // write superblock
-            file.seek(SeekFrom::Start(0)).await.expect("failed to seek file");
-            file.write_all(&sb_bin).await.expect("failed to write superblock to local");
+            file.seek(SeekFrom::Start(0)).await?;
+            file.write_all(&sb_bin).await?;
 
             let mut superblock = std::mem::MaybeUninit::<sqfs_super_t>::uninit();
             let sb = unsafe { 
@@ -161,24 +248,17 @@ impl Local {
 
             // align to block size boundary
             meta_start = (meta_start >> chunk_log) << chunk_log;
-            let stream = remote.as_ref()
-                            .unwrap()
-                            .get_range(meta_start as usize, (filesize - 1) as usize)
-                            .await
-                            .expect("unable to read superblock from remote");
+            file.flush().await?;
+            drop(file);
 
-            file.seek(SeekFrom::Start(meta_start)).await.expect("failed to seek file");
-            let mut reader = tokio::io::BufReader::new(stream.into_async_read());
-            let mut writer = tokio::io::BufWriter::new(&mut file);
-            tokio::io::copy(&mut reader, &mut writer).await.expect("failed to finish io copy");
-            writer.flush().await.expect("failed to flush data to local");
+            fetch_range(remote.as_ref().unwrap(), filepath, meta_start as usize, filesize as usize).await?;
         }
 
         let arcfs: Rc<dyn ArchiveFs>;
         if new_ver {
-            arcfs = Rc::new(squashfs::Archive::new_from_sparse(filepath, init_root));
+            arcfs = Rc::new(squashfs::Archive::new_from_sparse(filepath, init_root)?);
         } else {
-            arcfs = Rc::new(squashfs_v1::Archive::new_from_sparse(filepath, init_root));
+            arcfs = Rc::new(squashfs_v1::Archive::new_from_sparse(filepath, init_root)?);
         }
         arcfs.set_hook();
         let sb = arcfs.get_sb();
@@ -195,74 +275,404 @@ impl Local {
 
         let abs_pathbuf;
         if path.is_relative() {
-            let mut pb = std::env::current_dir().unwrap();
+            let mut pb = std::env::current_dir()?;
             pb.push(path);
-            abs_pathbuf = pb.as_path().canonicalize().unwrap();
+            abs_pathbuf = pb.as_path().canonicalize()?;
         } else {
             abs_pathbuf = path.to_path_buf();
         }
 
-        Self {
+        Ok(Self {
             remote: remote,
             filepath: abs_pathbuf.as_path().display().to_string(),
             sb: sb,
             arcfs: arcfs,
             hdmode: hdmode,
             chunk_log: chunk_log,
-        }
+            offline: offline,
+            foreground: Arc::new(AtomicUsize::new(0)),
+            trace: None,
+            fetch_mode: FetchMode::CHUNK,
+        })
     }
 
     pub fn hdmode(&self) -> HoleDetectMode {
         self.hdmode
     }
 
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
     pub fn request_remote_data_task(&self, start_offset: usize, req_size: usize) -> Result<(), Error> {
 
+        if self.offline {
+            debug!("offline mode, range {} - {} not in cache", start_offset, start_offset + req_size);
+            return Err(Error::new(ErrorKind::NotFound, "data not in local cache, offline mode"));
+        }
+
         if self.remote.is_none() {
             return Ok(());
         }
 
-        let aligned_start = (start_offset >> self.chunk_log) << self.chunk_log;
+        let aligned_start;
+        let aligned_end;
+        if self.fetch_mode == FetchMode::EXTENT {
+            let page_size = (1 as usize) << PAGE_LOG;
+            aligned_start = (start_offset >> PAGE_LOG) << PAGE_LOG;
+            aligned_end = std::cmp::min(((start_offset + req_size + page_size - 1) >> PAGE_LOG) << PAGE_LOG,
+                                self.arcfs.get_archive_file_size());
+            debug!("align to page boundary offset {} - {}", aligned_start, aligned_end);
+        } else {
+            aligned_start = (start_offset >> self.chunk_log) << self.chunk_log;
+
+            let chunk_size = (1 as usize) << self.chunk_log;
+            aligned_end = (((start_offset + req_size) >> self.chunk_log) << self.chunk_log) + chunk_size;
+            debug!("align end to block boundary offset {} - {}", aligned_start, aligned_end);
+        }
+
+        self.fetch_blocking(aligned_start, aligned_end)
+    }
+
+    pub fn set_fetch_mode(&mut self, mode: FetchMode) {
+        self.fetch_mode = mode;
+    }
+
+    pub fn fetch_mode(&self) -> FetchMode {
+        self.fetch_mode
+    }
+
+    // read path tells precise compressed extents it is going to read,
+    // fetch missing ones coalesced, page aligned
+    pub fn fetch_extents(&self, extents: Vec<(usize, usize)>) -> Result<(), Error> {
+
+        // misses are reported by read path in offline mode
+        if self.offline || self.remote.is_none() {
+            return Ok(());
+        }
+
+        let filesize = self.arcfs.get_archive_file_size();
+        for (offset, size) in coalesce_ranges(extents, PAGE_LOG, MAX_COALESCE_SIZE) {
+            let end = std::cmp::min(offset + size, filesize);
+            if !self.is_cached(offset, end - offset)? {
+                self.fetch_blocking(offset, end)?;
+            }
+        }
+        Ok(())
+    }
+
+    // fetch range [start, end) from remote in a dedicated thread and wait
+    fn fetch_blocking(&self, aligned_start: usize, aligned_end: usize) -> Result<(), Error> {
 
-        let chunk_size = (1 as usize) << self.chunk_log;
-        let aligned_end = (((start_offset + req_size) >> self.chunk_log) << self.chunk_log) + chunk_size;
-        debug!("align end to block boundary offset {} - {}", aligned_start, aligned_end);
+        self.trace_event(TRACE_FETCH, aligned_start, aligned_end - aligned_start);
 
         let remote = self.remote.clone();
         let filepath = self.filepath.clone();
-        std::thread::spawn(move || {
+        // let background hydration know foreground miss in progress
+        self.foreground.fetch_add(1, Ordering::SeqCst);
+        let res = std::thread::spawn(move || {
             tokio::runtime::Builder::new_current_thread()
                     .enable_all()
-                    .build()
-                    .unwrap()
+                    .build()?
                     .block_on(async {
-                let stream = remote.as_ref().unwrap().get_range(aligned_start, aligned_end - 1).await?;
-
-                let mut file = tokio::fs::OpenOptions::new()
-                                .write(true)
-                                .open(&filepath)
-                                .await?;
-                file.seek(SeekFrom::Start(aligned_start as u64)).await?;
-                // 10s for file lock wait timeout
-                let flock = FileLock::new(&file, Duration::new(10, 0));
-                flock.await?;
-                let mut reader = tokio::io::BufReader::new(stream.into_async_read());
-                let mut writer = tokio::io::BufWriter::new(&mut file);
-                tokio::io::copy(&mut reader, &mut writer).await?;
-                writer.flush().await?;
-                Ok::<(), Error>(())
+                fetch_range(remote.as_ref().unwrap(), &filepath, aligned_start, aligned_end).await
             })
-        }).join().unwrap()?;
+        }).join();
+        self.foreground.fetch_sub(1, Ordering::SeqCst);
+        res.map_err(|_| Error::new(ErrorKind::Other, "fetch thread panicked"))??;
+
+        Ok(())
+    }
+
+    // log every chunk fetch and cache hit to trace file, append if exists
+    pub fn set_trace(&mut self, tracefile: &str) -> Result<(), Error> {
+        let file = std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(tracefile)?;
+        self.trace = Some(Rc::new(file));
+        Ok(())
+    }
+
+    pub(crate) fn trace_event(&self, event: &str, offset: usize, size: usize) {
+
+        if let Some(file) = self.trace.as_ref() {
+            let ts = std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_millis();
+            let line = format!("{} {} {} {}\n", ts, event, offset, size);
+            if let Err(e) = file.as_ref().write_all(line.as_bytes()) {
+                warn!("failed to write trace, error: {}", e);
+            }
+        }
+    }
+
+    // warm local cache with ranges recorded in trace file,
+    // return bytes downloaded and count of GET requests
+    pub async fn prefetch_trace(&self, tracefile: &str, concurrency: usize) -> Result<(usize, usize), Error> {
+        let text = tokio::fs::read_to_string(tracefile).await?;
+        let ranges = parse_trace(&text)?;
+        info!("{} ranges loaded from trace {}", ranges.len(), tracefile);
+        self.prefetch(ranges, concurrency).await
+    }
+
+    // fetch ranges aligned to chunk boundary, coalesced and concurrently,
+    // skip those already in local cache
+    pub async fn prefetch(&self, ranges: Vec<(usize, usize)>, concurrency: usize) -> Result<(usize, usize), Error> {
+
+        if self.offline || self.remote.is_none() {
+            return Err(Error::new(ErrorKind::Unsupported, "prefetch requires remote archive"));
+        }
+
+        let (todo, _) = self.missing_ranges(&ranges)?;
+        debug!("{} ranges to prefetch", todo.len());
+
+        let concurrency = std::cmp::max(concurrency, 1);
+        let mut bytes = 0;
+        let mut tasks = tokio::task::JoinSet::new();
+        for (offset, size) in todo.iter() {
+            if tasks.len() >= concurrency {
+                tasks.join_next().await.unwrap().map_err(|e| Error::new(ErrorKind::Other, e))??;
+            }
+            let remote = self.remote.clone().unwrap();
+            let filepath = self.filepath.clone();
+            let (offset, size) = (*offset, *size);
+            tasks.spawn(async move {
+                fetch_range(&remote, &filepath, offset, offset + size).await
+            });
+            bytes += size;
+        }
+        while let Some(res) = tasks.join_next().await {
+            res.map_err(|e| Error::new(ErrorKind::Other, e))??;
+        }
+
+        Ok((bytes, todo.len()))
+    }
 
+    // chunk aligned and coalesced ranges covering parts of ranges not in
+    // local cache, with bytes of ranges already cached
+    // prefetch, a local image or offline archive has nothing to fetch from
+    // and its reads go through cache or fail on their own
+    pub async fn prefetch_ahead(&self, ranges: Vec<(usize, usize)>, concurrency: usize) -> Result<(), Error> {
+        match self.prefetch(ranges, concurrency).await {
+            Ok((bytes, count)) => info!("prefetch {} bytes with {} GET requests", bytes, count),
+            Err(e) if e.kind() == ErrorKind::Unsupported => debug!("no prefetch, {}", e),
+            Err(e) => return Err(e),
+        }
         Ok(())
     }
 
-    pub fn get_arcfs(&self) -> *const dyn ArchiveFs {
-        Rc::as_ptr(&self.arcfs)
+    // compressed extents of each file, sorted by offset of its first extent
+    // so reads go forward, files failed to resolve are returned apart
+    pub fn extents_by_offset<T>(&self, files: Vec<(String, T)>) -> (Vec<(String, T, Vec<(usize, usize)>)>, Vec<(String, Error)>) {
+        let mut resolved = Vec::new();
+        let mut failed = Vec::new();
+        for (path, extra) in files {
+            match self.file_extents(&path) {
+                Ok(extents) => resolved.push((path, extra, extents)),
+                Err(e) => failed.push((path, e)),
+            }
+        }
+        resolved.sort_by_key(|f| f.2.first().map_or(0, |e| e.0));
+        (resolved, failed)
+    }
+
+    fn missing_ranges(&self, ranges: &[(usize, usize)]) -> Result<(Vec<(usize, usize)>, usize), Error> {
+
+        let filesize = self.arcfs.get_archive_file_size();
+        let mut cached = 0;
+        let mut missing = Vec::new();
+        for (offset, size) in chunk_pieces(ranges, self.chunk_log) {
+            if self.is_cached(offset, size)? {
+                cached += size;
+            } else {
+                missing.push((offset, size));
+            }
+        }
+        let missing = coalesce_ranges(missing, self.chunk_log, MAX_COALESCE_SIZE).into_iter()
+                        .map(|(offset, size)| (offset, std::cmp::min(offset + size, filesize) - offset))
+                        .collect();
+        Ok((missing, cached))
+    }
+
+    // regular files of paths, a directory stands for every file below it,
+    // resolved to compressed extents and checked against local cache
+    pub fn plan(&self, paths: &[String]) -> Result<Plan, Error> {
+
+        let mut plan = Plan::default();
+        let filter = Filter {
+            file_type: Some(FileType::REGULAR),
+            ..Default::default()
+        };
+        let mut seen = HashSet::new();
+        let mut extents = Vec::new();
+        for path in paths {
+            let found = match self.find(path, &filter) {
+                Ok(found) => found,
+                Err(e) if e.kind() == ErrorKind::NotFound => {
+                    plan.missing.push(path.clone());
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+            for entry in found {
+                let entry = entry?;
+                if seen.insert(entry.path.clone()) {
+                    extents.extend(self.file_extents(&entry.path)?);
+                }
+            }
+        }
+        plan.files = seen.len();
+
+        plan.extents = coalesce_ranges(extents, 0, usize::MAX);
+        plan.bytes = plan.extents.iter().map(|e| e.1).sum();
+        let (ranges, cached) = self.missing_ranges(&plan.extents)?;
+        plan.cached_bytes = cached;
+        plan.fetch_bytes = ranges.iter().map(|r| r.1).sum();
+        plan.requests = ranges.iter().map(|r| self.requests_of(r.1)).sum();
+        plan.ranges = ranges;
+        Ok(plan)
+    }
+
+    // GET requests fetch_range makes for a range of size
+    fn requests_of(&self, size: usize) -> usize {
+        match &self.remote {
+            Some(remote) if remote.concurrency() > 1 && size > remote.part_size() => {
+                (size + remote.part_size() - 1) / remote.part_size()
+            },
+            _ => 1,
+        }
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        1 << self.chunk_log
+    }
+
+    // fill in all missing chunks of data area in offset order in background,
+    // rate in bytes per second, None or 0 for unlimited
+    pub fn hydrate(&self, rate: Option<usize>) -> Result<Hydrator, Error> {
+
+        if self.offline || self.remote.is_none() {
+            return Err(Error::new(ErrorKind::Unsupported, "hydrate requires remote archive"));
+        }
+
+        let chunk_log = self.chunk_log;
+        let chunk_size = (1 as usize) << chunk_log;
+        let data_end = self.sb.inode_table_start as usize;
+        let total = (data_end + chunk_size - 1) >> chunk_log;
+
+        let done = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let rate = rate.unwrap_or_default();
+
+        let remote = self.remote.clone().unwrap();
+        let filepath = self.filepath.clone();
+        let hdmode = self.hdmode;
+        let foreground = self.foreground.clone();
+        let _done = done.clone();
+        let _stop = stop.clone();
+        // chunk map is built from holes of local cache as worker goes, so an
+        // interrupted hydration resumes where it stopped and caller never
+        // waits for a scan, a chunk partly filled in extent mode counts as missing
+        let handle = std::thread::spawn(move || {
+            tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(async {
+                let mut fetched = 0;
+                for i in 0..total {
+                    if _stop.load(Ordering::Relaxed) {
+                        debug!("hydrate stopped");
+                        break;
+                    }
+
+                    let start = i << chunk_log;
+                    let end = std::cmp::min(start + chunk_size, data_end);
+                    if range_cached(&filepath, hdmode, PAGE_LOG, start, end - start)? {
+                        _done.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+
+                    // yield to foreground misses
+                    while foreground.load(Ordering::SeqCst) > 0 {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+
+                    // might be filled by foreground read meanwhile
+                    if !range_cached(&filepath, hdmode, PAGE_LOG, start, end - start)? {
+                        let now = Instant::now();
+                        fetch_range(&remote, &filepath, start, end).await?;
+                        fetched += 1;
+                        if rate > 0 {
+                            let expect = Duration::from_secs_f64((end - start) as f64 / rate as f64);
+                            let elapsed = now.elapsed();
+                            if expect > elapsed {
+                                tokio::time::sleep(expect - elapsed).await;
+                            }
+                        }
+                    }
+                    _done.fetch_add(1, Ordering::Relaxed);
+                }
+                info!("hydrate fetched {} of {} chunks, chunk size {}", fetched, total, chunk_size);
+                Ok::<(), Error>(())
+            })
+        });
+
+        Ok(Hydrator {
+            handle: Some(handle),
+            done: done,
+            total: total,
+            stop: stop,
+        })
+    }
+
+    // make read hook on this thread serve this archive, needed before each
+    // access when more than one archive is open
+    pub fn activate(&self) {
+        CONTEXT.with(|c| *c.borrow_mut() = Some(self.clone()));
     }
 
-    pub fn extract_one(&self, path: &str, outpath: &str) -> Result<usize, Error> {
-        self.arcfs.extract_one(path, outpath)
+    pub fn superblock(&self) -> sqfs_super_t {
+        self.sb
+    }
+
+    // extract entry at path as name in an already opened destination directory
+    pub fn extract_one_at(&self, path: &str, dir: &SafeDir, name: &OsStr, opts: &ExtractOptions) -> Result<usize, Error> {
+        self.arcfs.extract_one_at(path, dir, name, opts)
+    }
+
+    pub fn extract_tree(&self, path: &str, outdir: &str, opts: &ExtractOptions) -> Result<usize, Error> {
+        self.arcfs.extract_tree(path, outdir, opts)
+    }
+
+    pub fn extract_to(&self, path: &str, output: &mut dyn Write) -> Result<usize, Error> {
+        self.arcfs.extract_to(path, output)
+    }
+
+    pub fn read_block(&self, path: &str, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        self.arcfs.read_block(path, index)
+    }
+
+    pub fn read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.arcfs.read_at(path, offset, len)
+    }
+
+    pub fn open(&self, path: &str) -> Result<ArchiveFile, Error> {
+        self.arcfs.clone().open(path)
+    }
+
+    // decode block by block, so that async sink never waits on a whole file
+    pub async fn extract_to_async<W: tokio::io::AsyncWrite + Unpin>(&self, path: &str, output: &mut W) -> Result<usize, Error> {
+        let mut total = 0;
+        let mut i = 0;
+        while let Some(block) = self.arcfs.read_block(path, i)? {
+            output.write_all(&block).await?;
+            total += block.len();
+            i += 1;
+        }
+        output.flush().await?;
+        Ok(total)
     }
 
     pub fn print_list(&self, path: Option<String>) {
@@ -273,27 +683,173 @@ impl Local {
         self.arcfs.print_file_stat(filepath);
     }
 
-    pub fn file_list(&self, path: Option<String>) -> Vec<(String, libc::stat64)> {
-        self.arcfs.file_list(path)
+    pub fn metadata(&self, path: &str) -> Result<Metadata, Error> {
+        self.arcfs.metadata(path)
+    }
+
+    pub fn read_dir(&self, path: &str) -> Result<ReadDir, Error> {
+        self.arcfs.read_dir(path)
+    }
+
+    // byte path variants for callers like fuse, where names need not be UTF-8
+    pub fn metadata_bytes(&self, path: &[u8]) -> Result<Metadata, Error> {
+        self.arcfs.metadata_bytes(path)
+    }
+
+    pub fn read_dir_bytes(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, Result<Metadata, Error>)>, Error> {
+        self.arcfs.read_dir_bytes(path)
+    }
+
+    pub fn read_at_bytes(&self, path: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.arcfs.read_at_bytes(path, offset, len)
+    }
+
+    // path itself and every entry below it matching filter, on metadata only
+    pub fn find<'a>(&self, path: &str, filter: &'a Filter) -> Result<impl Iterator<Item = Result<Entry, Error>> + 'a, Error> {
+        let top = Entry {
+            path: path.trim_matches('/').to_string(),
+            metadata: self.metadata(path)?,
+        };
+        let mut below = None;
+        if top.metadata.is_dir() {
+            below = Some(self.walk(path)?);
+        }
+        Ok(std::iter::once(Ok(top))
+            .chain(below.into_iter().flatten())
+            .filter(move |e| e.as_ref().map_or(true, |e| filter.matches(e))))
+    }
+
+    pub fn block_layout(&self, filepath: &str) -> Result<BlockLayout, Error> {
+        self.arcfs.block_layout(filepath)
+    }
+
+    // resolve by inode number, needs export table in archive
+    pub fn inode_metadata(&self, ino: u32) -> Result<Metadata, Error> {
+        self.arcfs.inode_metadata(ino)
+    }
+
+    pub fn inode_path(&self, ino: u32) -> Result<String, Error> {
+        self.arcfs.inode_path(ino)
+    }
+
+    // index path to inode of every entry so lookups skip walking from root,
+    // with cache the index is kept in that file and reused while it matches
+    pub fn build_index(&self, cache: Option<&str>) -> Result<usize, Error> {
+        self.arcfs.build_index(cache)
+    }
+
+    // check every table, inode and data block, fetching what is not cached
+    pub fn fsck(&self) -> Result<Report, Error> {
+        self.arcfs.fsck()
+    }
+
+    // manifest of this archive, from path or sidecar of local cache, the
+    // sidecar is fetched from S3 when not there yet
+    pub async fn manifest(&self, path: Option<&str>) -> Result<Manifest, Error> {
+        let sidecar = format!("{}{}", self.filepath, MANIFEST_SUFFIX);
+        let path = path.unwrap_or(&sidecar);
+        if !Path::new(path).try_exists()? {
+            match &self.remote {
+                Some(remote) if !self.offline => {
+                    info!("fetch manifest of archive to {}", path);
+                    remote.get_sidecar(MANIFEST_SUFFIX, path).await?;
+                },
+                _ => return Err(Error::new(ErrorKind::NotFound, format!("manifest {} not found", path))),
+            }
+        }
+        let manifest = Manifest::load(path)?;
+        if !manifest.matches(&self.sb) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("manifest {} is of another archive", path)));
+        }
+        Ok(manifest)
+    }
+
+    // store manifest as sidecar object next to remote archive
+    pub async fn upload_manifest(&self, path: &str) -> Result<(), Error> {
+        match &self.remote {
+            Some(remote) if !self.offline => Ok(remote.put_sidecar(MANIFEST_SUFFIX, path).await?),
+            _ => Err(Error::new(ErrorKind::Unsupported, "no remote archive to upload manifest to")),
+        }
+    }
+
+    // every entry below path, depth first
+    pub fn walk(&self, path: &str) -> Result<WalkDir, Error> {
+        WalkDir::new(self.arcfs.clone(), path)
+    }
+
+    // test if whole range [offset, offset + size) exists in local cache
+    pub fn is_cached(&self, offset: usize, size: usize) -> Result<bool, Error> {
+
+        if size == 0 || self.is_metadata_area(offset) {
+            return Ok(true);
+        }
+
+        range_cached(&self.filepath, self.hdmode, self.piece_log(), offset, size)
+    }
+
+    // granularity local cache is filled in, extent mode fills chunks page by page
+    pub(crate) fn piece_log(&self) -> usize {
+        match self.fetch_mode {
+            FetchMode::EXTENT => PAGE_LOG,
+            FetchMode::CHUNK => self.chunk_log,
+        }
+    }
+
+    pub fn file_extents(&self, filepath: &str) -> Result<Vec<(usize, usize)>, Error> {
+        self.arcfs.file_extents(filepath)
     }
 
-    pub fn file_stat(&self, filepath: &str) -> Option<libc::stat64> {
-        self.arcfs.file_stat(filepath)
+    // report cache state of every file under path, with cached and total bytes
+    pub fn cache_status(&self, path: Option<String>) -> Result<Vec<(String, CacheState, usize, usize)>, Error> {
+
+        let filter = Filter {
+            file_type: Some(FileType::REGULAR),
+            ..Default::default()
+        };
+        let mut v = Vec::new();
+        for entry in self.find(&path.unwrap_or_else(|| "/".to_string()), &filter)? {
+            let filepath = format!("/{}", entry?.path);
+            let extents = self.file_extents(&filepath)?;
+            let mut cached = 0;
+            let mut total = 0;
+            for (offset, size) in extents {
+                if self.is_cached(offset, size)? {
+                    cached += size;
+                }
+                total += size;
+            }
+
+            let state;
+            if cached == total {
+                state = CacheState::FULL;
+            } else if cached == 0 {
+                state = CacheState::NONE;
+            } else {
+                state = CacheState::PARTIAL;
+            }
+            v.push((filepath, state, cached, total));
+        }
+        Ok(v)
     }
 
     pub fn is_metadata_area(&self, offset: usize) -> bool {
         if offset < self.sb.inode_table_start as usize {
             return false;
         } else if offset > self.arcfs.get_archive_file_size() {
-            panic!("requested offset {} is large than file size", offset);
+            // nothing to fetch there, let the read itself fail
+            warn!("requested offset {} is large than file size", offset);
         }
         return true;
     }
 
     pub fn print_superblock(&self) {
-        let filesz = std::fs::metadata(&self.filepath).unwrap().len();
-        println!("archive size:\t{}", filesz);
-        println!("metadata size:\t{}", filesz - self.sb.inode_table_start);
+        match std::fs::metadata(&self.filepath) {
+            Ok(m) => {
+                println!("archive size:\t{}", m.len());
+                println!("metadata size:\t{}", m.len().saturating_sub(self.sb.inode_table_start));
+            },
+            Err(e) => error!("failed to stat archive file {}, error: {}", self.filepath, e),
+        }
         println!("======== super block ========");
         println!("inode count:\t{}", self.sb.inode_count);
         println!("block size:\t{}", self.sb.block_size);
@@ -316,6 +872,222 @@ impl Local {
     }
 }
 
+pub struct Hydrator {
+    handle: Option<std::thread::JoinHandle<Result<(), Error>>>,
+    done: Arc<AtomicUsize>,
+    total: usize,
+    stop: Arc<AtomicBool>,
+}
+
+impl Hydrator {
+
+    // completion percentage of data area
+    pub fn progress(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        self.done.load(Ordering::Relaxed) as f64 * 100.0 / self.total as f64
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.handle.as_ref().map(|h| h.is_finished()).unwrap_or(true)
+    }
+
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    pub fn join(mut self) -> Result<(), Error> {
+        match self.handle.take() {
+            Some(h) => h.join().map_err(|_| Error::new(ErrorKind::Other, "hydrate thread panicked"))?,
+            None => Ok(()),
+        }
+    }
+}
+
+// parse trace lines into (offset, size) ranges
+pub fn parse_trace(text: &str) -> Result<Vec<(usize, usize)>, Error> {
+
+    let mut ranges = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        if fields.len() != 4 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid trace line: {}", line)));
+        }
+        let offset = fields[2].parse::<usize>()
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid offset in trace line: {}", line)))?;
+        let size = fields[3].parse::<usize>()
+                        .map_err(|_| Error::new(ErrorKind::InvalidData, format!("invalid size in trace line: {}", line)))?;
+        ranges.push((offset, size));
+    }
+    Ok(ranges)
+}
+
+// align ranges to (1 << align_log) boundary, sort and merge
+// overlapped or adjacent ones, each merged range no larger than max_size
+pub fn coalesce_ranges(mut ranges: Vec<(usize, usize)>, align_log: usize, max_size: usize) -> Vec<(usize, usize)> {
+
+    let align = (1 as usize) << align_log;
+    ranges.retain(|r| r.1 > 0);
+    for r in ranges.iter_mut() {
+        let start = (r.0 >> align_log) << align_log;
+        let end = ((r.0 + r.1 + align - 1) >> align_log) << align_log;
+        *r = (start, end - start);
+    }
+    ranges.sort();
+
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (offset, size) in ranges {
+        if let Some(last) = merged.last_mut() {
+            let last_end = last.0 + last.1;
+            if offset <= last_end {
+                last.1 = std::cmp::max(last_end, offset + size) - last.0;
+                continue;
+            }
+        }
+        merged.push((offset, size));
+    }
+
+    // no single request over max_size
+    let mut limited = Vec::with_capacity(merged.len());
+    for (offset, size) in merged {
+        let mut start = offset;
+        while start < offset + size {
+            let step = std::cmp::min(max_size, offset + size - start);
+            limited.push((start, step));
+            start += step;
+        }
+    }
+    limited
+}
+
+// split ranges at chunk boundaries, so each piece is cached or not as a whole
+pub fn chunk_pieces(ranges: &[(usize, usize)], chunk_log: usize) -> Vec<(usize, usize)> {
+
+    let mut pieces = Vec::new();
+    for (offset, size) in ranges.iter() {
+        let end = offset + size;
+        let mut start = *offset;
+        while start < end {
+            let piece_end = std::cmp::min(((start >> chunk_log) + 1) << chunk_log, end);
+            pieces.push((start, piece_end - start));
+            start = piece_end;
+        }
+    }
+    pieces
+}
+
+// offset of first piece of (1 << piece_log) all zero in data read at offset,
+// taken as not yet in local cache
+pub(crate) fn first_zero_piece(data: &[u8], offset: usize, piece_log: usize) -> Option<usize> {
+    chunk_pieces(&[(offset, data.len())], piece_log).into_iter()
+        .find(|(o, s)| is_zero(&data[o - offset..o - offset + s]))
+        .map(|(o, _)| o)
+}
+
+// test if whole range [offset, offset + size) exists in local cache file,
+// with all zero detection no piece of (1 << piece_log) in range is all zero
+fn range_cached(filepath: &str, hdmode: HoleDetectMode, piece_log: usize, offset: usize, size: usize) -> Result<bool, Error> {
+
+    let file = std::fs::File::open(filepath)?;
+    if hdmode == HoleDetectMode::ALLZERO {
+        let mut buf = vec![0; size];
+        file.read_exact_at(&mut buf, offset as u64)?;
+        return Ok(first_zero_piece(&buf, offset, piece_log).is_none());
+    }
+
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_HOLE) };
+    if hole < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(hole as usize >= offset + size)
+}
+
+// download range [start, end) of remote archive into same offset of local cache,
+// large range split into sub-range GETs running concurrently
+async fn fetch_range(remote: &Remote, filepath: &str, start: usize, end: usize) -> Result<(), Error> {
+
+    let part_size = remote.part_size();
+    let concurrency = remote.concurrency();
+    if end - start <= part_size || concurrency <= 1 {
+        return fetch_part(remote, filepath, start, end).await;
+    }
+
+    // each part written at its own offset by own handle, locking only its writes
+    debug!("split range {} - {} to parts of {}, concurrency {}", start, end, part_size, concurrency);
+    let mut tasks = tokio::task::JoinSet::new();
+    let mut part_start = start;
+    while part_start < end {
+        let part_end = std::cmp::min(part_start + part_size, end);
+        if tasks.len() >= concurrency {
+            tasks.join_next().await.unwrap().map_err(|e| Error::new(ErrorKind::Other, e))??;
+        }
+        let remote = remote.clone();
+        let filepath = filepath.to_string();
+        tasks.spawn(async move {
+            fetch_part(&remote, &filepath, part_start, part_end).await
+        });
+        part_start = part_end;
+    }
+    while let Some(res) = tasks.join_next().await {
+        res.map_err(|e| Error::new(ErrorKind::Other, e))??;
+    }
+
+    Ok(())
+}
+
+// stream range [start, end) into local cache, buffered and written piece by
+// piece, file lock is taken around each write only, never across network wait
+async fn fetch_part(remote: &Remote, filepath: &str, start: usize, end: usize) -> Result<(), Error> {
+
+    let stream = remote.get_range(start, end - 1).await?;
+
+    let mut file = tokio::fs::OpenOptions::new()
+                    .write(true)
+                    .open(filepath)
+                    .await?;
+    let mut reader = tokio::io::BufReader::new(stream.into_async_read());
+    let mut buf = vec![0u8; std::cmp::min(WRITE_BUF_SIZE, end - start)];
+    let mut offset = start;
+    loop {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = reader.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        if filled == 0 {
+            break;
+        }
+        write_locked(&mut file, offset, &buf[..filled]).await?;
+        offset += filled;
+        if filled < buf.len() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn write_locked(file: &mut tokio::fs::File, offset: usize, data: &[u8]) -> Result<(), Error> {
+
+    // 10s for file lock wait timeout
+    FileLock::new(file, Duration::new(10, 0)).await?;
+    let res = async {
+        file.seek(SeekFrom::Start(offset as u64)).await?;
+        file.write_all(data).await?;
+        file.flush().await
+    }.await;
+    if let Err(e) = file.unlock() {
+        warn!("failed to unlock local cache, error: {}", e);
+    }
+    res
+}
+
 struct FileLock<'a> {
     file: &'a tokio::fs::File,
     start: Instant,
@@ -357,3 +1129,52 @@ impl<'a> Future for FileLock<'a> {
         Poll::Pending
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_trace() {
+        let text = "1671000000000 hit 4096 1024\n\n1671000000001 fetch 0 131072\n";
+        let ranges = parse_trace(text).unwrap();
+        assert_eq!(ranges, vec![(4096, 1024), (0, 131072)]);
+        assert!(parse_trace("1671000000000 hit 4096").is_err());
+    }
+
+    #[test]
+    fn test_coalesce_ranges() {
+        // aligned to 4K and merged
+        let v = coalesce_ranges(vec![(5000, 100), (0, 10), (4096, 10)], 12, usize::MAX);
+        assert_eq!(v, vec![(0, 8192)]);
+
+        // gap in between
+        let v = coalesce_ranges(vec![(0, 4096), (16384, 1), (0, 0)], 12, usize::MAX);
+        assert_eq!(v, vec![(0, 4096), (16384, 4096)]);
+
+        // limited by max size
+        let v = coalesce_ranges(vec![(0, 4096), (4096, 4096), (8192, 4096)], 12, 8192);
+        assert_eq!(v, vec![(0, 8192), (8192, 4096)]);
+
+        // overlapping ranges and a single large one split too
+        let v = coalesce_ranges(vec![(0, 8192), (4096, 16384), (65536, 20480)], 12, 8192);
+        assert_eq!(v, vec![(0, 8192), (8192, 8192), (16384, 4096), (65536, 8192), (73728, 8192), (81920, 4096)]);
+    }
+
+    #[test]
+    fn test_first_zero_piece() {
+        let mut data = vec![1u8; 3 * 4096];
+        assert_eq!(first_zero_piece(&data, 8192, 12), None);
+        // one non zero byte does not make a missing piece cached
+        data[4096..8192].iter_mut().for_each(|b| *b = 0);
+        assert_eq!(first_zero_piece(&data, 8192, 12), Some(12288));
+        // coarser pieces partly zero are taken as cached
+        assert_eq!(first_zero_piece(&data[..8192], 8192, 13), None);
+    }
+
+    #[test]
+    fn test_chunk_pieces() {
+        let v = chunk_pieces(&[(100, 8000), (8192, 10), (0, 0)], 12);
+        assert_eq!(v, vec![(100, 3996), (4096, 4004), (8192, 10)]);
+    }
+}
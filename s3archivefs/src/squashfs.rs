@@ -1,31 +1,28 @@
 use std::ptr;
-use std::io::Error;
-use std::ffi::{CString, CStr};
+use std::io::{Error, ErrorKind};
+use std::ffi::{CString, CStr, OsStr};
+use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
 use log::{info, debug, warn};
 use libc;
 use libc::{c_char, c_void, c_int, size_t};
 use crate::bindings::*;
 use crate::hook_helper::*;
+use crate::extract::{ExtractOptions, SafeDir};
+use crate::error;
+use crate::entry::{self, FileType, Metadata, Entry, ReadDir};
+use crate::index::{self, PathIndex, Lookup};
 use super::*;
 
-#[allow(non_camel_case_types)]
-pub type sqfs_readdir_callback_t = Option<
-    unsafe extern "C" fn(
-        buf: *mut c_void,
-        name: *const c_char,
-        stbuf: *const libc::stat,
-        filler: Option<unsafe extern "C" fn()>,
-    ) -> c_int,
->;
-
-pub struct DirReader<'a> {
-    ctx: &'a mut Archive,
+pub(crate) struct DirReader<'a> {
+    ctx: &'a Archive,
     dr: *mut sqfs_dir_reader_t,
 }
 
 impl<'a> DirReader<'a> {
 
-    pub fn new(ctx: &'a mut Archive, dr: *mut sqfs_dir_reader_t) -> Self {
+    pub(crate) fn new(ctx: &'a Archive, dr: *mut sqfs_dir_reader_t) -> Self {
 
         Self {
             ctx: ctx,
@@ -42,7 +39,7 @@ impl<'a> Drop for DirReader<'a> {
 }
 
 impl<'a> Iterator for DirReader<'a> {
-    type Item = (String, libc::stat);
+    type Item = (Vec<u8>, Result<Metadata, Error>);
 
     fn next(&mut self) -> Option<Self::Item> {
 
@@ -59,24 +56,23 @@ impl<'a> Iterator for DirReader<'a> {
             return None;
         }
 
-        let name = std::str::from_utf8_unchecked(
-            std::slice::from_raw_parts((*ent).name.as_ptr(), (*ent).size as usize + 1)
-        ).to_string();
+        // names are raw bytes in archive, not always UTF-8
+        let name = std::slice::from_raw_parts((*ent).name.as_ptr(), (*ent).size as usize + 1).to_vec();
 
         let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
         let err = sqfs_dir_reader_get_inode(self.dr, ptr::addr_of_mut!(inode));
         if err != 0 {
             sqfs_free(ent as *mut c_void);
-            debug!("failed to get inode for {:?}, err: {}", name, err);
+            debug!("failed to get inode for {:?}, err: {}", String::from_utf8_lossy(&name), err);
             return None;
         }
 
-        let st = self.ctx.generic_inode_to_stat(inode);
+        let meta = self.ctx.metadata_of(inode);
 
         sqfs_free(ent as *mut c_void);
         sqfs_free(inode as *mut c_void);
 
-        Some((name, st))
+        Some((name, meta))
 
         }
     }
@@ -89,10 +85,20 @@ pub struct Archive {
     pub cmp: *mut sqfs_compressor_t,
     pub file: *mut sqfs_file_t,
     pub idtbl: *mut sqfs_id_table_t,
+    // block extents of a read are told to fetcher in extent mode
+    pub frag: *mut sqfs_frag_table_t,
+    // null when archive has no xattrs
+    pub xattr: *mut sqfs_xattr_reader_t,
+    // kept across reads so its fragment table is loaded once
+    pub data: *mut sqfs_data_reader_t,
+    pub index: RefCell<Option<Rc<PathIndex>>>,
 }
 
 impl Drop for Archive {
     fn drop(&mut self) {
+        sqfs_destroy(self.data);
+        sqfs_destroy(self.xattr);
+        sqfs_destroy(self.frag);
         sqfs_destroy(self.idtbl);
         sqfs_destroy(self.cmp);
         sqfs_destroy(self.file);
@@ -100,6 +106,13 @@ impl Drop for Archive {
     }
 }
 
+// most bytes decoded by one raw read, whose size is u32
+const READ_CHUNK: usize = 0x10_0000;
+
+fn unsupported(what: &str, path: &str) -> Error {
+    Error::new(ErrorKind::Unsupported, format!("{} of {} is not supported by this archive reader", what, path))
+}
+
 impl ArchiveFs for Archive {
 
     fn get_sb(&self) -> sqfs_super_t {
@@ -125,47 +138,143 @@ impl ArchiveFs for Archive {
         }
     }
 
-    fn extract_one(&self, path: &str, outpath: &str) -> Result<usize, Error> {
-        let _ = path;
-        let _ = outpath;
-        unimplemented!();
+    fn extract_one_at(&self, path: &str, dir: &SafeDir, name: &OsStr, opts: &ExtractOptions) -> Result<usize, Error> {
+        let _ = dir;
+        let _ = name;
+        let _ = opts;
+        Err(unsupported("extract", path))
+    }
+
+    fn extract_tree(&self, path: &str, outdir: &str, opts: &ExtractOptions) -> Result<usize, Error> {
+        let _ = outdir;
+        let _ = opts;
+        Err(unsupported("extract", path))
+    }
+
+    fn extract_to(&self, path: &str, output: &mut dyn std::io::Write) -> Result<usize, Error> {
+        let (inode, size) = self.resolve_regular(path.as_bytes())?;
+        let mut buf = vec![0u8; std::cmp::min(READ_CHUNK as u64, size) as usize];
+        let mut total = 0;
+        while (total as u64) < size {
+            let n = self.read_inode_at(&inode, &mut buf, total as u64)?;
+            if n == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, format!("{} ends at {} of {} bytes", path, total, size)));
+            }
+            output.write_all(&buf[..n])?;
+            total += n;
+        }
+        output.flush()?;
+        Ok(total)
+    }
+
+    fn read_block(&self, path: &str, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        let (inode, _) = self.resolve_regular(path.as_bytes())?;
+        self.read_inode_block(&inode, index)
+    }
+
+    fn read_inode_block(&self, inode: &file::Inode, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        let mut size: sqfs_u64 = 0;
+        unsafe { sqfs_inode_get_file_size(inode.as_ptr(), ptr::addr_of_mut!(size)) };
+        let block_size = self.sb.block_size as u64;
+        let start = index as u64 * block_size;
+        if start >= size {
+            return Ok(None);
+        }
+        let mut buf = vec![0u8; std::cmp::min(block_size, size - start) as usize];
+        let n = self.read_inode_at(inode, &mut buf, start)?;
+        buf.truncate(n);
+        Ok(Some(buf))
+    }
+
+    fn open(self: Rc<Self>, path: &str) -> Result<file::ArchiveFile, Error> {
+        let (inode, size) = self.resolve_regular(path.as_bytes())?;
+        Ok(file::ArchiveFile::from_inode(self, path, inode, size))
+    }
+
+    fn read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.read_range(path.as_bytes(), offset, len)
     }
 
     fn print_list(&self, path: Option<String>) {
-        let _ = path;
-        unimplemented!();
+        warn!("{}", unsupported("list", &path.unwrap_or_default()));
     }
 
     fn print_file_stat(&self, filepath: &str) {
-        let _ = filepath;
-        unimplemented!();
+        warn!("{}", unsupported("stat", filepath));
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, Error> {
+        self.lookup(path.as_bytes())
+    }
+
+    fn read_dir(&self, path: &str) -> Result<ReadDir, Error> {
+        let entries = self.list_dir(path.as_bytes())?;
+        Ok(ReadDir::new(entries.into_iter().map(|(name, meta)| meta.map(|m| Entry {
+            path: entry::join_path(path, &String::from_utf8_lossy(&name)),
+            metadata: m,
+        })).collect()))
+    }
+
+    fn metadata_bytes(&self, path: &[u8]) -> Result<Metadata, Error> {
+        self.lookup(path)
+    }
+
+    fn read_dir_bytes(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, Result<Metadata, Error>)>, Error> {
+        self.list_dir(path)
+    }
+
+    fn read_at_bytes(&self, path: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.read_range(path, offset, len)
+    }
+
+    fn file_extents(&self, filepath: &str) -> Result<Vec<(usize, usize)>, Error> {
+        Err(unsupported("file extents", filepath))
+    }
+
+    fn block_layout(&self, filepath: &str) -> Result<entry::BlockLayout, Error> {
+        Err(unsupported("block layout", filepath))
+    }
+
+    fn inode_metadata(&self, ino: u32) -> Result<Metadata, Error> {
+        let path = self.inode_path(ino)?;
+        self.lookup(path.as_bytes())
+    }
+
+    // only through path index, this reader does not scan directory table
+    fn inode_path(&self, ino: u32) -> Result<String, Error> {
+        match self.index.borrow().as_ref() {
+            Some(idx) => idx.path_of(ino).ok_or_else(|| Error::new(ErrorKind::NotFound, format!("inode {} not found", ino))),
+            None => Err(unsupported("inode path without path index", &ino.to_string())),
+        }
     }
 
-    fn file_list(&self, path: Option<String>) -> Vec<(String, libc::stat64)> {
-        let _ = path;
-        unimplemented!();
+    fn build_index(&self, cache: Option<&str>) -> Result<usize, Error> {
+        let idx = unsafe { PathIndex::open(self.file, self.cmp, &self.sb, cache)? };
+        let len = idx.len();
+        self.index.replace(Some(Rc::new(idx)));
+        Ok(len)
     }
 
-    fn file_stat(&self, filepath: &str) -> Option<libc::stat64> {
-        let _ = filepath;
-        unimplemented!();
+    fn fsck(&self) -> Result<fsck::Report, Error> {
+        Err(unsupported("fsck", "/"))
     }
 }
 
 impl Archive {
 
-    pub fn new(path: &str) -> Box<impl ArchiveFs> {
-        Box::new(Self::new_from_sparse(path, false))
+    pub fn new(path: &str) -> Result<Box<impl ArchiveFs>, error::Error> {
+        Ok(Box::new(Self::new_from_sparse(path, false)?))
     }
 
-    pub fn new_from_sparse(path: &str, init_root: bool) -> impl ArchiveFs {
+    pub fn new_from_sparse(path: &str, init_root: bool) -> Result<impl ArchiveFs, error::Error> {
         let _ = init_root;
         unsafe {
             Self::new_from_file(path)
         }
     }
 
-    unsafe fn new_from_file(filename: &str) -> Self {
+    // on early return drop releases what is created so far
+    unsafe fn new_from_file(filename: &str) -> Result<Self, error::Error> {
 
         let mut ctx = Self {
             sb: std::mem::zeroed(),
@@ -173,24 +282,29 @@ impl Archive {
             cmp: ptr::null_mut(),
             file: ptr::null_mut(),
             idtbl: ptr::null_mut(),
+            frag: ptr::null_mut(),
+            xattr: ptr::null_mut(),
+            data: ptr::null_mut(),
+            index: RefCell::new(None),
         };
 
         // ownership transfer to ptr
-        let filename_ptr = CString::new(filename).unwrap().into_raw();
+        let filename_ptr = CString::new(filename)
+                            .map_err(|_| error::Error::INVALID(format!("invalid path {}", filename)))?
+                            .into_raw();
         let file = sqfs_open_file(filename_ptr, SQFS_FILE_OPEN_FLAGS_SQFS_FILE_OPEN_READ_ONLY);
         // retake ptr to free memory
         let _ = CString::from_raw(filename_ptr);
         if file.is_null() {
-            panic!("can not open file {}", filename);
+            return Err(error::Error::IO(Error::new(ErrorKind::NotFound, format!("can not open file {}", filename))));
         }
+        ctx.file = file;
 
         let ret = sqfs_super_read(ptr::addr_of_mut!(ctx.sb), file);
-        if ret > 0 {
-            panic!("error reading super block");
+        if ret != 0 {
+            return Err(error::sqfs(ret, "reading super block"));
         }
 
-        ctx.file = file;
-
         sqfs_compressor_config_init(ptr::addr_of_mut!(ctx.cfg),
                         ctx.sb.compression_id as u32,
                         ctx.sb.block_size as usize,
@@ -198,490 +312,275 @@ impl Archive {
 
         let ret = sqfs_compressor_create(ptr::addr_of_mut!(ctx.cfg), ptr::addr_of_mut!(ctx.cmp));
         if ret != 0 {
-            panic!("error creating compressor");
+            return Err(error::sqfs(ret, "creating compressor"));
         }
 
         let idtbl = sqfs_id_table_create(0);
         if idtbl.is_null() {
-            panic!("error creating ID table");
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating ID table"));
         }
+        ctx.idtbl = idtbl;
 
         let ret = sqfs_id_table_read(idtbl, file, ptr::addr_of_mut!(ctx.sb), ctx.cmp);
         if ret != 0 {
-            panic!("error loading ID table");
+            return Err(error::sqfs(ret, "loading ID table"));
         }
-        ctx.idtbl = idtbl;
-
-        ctx
-    }
 
-    pub unsafe fn read(&mut self, path: *const c_char, buf: *mut c_char, size: size_t, offset: off_t) -> c_int {
-
-        debug!("read - path: {}, size: {}, offset: {}",
-            CStr::from_ptr(path).to_str().unwrap(), size, offset);
-
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
-
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
-        if dr.is_null() {
-            panic!("can not create dir reader");
+        ctx.frag = sqfs_frag_table_create(0);
+        if ctx.frag.is_null() {
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating fragment table"));
         }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
 
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        sqfs_destroy(dr as *mut c_void);
+        let ret = sqfs_frag_table_read(ctx.frag, file, ptr::addr_of_mut!(ctx.sb), ctx.cmp);
         if ret != 0 {
-            return -libc::ENOENT;
+            return Err(error::sqfs(ret, "reading fragment table"));
         }
 
-        let data = sqfs_data_reader_create(self.file, self.sb.block_size as usize, self.cmp, 0);
-        if data.is_null() {
-            panic!("can not create data reader");
+        ctx.data = sqfs_data_reader_create(file, ctx.sb.block_size as usize, ctx.cmp, 0);
+        if ctx.data.is_null() {
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating data reader"));
         }
 
-        let ret = sqfs_data_reader_load_fragment_table(data, ptr::addr_of_mut!(self.sb));
+        let ret = sqfs_data_reader_load_fragment_table(ctx.data, ptr::addr_of!(ctx.sb));
         if ret != 0 {
-            panic!("can not load fragment table");
+            return Err(error::sqfs(ret, "loading fragment table"));
         }
 
-        let mut remain = size as u32;
-        let buf_ptr = buf as *mut c_void;
-        let mut off: usize = 0;
-        loop {
-            let diff = sqfs_data_reader_read(data, inode, offset as u64 + off as u64, buf_ptr.add(off), remain);
-            if diff == 0 {
-                break;
+        if (ctx.sb.flags & SQFS_SUPER_FLAGS_SQFS_FLAG_NO_XATTRS as u16) == 0 {
+            ctx.xattr = sqfs_xattr_reader_create(0);
+            if ctx.xattr.is_null() {
+                return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating xattr reader"));
             }
-            if diff < 0 {
-                sqfs_free(inode as *mut c_void);
-                sqfs_destroy(data as *mut c_void);
-                return -libc::EIO;
-            }
-            off += diff as usize;
-            remain -= diff as u32;
-            if remain == 0 {
-                break;
-            }
-        };
-        sqfs_free(inode as *mut c_void);
-        sqfs_destroy(data as *mut c_void);
-        off as i32
-    }
-
-    pub unsafe fn readdir<'a>(&'a mut self, path: *const c_char) -> Option<DirReader> {
-
-        debug!("readdir - path: {}", CStr::from_ptr(path).to_str().unwrap());
 
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
-
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
-        if dr.is_null() {
-            panic!("can not create dir reader");
-        }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
-
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        if ret != 0 {
-            debug!("not able to find inode for path: {}", CStr::from_ptr(path).to_str().unwrap());
-            return None;
-        }
-
-        let ret = sqfs_dir_reader_open_dir(dr, inode, 0);
-        sqfs_free(inode as *mut c_void);
-        if ret != 0 {
-            warn!("failed to open dir for inode");
-            return None;
+            let ret = sqfs_xattr_reader_load(ctx.xattr, ptr::addr_of!(ctx.sb), file, ctx.cmp);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "loading xattr reader"));
+            }
         }
 
-        Some(DirReader::new(self, dr))
+        Ok(ctx)
     }
 
-    pub unsafe fn readdir_cb(&mut self, path: *const c_char, buf: *mut c_void,
-            filler: Option<unsafe extern "C" fn()>, cb: sqfs_readdir_callback_t) -> c_int {
-
-        let cb_func = cb.unwrap();
-        debug!("readdir - path: {}", CStr::from_ptr(path).to_str().unwrap());
+    // safe wrappers of the raw ops below, which stay inside the crate, each
+    // resolves path once and works on that inode
 
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
+    // inode of path, freed when dropped
+    fn resolve(&self, path: &[u8]) -> Result<file::Inode, Error> {
+        let f = CString::new(path).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        Ok(file::Inode::from_inode(unsafe { self.path_inode(f.as_ptr())? }))
+    }
 
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
-        if dr.is_null() {
-            panic!("can not create dir reader");
-        }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
-
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        if ret != 0 {
-            debug!("not able to find inode for path: {}", CStr::from_ptr(path).to_str().unwrap());
-            return -libc::ENOENT;
+    // inode of regular file at path with its size
+    fn resolve_regular(&self, path: &[u8]) -> Result<(file::Inode, u64), Error> {
+        let inode = self.resolve(path)?;
+        let st = unsafe { self.generic_inode_to_stat(inode.as_ptr()) };
+        if (st.st_mode & libc::S_IFMT) != libc::S_IFREG {
+            return Err(Error::new(ErrorKind::Other, format!("{} is not a regular file", String::from_utf8_lossy(path))));
         }
+        Ok((inode, st.st_size as u64))
+    }
 
-        let ret = sqfs_dir_reader_open_dir(dr, inode, 0);
-        sqfs_free(inode as *mut c_void);
-        if ret != 0 {
-            warn!("failed to open dir for inode");
-            return -libc::ENOENT;
+    // decode into buf from offset, at most READ_CHUNK bytes, short only at end of file
+    fn read_inode_at(&self, inode: &file::Inode, buf: &mut [u8], offset: u64) -> Result<usize, Error> {
+        let ret = unsafe {
+            self.read_inode(inode.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len(), offset as off_t)
+        };
+        if ret < 0 {
+            return Err(Error::from_raw_os_error(-ret));
         }
+        Ok(ret as usize)
+    }
 
-        loop {
-            let mut ent: *mut sqfs_dir_entry_t = ptr::null_mut();
-            let err = sqfs_dir_reader_read(dr, ptr::addr_of_mut!(ent));
-            if err > 0 {
+    // range of regular file, clamped to end of file
+    fn read_range(&self, path: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        let (inode, size) = self.resolve_regular(path)?;
+        // never allocate past end of file
+        let len = std::cmp::min(len as u64, size.saturating_sub(offset as u64)) as usize;
+        let mut buf = vec![0u8; len];
+        let mut done = 0;
+        while done < len {
+            let end = std::cmp::min(len, done + READ_CHUNK);
+            let n = self.read_inode_at(&inode, &mut buf[done..end], (offset + done) as u64)?;
+            if n == 0 {
                 break;
             }
-            if err < 0 {
-                sqfs_destroy(dr as *mut c_void);
-                return err;
-            }
-            // should_skip
-            //
-
-            let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
-            let err = sqfs_dir_reader_get_inode(dr, ptr::addr_of_mut!(inode));
-            if err > 0 {
-                sqfs_free(ent as *mut c_void);
-                sqfs_destroy(dr as *mut c_void);
-                return err;
-            }
-
-            let st = self.generic_inode_to_stat(inode);
-            cb_func(buf, (*ent).name.as_ptr() as *const c_char, ptr::addr_of!(st), filler);
-
-            sqfs_free(ent as *mut c_void);
+            done += n;
         }
-        sqfs_destroy(dr as *mut c_void);
-
-        0
+        buf.truncate(done);
+        Ok(buf)
     }
 
-    pub unsafe fn readlink(&mut self, path: *const c_char, buf: *mut c_char, size: size_t) -> c_int {
-
-        debug!("readlink() - path: {}, size: {}", CStr::from_ptr(path).to_str().unwrap(), size);
+    // inode of path, caller frees it
+    unsafe fn path_inode(&self, path: *const c_char) -> Result<*mut sqfs_inode_generic_t, Error> {
 
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
-
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
+        let dr = sqfs_dir_reader_create(ptr::addr_of!(self.sb), self.cmp, self.file, 0x1);
         if dr.is_null() {
-            panic!("can not create dir reader");
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating dir reader").into());
         }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
-
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
+        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
+        let ret = self.find_inode(dr, path, ptr::addr_of_mut!(inode));
         sqfs_destroy(dr as *mut c_void);
         if ret != 0 {
-            return -libc::ENOENT;
-        }
-
-        let ret;
-        match (*inode).base.type_ as u32 {
-            SQFS_INODE_TYPE_SQFS_INODE_SLINK => {
-                let link_size = (*inode).data.slink.target_size as usize;
-                if link_size + 1 > size {
-                    ret = -libc::EIO;
-                } else {
-                    let link_target = (*inode).extra.as_ptr() as *const c_void;
-                    libc::memset(buf as *mut c_void, 0, link_size + 1);
-                    libc::memcpy(buf as *mut c_void, link_target, link_size);
-                    ret = 0;
-                }
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => {
-                let link_size = (*inode).data.slink_ext.target_size as usize;
-                if link_size + 1 > size {
-                    ret = - libc::EIO;
-                } else {
-                    let link_target = (*inode).extra.as_ptr() as *const c_void;
-                    libc::memset(buf as *mut c_void, 0, link_size + 1);
-                    libc::memcpy(buf as *mut c_void, link_target, link_size);
-                    ret = 0;
-                }
-            },
-            _ => {
-                ret = 0;
-            },
+            return Err(error::sqfs(ret, &format!("looking up {}", CStr::from_ptr(path).to_string_lossy())).into());
         }
-        sqfs_free(inode as *mut c_void);
-        ret
+        Ok(inode)
     }
 
-    pub unsafe fn getattr(&mut self, path: *const c_char, stbuf: *mut libc::stat) -> c_int {
-
-        debug!("getattr - path: {}", CStr::from_ptr(path).to_str().unwrap());
-
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
-
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
-        if dr.is_null() {
-            panic!("can not create dir reader");
+    // extended attributes of inode, empty when it has none
+    unsafe fn inode_xattrs(&self, inode: *const sqfs_inode_generic_t) -> Result<HashMap<Vec<u8>, Vec<u8>>, Error> {
+        if self.xattr.is_null() {
+            return Ok(HashMap::new());
         }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
-
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        sqfs_destroy(dr as *mut c_void);
-        if ret != 0 {
-            return -libc::ENOENT;
+        let mut index: sqfs_u32 = 0xFFFFFFFF;
+        sqfs_inode_get_xattr_index(inode, ptr::addr_of_mut!(index));
+        if index == 0xFFFFFFFF {
+            return Ok(HashMap::new());
         }
-
-        (*stbuf) = self.generic_inode_to_stat(inode);
-        sqfs_free(inode as *mut c_void);
-
-        0
+        Ok(squashfs_v1::xattr_set(self.xattr, index)?)
     }
 
-    pub unsafe fn getxattr(&mut self, path: *const c_char, name: *const c_char, value: *mut c_char, size: size_t) -> c_int {
-
-        debug!("getxattr() - path: {}, name: {}, size: {}",
-            CStr::from_ptr(path).to_str().unwrap(), CStr::from_ptr(name).to_str().unwrap(), size);
-
-        if name.is_null() {
-            return -libc::ENODATA;
-        }
+    // stat, link target and xattrs all from one inode
+    pub(crate) unsafe fn metadata_of(&self, inode: *mut sqfs_inode_generic_t) -> Result<Metadata, Error> {
+        let st = self.generic_inode_to_stat(inode);
+        let file_type = FileType::from_mode(st.st_mode)
+            .ok_or_else(|| error::Error::INVALID(format!("unknown file mode {:o} of inode {}", st.st_mode, st.st_ino)))?;
+        let link_target = match file_type {
+            FileType::SYMLINK => Some(squashfs_v1::Archive::link_target(inode)),
+            _ => None,
+        };
+        Ok(Metadata {
+            file_type: file_type,
+            size: st.st_size as u64,
+            mode: st.st_mode & !libc::S_IFMT,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mtime: st.st_mtime,
+            nlink: st.st_nlink as u32,
+            rdev: st.st_rdev as u32,
+            ino: st.st_ino as u32,
+            xattrs: self.inode_xattrs(inode)?,
+            link_target: link_target,
+        })
+    }
 
-        let name_len = libc::strlen(name);
-        if name_len == 0 {
-            return -libc::ENODATA;
-        }
+    pub fn xattrs(&self, path: &str) -> Result<HashMap<Vec<u8>, Vec<u8>>, Error> {
+        let inode = self.resolve(path.as_bytes())?;
+        unsafe { self.inode_xattrs(inode.as_ptr()) }
+    }
 
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
-        let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
+    pub fn read_link(&self, path: &str) -> Result<Vec<u8>, Error> {
+        self.lookup(path.as_bytes())?.link_target
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("{} is not a symlink", path)))
+    }
 
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
-        if dr.is_null() {
-            panic!("can not create dir reader");
-        }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
+    pub fn lookup(&self, path: &[u8]) -> Result<Metadata, Error> {
+        let inode = self.resolve(path)?;
+        unsafe { self.metadata_of(inode.as_ptr()) }
+    }
 
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        sqfs_destroy(dr as *mut c_void);
-        if ret != 0 {
-            return -libc::ENODATA;
-        }
+    // names and metadata of entries in directory at path, metadata of each is
+    // taken from the inode dir reader already loaded
+    pub fn list_dir(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, Result<Metadata, Error>)>, Error> {
+        let f = CString::new(path).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let dr = match unsafe { self.readdir(f.as_ptr()) } {
+            Some(dr) => dr,
+            None => return Err(Error::new(ErrorKind::NotFound, "Entry not found")),
+        };
+        Ok(dr.collect())
+    }
 
-        let mut xattr_idx = 0xFFFFFFFF;
+    // inode of path, from the index when built, otherwise walk from root
+    unsafe fn find_inode(&self, dr: *mut sqfs_dir_reader_t, path: *const c_char,
+            inode: *mut *mut sqfs_inode_generic_t) -> c_int {
 
-        match (*inode).base.type_ as u32 {
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_BDEV |
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_CDEV => {
-                xattr_idx = (*inode).data.dev_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_FIFO |
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_SOCKET => {
-                xattr_idx = (*inode).data.ipc_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => {
-                xattr_idx = (*inode).data.slink_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE => {
-                xattr_idx = (*inode).data.file_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => {
-                xattr_idx = (*inode).data.dir_ext.xattr_idx;
+        let found = match (self.index.borrow().as_ref(), CStr::from_ptr(path).to_str()) {
+            (Some(idx), Ok(p)) => idx.lookup(p),
+            _ => Lookup::UNKNOWN,
+        };
+        match found {
+            Lookup::FOUND(e) => index::read_inode(self.file, self.cmp, &self.sb, e.inode_ref, inode),
+            Lookup::MISSING => SQFS_ERROR_SQFS_ERROR_NO_ENTRY,
+            Lookup::UNKNOWN => {
+                let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
+                let ret = sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
+                if ret != 0 {
+                    return ret;
+                }
+                let ret = sqfs_dir_reader_find_by_path(dr, root, path, inode);
+                sqfs_free(root as *mut c_void);
+                ret
             },
-            _ => {
-                debug!("type {} is not a ext inode", (*inode).base.type_);
-            }
-        }
-
-        if xattr_idx == 0xFFFFFFFF {
-            sqfs_free(inode as *mut c_void);
-            return -libc::ENODATA;
-        }
-
-        let mut desc: sqfs_xattr_id_t = std::mem::zeroed();
-
-        let xr = sqfs_xattr_reader_create(0);
-        if xr.is_null() {
-            panic!("error creating xattr reader");
-        }
-
-        let ret = sqfs_xattr_reader_load(xr, std::ptr::addr_of!(self.sb), self.file, self.cmp);
-        if ret != 0 {
-            panic!("error loading xattr reader");
-        }
-
-        if sqfs_xattr_reader_get_desc(xr, xattr_idx, std::ptr::addr_of_mut!(desc)) != 0 {
-            panic!("unable to resolve xattr idx: {}", xattr_idx);
         }
+    }
 
-        let ret = sqfs_xattr_reader_seek_kv(xr, std::ptr::addr_of_mut!(desc));
-        if ret != 0 {
-            panic!("error locating xattr key-value pairs");
+    // read range of an already resolved inode, which stays owned by caller
+    unsafe fn read_inode(&self, inode: *const sqfs_inode_generic_t, buf: *mut c_char, size: size_t, offset: off_t) -> c_int {
+
+        // blocks covering the range, fetched in one go in extent mode
+        let mut file_size: sqfs_u64 = 0;
+        sqfs_inode_get_file_size(inode, ptr::addr_of_mut!(file_size));
+        let end = std::cmp::min(offset as u64 + size as u64, file_size);
+        if (offset as u64) < end {
+            let block_size = self.sb.block_size as u64;
+            let first = (offset as u64 / block_size) as usize;
+            let last = ((end - 1) / block_size) as usize;
+            if let Err(e) = squashfs_v1::prefetch_block_extents(self.frag, inode, first, last) {
+                warn!("failed to fetch extents of block #{} to #{}, error: {}", first, last, e);
+                return -libc::EIO;
+            }
         }
 
-        let mut key: *mut sqfs_xattr_entry_t = ptr::null_mut();
-        let mut val: *mut sqfs_xattr_value_t = ptr::null_mut();
-
-        let mut count = desc.count;
-        let mut val_size: c_int = 0;
-        while count > 0 {
-
-            let ret = sqfs_xattr_reader_read_key(xr, std::ptr::addr_of_mut!(key));
-            if ret != 0 {
-                panic!("error reading xattr key, err: {}", ret);
+        let mut remain = size as u32;
+        let buf_ptr = buf as *mut c_void;
+        let mut off: usize = 0;
+        loop {
+            let diff = sqfs_data_reader_read(self.data, inode, offset as u64 + off as u64, buf_ptr.add(off), remain);
+            if diff == 0 {
+                break;
             }
-
-            let ret = sqfs_xattr_reader_read_value(xr, key, std::ptr::addr_of_mut!(val));
-            if ret != 0 {
-                sqfs_free(key as *mut c_void);
-                panic!("error reading xattr value");
+            if diff < 0 {
+                return -libc::EIO;
             }
-
-            debug!("found xattr key {} - size {}, val {} - size {}",
-                CStr::from_ptr((*key).key.as_ptr() as *const i8).to_str().unwrap(), (*key).size,
-                CStr::from_ptr((*val).value.as_ptr() as *const i8).to_str().unwrap(), (*val).size);
-
-            let ret = libc::strncmp(name, (*key).key.as_ptr() as *const i8, name_len);
-            if ret == 0 {
-                val_size = (*val).size as c_int;
-                if size != 0 {
-                    if val_size <= size as c_int {
-                        libc::memcpy(value as *mut c_void, (*val).value.as_ptr() as *const c_void, val_size as usize);
-                    } else {
-                        val_size = -libc::ERANGE;
-                    }
-                } else {
-                    // if input size is zero, do nothing
-                    // just return size of value
-                }
-                sqfs_free(key as *mut c_void);
-                sqfs_free(val as *mut c_void);
+            off += diff as usize;
+            remain -= diff as u32;
+            if remain == 0 {
                 break;
             }
-            sqfs_free(key as *mut c_void);
-            sqfs_free(val as *mut c_void);
-            count -= 1;
-        }
-
-        sqfs_free(inode as *mut c_void);
-        sqfs_destroy(xr as *mut c_void);
-
-        val_size as c_int
+        };
+        off as i32
     }
 
-    pub unsafe fn listxattr(&mut self, path: *const c_char, list: *mut c_char, size: size_t) -> c_int {
+    pub(crate) unsafe fn readdir<'a>(&'a self, path: *const c_char) -> Option<DirReader> {
 
-        debug!("listxattr() - path: {}, size: {}", CStr::from_ptr(path).to_str().unwrap(), size);
+        debug!("readdir - path: {}", CStr::from_ptr(path).to_string_lossy());
 
-        let mut root: *mut sqfs_inode_generic_t = ptr::null_mut();
         let mut inode: *mut sqfs_inode_generic_t = ptr::null_mut();
 
-        let dr = sqfs_dir_reader_create(ptr::addr_of_mut!(self.sb), self.cmp, self.file, 0x1);
+        let dr = sqfs_dir_reader_create(ptr::addr_of!(self.sb), self.cmp, self.file, 0x1);
         if dr.is_null() {
-            panic!("can not create dir reader");
-        }
-        sqfs_dir_reader_get_root_inode(dr, ptr::addr_of_mut!(root));
-
-        let ret = sqfs_dir_reader_find_by_path(dr, root, path, ptr::addr_of_mut!(inode));
-        sqfs_free(root as *mut c_void);
-        sqfs_destroy(dr as *mut c_void);
-        if ret != 0 {
-            return -libc::ENOENT;
-        }
-
-        let mut xattr_idx = 0xFFFFFFFF;
-
-        match (*inode).base.type_ as u32 {
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_BDEV |
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_CDEV => {
-                xattr_idx = (*inode).data.dev_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_FIFO |
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_SOCKET => {
-                xattr_idx = (*inode).data.ipc_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => {
-                xattr_idx = (*inode).data.slink_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE => {
-                xattr_idx = (*inode).data.file_ext.xattr_idx;
-            },
-            SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => {
-                xattr_idx = (*inode).data.dir_ext.xattr_idx;
-            },
-            _ => {
-                debug!("type {} is not a ext inode", (*inode).base.type_);
-            }
-        }
-
-        if xattr_idx == 0xFFFFFFFF {
-            sqfs_free(inode as *mut c_void);
-            return -libc::ENOENT;
-        }
-
-        let mut desc: sqfs_xattr_id_t = std::mem::zeroed();
-
-        let xr = sqfs_xattr_reader_create(0);
-        if xr.is_null() {
-            panic!("error creating xattr reader");
+            warn!("can not create dir reader");
+            return None;
         }
 
-        let ret = sqfs_xattr_reader_load(xr, std::ptr::addr_of!(self.sb), self.file, self.cmp);
+        let ret = self.find_inode(dr, path, ptr::addr_of_mut!(inode));
         if ret != 0 {
-            panic!("error loading xattr reader");
-        }
-
-        if sqfs_xattr_reader_get_desc(xr, xattr_idx, std::ptr::addr_of_mut!(desc)) != 0 {
-            panic!("unable to resolve xattr idx: {}", xattr_idx);
+            debug!("not able to find inode for path: {}", CStr::from_ptr(path).to_string_lossy());
+            sqfs_destroy(dr as *mut c_void);
+            return None;
         }
 
-        let ret = sqfs_xattr_reader_seek_kv(xr, std::ptr::addr_of_mut!(desc));
+        let ret = sqfs_dir_reader_open_dir(dr, inode, 0);
+        sqfs_free(inode as *mut c_void);
         if ret != 0 {
-            panic!("error locating xattr key-value pairs");
-        }
-
-        let mut key: *mut sqfs_xattr_entry_t = ptr::null_mut();
-        let mut val: *mut sqfs_xattr_value_t = ptr::null_mut();
-
-        let mut count = desc.count;
-        let mut list_size: c_int = 0;
-        libc::memset(list as *mut c_void, 0, size as usize);
-        while count > 0 {
-
-            let ret = sqfs_xattr_reader_read_key(xr, std::ptr::addr_of_mut!(key));
-            if ret != 0 {
-                panic!("error reading xattr key");
-            }
-
-            let ret = sqfs_xattr_reader_read_value(xr, key, std::ptr::addr_of_mut!(val));
-            if ret != 0 {
-                sqfs_free(key as *mut c_void);
-                panic!("error reading xattr value");
-            }
-
-            let prefix = sqfs_get_xattr_prefix((*key).type_ as SQFS_XATTR_TYPE & SQFS_XATTR_TYPE_SQFS_XATTR_PREFIX_MASK);
-            let prefix_len = libc::strlen(prefix) as c_int;
-
-            if size != 0 {
-
-                libc::memcpy((list as *mut c_void).offset(list_size as isize),
-                    (*key).key.as_ptr() as *const c_void,
-                    (*key).size as usize + prefix_len as usize);
-            }
-
-            list_size += (*key).size as c_int + prefix_len + 1;
-            sqfs_free(key as *mut c_void);
-            sqfs_free(val as *mut c_void);
-            count -= 1;
+            warn!("failed to open dir for inode");
+            sqfs_destroy(dr as *mut c_void);
+            return None;
         }
 
-        sqfs_free(inode as *mut c_void);
-        sqfs_destroy(xr as *mut c_void);
-
-        list_size as c_int
+        Some(DirReader::new(self, dr))
     }
 
-    pub unsafe fn generic_inode_to_stat(&mut self, inode: *mut sqfs_inode_generic_t) -> libc::stat {
+    pub(crate) unsafe fn generic_inode_to_stat(&self, inode: *mut sqfs_inode_generic_t) -> libc::stat {
 
         let mut st: libc::stat = std::mem::zeroed();
         let mut xattr_idx = 0xFFFFFFFF;
@@ -744,7 +643,7 @@ impl Archive {
                 xattr_idx = (*inode).data.dir_ext.xattr_idx;
             },
              _ => {
-                 todo!();
+                 warn!("unknown inode type {}", (*inode).base.type_);
              }
         }
 
@@ -766,25 +665,12 @@ impl Archive {
             st.st_gid = gid;
         }
 
-        if xattr_idx != 0xFFFFFFFF {
-
+        // xattr size only adds to st_blocks, on error leave it out
+        if xattr_idx != 0xFFFFFFFF && !self.xattr.is_null() {
             let mut desc: sqfs_xattr_id_t = std::mem::zeroed();
-
-            let xattr_rd = sqfs_xattr_reader_create(0);
-            if xattr_rd.is_null() {
-                panic!("error creating xattr reader");
-            }
-
-            let ret = sqfs_xattr_reader_load(xattr_rd, ptr::addr_of_mut!(self.sb), self.file, self.cmp);
-            if ret != 0 {
-                panic!("error loading xattr reader");
-            }
-
-            if sqfs_xattr_reader_get_desc(xattr_rd, xattr_idx, ptr::addr_of_mut!(desc)) != 0 {
-                panic!("unable to resolve xattr idx: {}", xattr_idx);
-            }
-
-            if desc.size > 0 {
+            if sqfs_xattr_reader_get_desc(self.xattr, xattr_idx, ptr::addr_of_mut!(desc)) != 0 {
+                warn!("unable to resolve xattr idx: {}", xattr_idx);
+            } else if desc.size > 0 {
                 st.st_blocks += ((desc.size as i64 - 1) >> 9) + 1;
             }
         }
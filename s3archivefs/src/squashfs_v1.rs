@@ -1,23 +1,37 @@
 use std::io::{Error, ErrorKind};
-use std::io::Write;
+use std::io::{Write, Seek, SeekFrom};
 use std::time::Instant;
 use std::mem::MaybeUninit;
-use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 use std::collections::HashMap;
+use std::cell::RefCell;
+use std::rc::Rc;
 use std::ffi::{CString, CStr, OsStr};
 use std::os::unix::ffi::OsStrExt;
-use log::{info, debug, error};
+use log::{info, debug, warn, error};
 use libc::{c_void, size_t};
 use nix::sys::stat::SFlag;
 use crate::bindings::*;
 use crate::hook_helper::*;
+use crate::repo::{CONTEXT, FetchMode};
 use crate::ArchiveFs;
+use crate::extract;
+use crate::error;
+use crate::entry::{self, FileType, Metadata, Entry, ReadDir, BlockLayout, Fragment};
+use crate::index::{self, PathIndex, Lookup};
+use crate::fsck::{self, Area};
 use super::*;
 
 fn s_isreg(st_mode: sqfs_u16) -> bool {
     (SFlag::S_IFMT.bits() & st_mode as u32) == SFlag::S_IFREG.bits()
 }
 
+// this reader looks paths up as str, byte paths must be valid UTF-8
+fn utf8_path(path: &[u8]) -> Result<&str, Error> {
+    std::str::from_utf8(path).map_err(|_| Error::new(ErrorKind::Unsupported,
+        format!("non UTF-8 path {} is not supported by this archive reader", String::from_utf8_lossy(path))))
+}
+
 #[derive(Clone)]
 pub struct Archive {
     file: *mut sqfs_file_t,
@@ -28,6 +42,10 @@ pub struct Archive {
     dir: *mut sqfs_dir_reader_t,
     root: *mut sqfs_tree_node_t,
     data: *mut sqfs_data_reader_t,
+    frag: *mut sqfs_frag_table_t,
+    index: RefCell<Option<Rc<PathIndex>>>,
+    // inode reference by inode number - 1, empty when not exportable
+    export: Vec<u64>,
 }
 
 impl Drop for Archive {
@@ -35,6 +53,7 @@ impl Drop for Archive {
         unsafe {
             sqfs_dir_tree_destroy(self.root)
         };
+        sqfs_destroy(self.frag);
         sqfs_destroy(self.data);
         sqfs_destroy(self.dir);
         sqfs_destroy(self.idtbl);
@@ -69,8 +88,37 @@ impl ArchiveFs for Archive {
         }
     }
 
-    fn extract_one(&self, path: &str, outpath: &str) -> Result<usize, Error> {
-        self.do_extract_one(path, outpath)
+    fn extract_one_at(&self, path: &str, dir: &extract::SafeDir, name: &OsStr, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+        self.do_extract_one_at(path, dir, name, opts)
+    }
+
+    fn extract_tree(&self, path: &str, outdir: &str, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+        self.do_extract_tree(path, outdir, opts)
+    }
+
+    fn extract_to(&self, path: &str, output: &mut dyn Write) -> Result<usize, Error> {
+        self.do_extract_to(path, output)
+    }
+
+    fn read_block(&self, path: &str, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        self.do_read_block(path, index)
+    }
+
+    fn read_inode_block(&self, inode: &file::Inode, index: usize) -> Result<Option<Vec<u8>>, Error> {
+        unsafe {
+            self.prefetch_block_extents(inode.as_ptr(), index, index)?;
+            self.decode_block(inode.as_ptr(), index, "opened file")
+        }
+    }
+
+    fn open(self: Rc<Self>, path: &str) -> Result<file::ArchiveFile, Error> {
+        let inode = unsafe { file::Inode::from_node(self.lookup_file(path)?) };
+        let size = unsafe { Self::inode_file_size(inode.as_ptr()) } as u64;
+        Ok(file::ArchiveFile::from_inode(self, path, inode, size))
+    }
+
+    fn read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.do_read_at(path, offset, len)
     }
 
     fn print_list(&self, path: Option<String>) {
@@ -81,454 +129,1380 @@ impl ArchiveFs for Archive {
         self.do_print_file_stat(filepath)
     }
 
-    fn file_list(&self, path: Option<String>) -> Vec<(String, libc::stat64)> {
+    fn metadata(&self, path: &str) -> Result<Metadata, Error> {
+        self.do_metadata(path)
+    }
+
+    fn read_dir(&self, path: &str) -> Result<ReadDir, Error> {
+        let entries = self.do_list_dir(path)?;
+        Ok(ReadDir::new(entries.into_iter().map(|(name, meta)| meta.map(|m| Entry {
+            path: entry::join_path(path, &String::from_utf8_lossy(&name)),
+            metadata: m,
+        })).collect()))
+    }
+
+    fn metadata_bytes(&self, path: &[u8]) -> Result<Metadata, Error> {
+        self.do_metadata(utf8_path(path)?)
+    }
+
+    fn read_dir_bytes(&self, path: &[u8]) -> Result<Vec<(Vec<u8>, Result<Metadata, Error>)>, Error> {
+        self.do_list_dir(utf8_path(path)?)
+    }
+
+    fn read_at_bytes(&self, path: &[u8], offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+        self.do_read_at(utf8_path(path)?, offset, len)
+    }
+
+    fn block_layout(&self, filepath: &str) -> Result<BlockLayout, Error> {
+        unsafe {
+            self.do_block_layout(filepath)
+        }
+    }
+
+    fn inode_metadata(&self, ino: u32) -> Result<Metadata, Error> {
+        self.do_inode_metadata(ino)
+    }
+
+    fn inode_path(&self, ino: u32) -> Result<String, Error> {
+        self.do_inode_path(ino)
+    }
+
+    fn build_index(&self, cache: Option<&str>) -> Result<usize, Error> {
+        let idx = unsafe { PathIndex::open(self.file, self.cmp, &*self.sb, cache)? };
+        let len = idx.len();
+        self.index.replace(Some(Rc::new(idx)));
+        Ok(len)
+    }
+
+    fn file_extents(&self, filepath: &str) -> Result<Vec<(usize, usize)>, Error> {
+        unsafe {
+            self.do_file_extents(filepath)
+        }
+    }
+
+    fn fsck(&self) -> Result<fsck::Report, Error> {
+        self.do_fsck()
+    }
+}
+
+impl Archive {
+
+    pub fn new(path: &str) -> Result<Box<impl ArchiveFs>, error::Error> {
+        Ok(Box::new(Self::new_from_sparse(path, false)?))
+    }
+
+    pub fn new_from_sparse(path: &str, init_root: bool) -> Result<impl ArchiveFs, error::Error> {
+        Self::do_new_from_sparse(path, init_root)
+    }
+
+    fn do_new_from_sparse(path: &str, init_root: bool) -> Result<Self, error::Error> {
+        let f = CString::new(path).map_err(|_| error::Error::INVALID(format!("invalid path {}", path)))?;
+        let mut cfg = MaybeUninit::<sqfs_compressor_config_t>::uninit();
+        unsafe {
+            // fill in one by one, on early return drop releases what is created so far
+            let mut arc = Self {
+                file: std::ptr::null_mut(),
+                sb: Box::new(std::mem::zeroed()),
+                cmp: std::ptr::null_mut(),
+                xattr: std::ptr::null_mut(),
+                idtbl: std::ptr::null_mut(),
+                dir: std::ptr::null_mut(),
+                root: std::ptr::null_mut(),
+                data: std::ptr::null_mut(),
+                frag: std::ptr::null_mut(),
+                index: RefCell::new(None),
+                export: Vec::new(),
+            };
+
+            arc.file = sqfs_open_file(f.as_ptr(), SQFS_FILE_OPEN_FLAGS_SQFS_FILE_OPEN_READ_ONLY);
+            if arc.file.is_null() {
+                return Err(error::Error::IO(Error::new(ErrorKind::NotFound, format!("can not open file {}", path))));
+            }
+            let file = arc.file;
+
+            let sb_p: *mut sqfs_super_t = &mut *arc.sb;
+            let ret = sqfs_super_read(sb_p, file);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "reading super block"));
+            }
+
+            sqfs_compressor_config_init(cfg.as_mut_ptr(),
+                    arc.sb.compression_id as u32,
+                    arc.sb.block_size as usize,
+                    SQFS_COMP_FLAG_SQFS_COMP_FLAG_UNCOMPRESS as u16);
+
+            let ret = sqfs_compressor_create(cfg.as_mut_ptr(), &mut arc.cmp);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "creating compressor"));
+            }
+            let cmp = arc.cmp;
+
+            if (arc.sb.flags & SQFS_SUPER_FLAGS_SQFS_FLAG_NO_XATTRS as u16) == 0 {
+                arc.xattr = sqfs_xattr_reader_create(0);
+                if arc.xattr.is_null() {
+                    return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating xattr reader"));
+                }
+
+                let ret = sqfs_xattr_reader_load(arc.xattr, sb_p, file, cmp);
+                if ret != 0 {
+                    return Err(error::sqfs(ret, "loading xattr reader"));
+                }
+            }
+
+            arc.idtbl = sqfs_id_table_create(0);
+            if arc.idtbl.is_null() {
+                return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating ID table"));
+            }
+
+            let ret = sqfs_id_table_read(arc.idtbl, file, sb_p, cmp);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "loading ID table"));
+            }
+
+            // optional, archive without it still opens, only inode lookup fails
+            if (arc.sb.flags & SQFS_SUPER_FLAGS_SQFS_FLAG_EXPORTABLE as u16) != 0
+                    && arc.sb.export_table_start != u64::MAX {
+                match Self::read_export_table(file, cmp, &*arc.sb) {
+                    Ok(export) => arc.export = export,
+                    Err(e) => warn!("failed to load export table, {}", e),
+                }
+            }
+
+            arc.dir = sqfs_dir_reader_create(sb_p, cmp, file, 0);
+            if arc.dir.is_null() {
+                return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating directory reader"));
+            }
+
+            if init_root {
+                let ret = sqfs_dir_reader_get_full_hierarchy(arc.dir, arc.idtbl, std::ptr::null(), 0, &mut arc.root);
+                if ret != 0 {
+                    return Err(error::sqfs(ret, "loading directory tree"));
+                }
+            }
+
+            arc.data = sqfs_data_reader_create(file, arc.sb.block_size as usize, cmp, 0);
+            if arc.data.is_null() {
+                return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating data reader"));
+            }
+
+            let ret = sqfs_data_reader_load_fragment_table(arc.data, sb_p);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "loading fragment table"));
+            }
+
+            arc.frag = sqfs_frag_table_create(0);
+            if arc.frag.is_null() {
+                return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating fragment table"));
+            }
+
+            let ret = sqfs_frag_table_read(arc.frag, file, sb_p, cmp);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "reading fragment table"));
+            }
+
+            // as soon as init all struct, hook read_at
+            let read_at = (*file).read_at.replace(archive_read_at);
+            let _ = (*file).write_at.replace(
+                std::mem::transmute::<ReadAtType, WriteAtType>(read_at.unwrap())
+            );
+
+            info!("s3 archive fs init success");
+            Ok(arc)
+        }
+    }
+
+    unsafe fn read_export_table(file: *mut sqfs_file_t, cmp: *mut sqfs_compressor_t,
+            sb: &sqfs_super_t) -> Result<Vec<u64>, error::Error> {
+
+        let count = sb.inode_count as usize;
+        let mut tbl: *mut c_void = std::ptr::null_mut();
+        let ret = sqfs_read_table(file, cmp, count * 8, sb.export_table_start,
+                    sb.directory_table_start, sb.export_table_start, &mut tbl);
+        if ret != 0 {
+            return Err(error::sqfs(ret, "reading export table"));
+        }
+        let export = std::slice::from_raw_parts(tbl as *const u64, count)
+                        .iter()
+                        .map(|r| u64::from_le(*r))
+                        .collect();
+        sqfs_free(tbl);
+        Ok(export)
+    }
+
+    fn collect_xattrs(&self, inode: *const sqfs_inode_generic_t) -> Option<HashMap<Vec<u8>, Vec<u8>>> {
+
+        if self.xattr.is_null() {
+            return None;
+        }
+
+        let mut index = MaybeUninit::<sqfs_u32>::uninit();
+        unsafe {
+            sqfs_inode_get_xattr_index(inode, index.as_mut_ptr());
+            let index = index.assume_init();
+
+            if index == 0xFFFFFFFF {
+                return None;
+            }
+
+            match self.xattr_set(index) {
+                Ok(kv) => Some(kv),
+                Err(e) => {
+                    error!("{}", e);
+                    None
+                },
+            }
+        }
+    }
+
+    // key value pairs of xattr set #index
+    unsafe fn xattr_set(&self, index: u32) -> Result<HashMap<Vec<u8>, Vec<u8>>, error::Error> {
+        xattr_set(self.xattr, index)
+    }
+
+    // extract entry at path as name in dir, never resolving an output path
+    fn do_extract_one_at(&self, path: &str, dir: &extract::SafeDir, name: &OsStr, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+
+        if let Err(e) = extract::check_path(path) {
+            return opts.skip_unsafe(Err(e), Path::new(path));
+        }
+
+        let filesz;
+        let now = Instant::now();
+        debug!("start to extract file {}", path);
+        unsafe {
+            // node freed on every return below
+            let node = file::Inode::from_node(self.lookup_node(path)?);
+            let n = node.as_node();
+            let inode = node.as_ptr();
+
+            debug!("{:>6}: {:?}", "name", CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap());
+            debug!("{:>6}: {}", "uid", (*n).uid);
+            debug!("{:>6}: {}", "gid", (*n).gid);
+            debug!("{:>6}: {}", "mode", (*inode).base.mode);
+            debug!("{:>6}: {}", "type", (*inode).base.type_);
+            debug!("{:>6}: {}", "modt", (*inode).base.mod_time);
+            debug!("{:>6}: {}", "ino", (*inode).base.inode_number);
+
+            let node_type = (*inode).base.type_ as u32;
+            if node_type == SQFS_INODE_TYPE_SQFS_INODE_DIR || node_type == SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR {
+                let s = format!("{} is a directory", path);
+                info!("{}", &s);
+                return Err(Error::new(ErrorKind::Other, s));
+            }
+
+            let outpath = dir.child_path(name).display().to_string();
+
+            if !s_isreg((*inode).base.mode) {
+                if self.extract_special(n, dir, name, opts)? {
+                    info!("output entry write to {}", outpath);
+                }
+                return Ok(0);
+            }
+
+            let ino = (*inode).base.inode_number;
+            let stat = Self::stat(n)?;
+            if opts.links.link(ino, dir, name)? {
+                info!("output file linked at {}", outpath);
+                return Ok(stat.st_size as usize);
+            }
+
+            self.prefetch_extents(inode)?;
+
+            let mut output = dir.create_file(name)?;
+            filesz = self.write_file_data(inode, &mut output, path)?;
+            if stat.st_nlink > 1 {
+                opts.links.record(ino, dir, name);
+            }
+            debug!("file content flushed to {}, cost: {:?}", outpath, now.elapsed());
+            let now = Instant::now();
+
+            // fill metadata for output file
+            dir.set_metadata(name, &self.entry_meta(n))?;
+            debug!("write all metadata cost: {:?}", now.elapsed());
+            info!("output file write to {}", outpath);
+        }
+        Ok(filesz)
+    }
+
+    unsafe fn inode_file_size(inode: *const sqfs_inode_generic_t) -> usize {
+        let mut file_size = MaybeUninit::<sqfs_u64>::uninit();
+        sqfs_inode_get_file_size(inode, file_size.as_mut_ptr());
+        file_size.assume_init() as usize
+    }
+
+    // decode data block #i of a regular file, or its fragment tail when i equals
+    // block count, sink gets decoded bytes, or None with length of a sparse block,
+    // return false when i is past the end of file
+    unsafe fn with_block(&self, inode: *const sqfs_inode_generic_t, i: usize, path: &str,
+            sink: &mut dyn FnMut(Option<&[u8]>, usize) -> Result<(), Error>) -> Result<bool, Error> {
+
+        let file_size = Self::inode_file_size(inode);
+        let block_size = self.sb.block_size as usize;
+        let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
+
+        let mut chunk = MaybeUninit::<*mut sqfs_u8>::uninit();
+        let mut chunk_size = MaybeUninit::<size_t>::uninit();
+
+        if i < blk_cnt {
+            let read = std::cmp::min(block_size, file_size - i * block_size);
+
+            // sparse block has no data on disk
+            if (*inode).extra.as_slice(blk_cnt)[i] & ((1 << 24) - 1) == 0 {
+                sink(None, read)?;
+                return Ok(true);
+            }
+
+            let ret = sqfs_data_reader_get_block(self.data, inode, i, chunk_size.as_mut_ptr(), chunk.as_mut_ptr());
+            if ret != 0 {
+                error!("error reading data block #{} of {}: {}", i, path, ret);
+                return Err(Error::from_raw_os_error(libc::EIO));
+            }
+        } else if i == blk_cnt && file_size > blk_cnt * block_size {
+            debug!("file has {} fragmented", file_size - blk_cnt * block_size);
+            let ret = sqfs_data_reader_get_fragment(self.data, inode, chunk_size.as_mut_ptr(), chunk.as_mut_ptr());
+            if ret != 0 {
+                error!("error reading fragment block of {}: {}", path, ret);
+                return Err(Error::from_raw_os_error(libc::EIO));
+            }
+        } else {
+            return Ok(false);
+        }
+
+        let chunk = chunk.assume_init();
+        let chunk_size = chunk_size.assume_init();
+        let buf = std::slice::from_raw_parts(chunk, chunk_size);
+        let res = sink(Some(buf), chunk_size);
+        libc::free(chunk as *mut c_void);
+        res?;
+        Ok(true)
+    }
+
+    // write all blocks then fragment of a regular file, return file size
+    unsafe fn write_file_data(&self, inode: *const sqfs_inode_generic_t, output: &mut std::fs::File, path: &str) -> Result<usize, Error> {
+
+        let filesz = Self::inode_file_size(inode);
+        debug!("{:>6}: {}", "size", filesz);
+
+        let mut sink = |buf: Option<&[u8]>, len: usize| -> Result<(), Error> {
+            match buf {
+                Some(buf) => output.write_all(buf),
+                // leave a hole instead of zeros
+                None => output.seek(SeekFrom::Current(len as i64)).map(|_| ()),
+            }
+        };
+        let mut i = 0;
+        while self.with_block(inode, i, path, &mut sink)? {
+            i += 1;
+        }
+        output.flush()?;
+        // trailing holes only moved file offset, extend to full size
+        output.set_len(filesz as u64)?;
+        Ok(filesz)
+    }
+
+    // stream all blocks then fragment of a regular file to writer, return file size
+    unsafe fn stream_file_data(&self, inode: *const sqfs_inode_generic_t, output: &mut dyn Write, path: &str) -> Result<usize, Error> {
+
+        let mut sink = |buf: Option<&[u8]>, len: usize| -> Result<(), Error> {
+            match buf {
+                Some(buf) => output.write_all(buf),
+                None => output.write_all(&vec![0u8; len]),
+            }
+        };
+        let mut i = 0;
+        while self.with_block(inode, i, path, &mut sink)? {
+            i += 1;
+        }
+        output.flush()?;
+        Ok(Self::inode_file_size(inode))
+    }
+
+    // load tree below path, whole tree or only direct children with NO_RECURSE,
+    // caller destroys the node
+    unsafe fn load_tree(&self, f: &CStr, flags: u32) -> Result<*mut sqfs_tree_node_t, Error> {
+
+        if let Some(n) = self.load_indexed(f, flags)? {
+            return Ok(n);
+        }
+
+        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
+        let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), flags, n.as_mut_ptr());
+        if ret != 0 {
+            if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
+                debug!("Entry not found");
+                return Err(Error::new(ErrorKind::NotFound, "Entry not found"));
+            }
+            error!("error loading directory tree of {:?}, {}", f, ret);
+            return Err(error::sqfs(ret, "loading directory tree").into());
+        }
+        Ok(n.assume_init())
+    }
+
+    // resolve path through the index, None when there is no index, the path
+    // is not normal or a whole directory tree is wanted, node shape matches the
+    // dir reader without flags (parent of the node left null, children point to
+    // it), so any flag other than no recurse, like filtering out devices or
+    // storing parents, goes the slow way
+    unsafe fn load_indexed(&self, f: &CStr, flags: u32) -> Result<Option<*mut sqfs_tree_node_t>, Error> {
+
+        if (flags & !SQFS_TREE_FILTER_FLAGS_SQFS_TREE_NO_RECURSE) != 0 {
+            return Ok(None);
+        }
+        let idx = match self.index.borrow().clone() {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let path = match f.to_str() {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        let e = match idx.lookup(path) {
+            Lookup::FOUND(e) => e,
+            Lookup::MISSING => {
+                debug!("Entry not found");
+                return Err(Error::new(ErrorKind::NotFound, "Entry not found"));
+            },
+            Lookup::UNKNOWN => return Ok(None),
+        };
+        if e.is_dir() && (flags & SQFS_TREE_FILTER_FLAGS_SQFS_TREE_NO_RECURSE) == 0 {
+            return Ok(None);
+        }
+
+        let mut inode: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+        let ret = index::read_inode(self.file, self.cmp, &*self.sb, e.inode_ref, &mut inode);
+        if ret != 0 {
+            return Err(error::sqfs(ret, &format!("reading inode of {}", path)).into());
+        }
+        let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+        let n = self.new_node(inode, name.as_bytes())?;
+        if e.is_dir() {
+            if let Err(e) = self.load_children(n) {
+                sqfs_dir_tree_destroy(n);
+                return Err(e);
+            }
+        }
+        Ok(Some(n))
+    }
+
+    // tree node as the dir reader would build it, takes the inode
+    unsafe fn new_node(&self, inode: *mut sqfs_inode_generic_t, name: &[u8]) -> Result<*mut sqfs_tree_node_t, Error> {
+
+        let n = libc::calloc(1, std::mem::size_of::<sqfs_tree_node_t>() + name.len() + 1) as *mut sqfs_tree_node_t;
+        if n.is_null() {
+            sqfs_free(inode as *mut c_void);
+            return Err(Error::new(ErrorKind::OutOfMemory, "allocating tree node"));
+        }
+        (*n).inode = inode;
+        std::ptr::copy_nonoverlapping(name.as_ptr(), (*n).name.as_mut_ptr(), name.len());
+
+        let mut ret = sqfs_id_table_index_to_id(self.idtbl, (*inode).base.uid_idx, &mut (*n).uid);
+        if ret == 0 {
+            ret = sqfs_id_table_index_to_id(self.idtbl, (*inode).base.gid_idx, &mut (*n).gid);
+        }
+        if ret != 0 {
+            sqfs_dir_tree_destroy(n);
+            return Err(error::sqfs(ret, "resolving uid and gid").into());
+        }
+        Ok(n)
+    }
+
+    // attach direct children of a directory node, in directory order
+    unsafe fn load_children(&self, n: *mut sqfs_tree_node_t) -> Result<(), Error> {
+
+        let ret = sqfs_dir_reader_open_dir(self.dir, (*n).inode, 0);
+        if ret != 0 {
+            return Err(error::sqfs(ret, "opening directory").into());
+        }
+
+        let mut last: *mut sqfs_tree_node_t = std::ptr::null_mut();
+        loop {
+            let mut ent: *mut sqfs_dir_entry_t = std::ptr::null_mut();
+            let ret = sqfs_dir_reader_read(self.dir, &mut ent);
+            if ret > 0 {
+                break;
+            }
+            if ret < 0 {
+                return Err(error::sqfs(ret, "reading directory").into());
+            }
+            let name = std::slice::from_raw_parts((*ent).name.as_ptr(), (*ent).size as usize + 1).to_vec();
+            sqfs_free(ent as *mut c_void);
+
+            let mut inode: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+            let ret = sqfs_dir_reader_get_inode(self.dir, &mut inode);
+            if ret != 0 {
+                return Err(error::sqfs(ret, "reading inode").into());
+            }
+            let c = self.new_node(inode, &name)?;
+            (*c).parent = n;
+            if last.is_null() {
+                (*n).children = c;
+            } else {
+                (*last).next = c;
+            }
+            last = c;
+        }
+        Ok(())
+    }
+
+    // look up node with its direct children, caller destroys the node
+    unsafe fn lookup_node(&self, path: &str) -> Result<*mut sqfs_tree_node_t, Error> {
+
+        let f = CString::new(path).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        self.load_tree(&f, SQFS_TREE_FILTER_FLAGS_SQFS_TREE_NO_RECURSE)
+    }
+
+    // look up node of a regular file, caller destroys the node
+    unsafe fn lookup_file(&self, path: &str) -> Result<*mut sqfs_tree_node_t, Error> {
+
+        let n = self.lookup_node(path)?;
+        if !s_isreg((*(*n).inode).base.mode) {
+            sqfs_dir_tree_destroy(n);
+            return Err(Error::new(ErrorKind::Other, format!("{} is not a regular file", path)));
+        }
+        Ok(n)
+    }
+
+    fn do_extract_to(&self, path: &str, output: &mut dyn Write) -> Result<usize, Error> {
+
+        let now = Instant::now();
+        unsafe {
+            let n = self.lookup_file(path)?;
+            let inode = (*n).inode;
+            let res = self.prefetch_extents(inode)
+                        .and_then(|_| self.stream_file_data(inode, output, path));
+            sqfs_dir_tree_destroy(n);
+            debug!("file {} streamed, cost: {:?}", path, now.elapsed());
+            res
+        }
+    }
+
+    // decode only blocks covering the range, clamped to end of file
+    fn do_read_at(&self, path: &str, offset: usize, len: usize) -> Result<Vec<u8>, Error> {
+
+        unsafe {
+            let n = self.lookup_file(path)?;
+            let inode = (*n).inode;
+
+            let file_size = Self::inode_file_size(inode);
+            let end = std::cmp::min(offset.saturating_add(len), file_size);
+            if offset >= end {
+                sqfs_dir_tree_destroy(n);
+                return Ok(Vec::new());
+            }
+
+            let block_size = self.sb.block_size as usize;
+            let first = offset / block_size;
+            let last = (end - 1) / block_size;
+            debug!("read {} range {}-{} from block #{} to #{}", path, offset, end, first, last);
+
+            let mut buf = Vec::with_capacity(end - offset);
+            let mut i = first;
+            let mut res = self.prefetch_block_extents(inode, first, last);
+            while res.is_ok() && i <= last {
+                let blk_start = i * block_size;
+                let mut sink = |data: Option<&[u8]>, blk_len: usize| -> Result<(), Error> {
+                    let from = offset.saturating_sub(blk_start);
+                    let to = std::cmp::min(end - blk_start, blk_len);
+                    match data {
+                        Some(data) => buf.extend_from_slice(&data[from..to]),
+                        None => buf.resize(buf.len() + to - from, 0),
+                    }
+                    Ok(())
+                };
+                res = self.with_block(inode, i, path, &mut sink).map(|_| ());
+                i += 1;
+            }
+            sqfs_dir_tree_destroy(n);
+            res?;
+            Ok(buf)
+        }
+    }
+
+    fn do_read_block(&self, path: &str, index: usize) -> Result<Option<Vec<u8>>, Error> {
+
+        unsafe {
+            let n = self.lookup_file(path)?;
+            let res = self.decode_block((*n).inode, index, path);
+            sqfs_dir_tree_destroy(n);
+            res
+        }
+    }
+
+    // copy of decoded block #index, zeros for a sparse block, None past end of file
+    unsafe fn decode_block(&self, inode: *const sqfs_inode_generic_t, index: usize, path: &str) -> Result<Option<Vec<u8>>, Error> {
+
+        let mut block = None;
+        let mut sink = |buf: Option<&[u8]>, len: usize| -> Result<(), Error> {
+            match buf {
+                Some(buf) => block = Some(Vec::from(buf)),
+                None => block = Some(vec![0u8; len]),
+            }
+            Ok(())
+        };
+        self.with_block(inode, index, path, &mut sink)?;
+        Ok(block)
+    }
+
+    // bytes of a regular file stored as sparse blocks
+    unsafe fn sparse_bytes(&self, inode: *const sqfs_inode_generic_t) -> u64 {
+
+        if (*inode).base.type_ as u32 == SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE {
+            return (*inode).data.file_ext.sparse;
+        }
+
+        let mut size = MaybeUninit::<sqfs_u64>::uninit();
+        sqfs_inode_get_file_size(inode, size.as_mut_ptr());
+        let size = size.assume_init();
+        let block_size = self.sb.block_size as u64;
+
+        let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
+        (*inode).extra.as_slice(blk_cnt).iter().enumerate()
+            .filter(|(_, x)| **x & ((1 << 24) - 1) == 0)
+            .map(|(i, _)| std::cmp::min(block_size, size - i as u64 * block_size))
+            .sum()
+    }
+
+    unsafe fn entry_meta(&self, n: *const sqfs_tree_node_t) -> extract::EntryMeta {
+
+        let inode = (*n).inode;
+        let node_type = (*inode).base.type_ as u32;
+        extract::EntryMeta {
+            mode: (*inode).base.mode as u32,
+            uid: (*n).uid,
+            gid: (*n).gid,
+            mtime: (*inode).base.mod_time as i64,
+            xattrs: self.collect_xattrs(inode),
+            symlink: node_type == SQFS_INODE_TYPE_SQFS_INODE_SLINK || node_type == SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK,
+        }
+    }
+
+    pub(crate) unsafe fn link_target(inode: *const sqfs_inode_generic_t) -> Vec<u8> {
+
+        let size = match (*inode).base.type_ as u32 {
+            SQFS_INODE_TYPE_SQFS_INODE_SLINK => (*inode).data.slink.target_size as usize,
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => (*inode).data.slink_ext.target_size as usize,
+            _ => 0,
+        };
+        let target = std::slice::from_raw_parts((*inode).extra.as_ptr() as *const u8, size);
+        Vec::from(target)
+    }
+
+    unsafe fn node_metadata(&self, n: *const sqfs_tree_node_t) -> Result<Metadata, Error> {
+
+        let inode = (*n).inode;
+        let st = Self::stat(n)?;
+        let file_type = FileType::from_mode(st.st_mode)
+            .ok_or_else(|| error::Error::INVALID(format!("unknown file mode {:o} of inode {}", st.st_mode, st.st_ino)))?;
+        let link_target = match file_type {
+            FileType::SYMLINK => Some(Self::link_target(inode)),
+            _ => None,
+        };
+        Ok(Metadata {
+            file_type: file_type,
+            size: st.st_size as u64,
+            mode: st.st_mode & !libc::S_IFMT,
+            uid: st.st_uid,
+            gid: st.st_gid,
+            mtime: st.st_mtime,
+            nlink: st.st_nlink as u32,
+            rdev: st.st_rdev as u32,
+            ino: st.st_ino as u32,
+            xattrs: self.collect_xattrs(inode).unwrap_or_default(),
+            link_target: link_target,
+        })
+    }
+
+    fn do_metadata(&self, path: &str) -> Result<Metadata, Error> {
+
         unsafe {
-            self.do_file_list(path)
+            let n = self.lookup_node(path)?;
+            let meta = self.node_metadata(n);
+            sqfs_dir_tree_destroy(n);
+            meta
+        }
+    }
+
+    // inode by number through the export table, caller frees the inode
+    unsafe fn inode_by_number(&self, ino: u32) -> Result<*mut sqfs_inode_generic_t, Error> {
+
+        if self.export.is_empty() {
+            return Err(Error::new(ErrorKind::Unsupported, "archive has no export table"));
+        }
+        if ino == 0 || ino as usize > self.export.len() {
+            return Err(Error::new(ErrorKind::NotFound, format!("inode {} not found", ino)));
+        }
+        let mut inode: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+        let ret = index::read_inode(self.file, self.cmp, &*self.sb, self.export[ino as usize - 1], &mut inode);
+        if ret != 0 {
+            return Err(error::sqfs(ret, &format!("reading inode {}", ino)).into());
+        }
+        Ok(inode)
+    }
+
+    unsafe fn dir_parent(inode: *const sqfs_inode_generic_t) -> Option<u32> {
+        match (*inode).base.type_ as u32 {
+            SQFS_INODE_TYPE_SQFS_INODE_DIR => Some((*inode).data.dir.parent_inode),
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => Some((*inode).data.dir_ext.parent_inode),
+            _ => None,
+        }
+    }
+
+    // name of entry with inode number ino in directory, matched on inode
+    // numbers from the directory table without reading sibling inodes
+    unsafe fn child_name(&self, dir: *const sqfs_inode_generic_t, ino: u32) -> Result<String, Error> {
+
+        let dm = sqfs_meta_reader_create(self.file, self.cmp, self.sb.directory_table_start, self.sb.bytes_used);
+        if dm.is_null() {
+            return Err(error::sqfs(SQFS_ERROR_SQFS_ERROR_ALLOC, "creating directory table reader").into());
+        }
+        let mut name = None;
+        let res = index::dir_entries(dm, &*self.sb, dir, "parent directory", &mut |n, e| {
+            if e.inode_num == ino {
+                name = Some(String::from_utf8_lossy(n).to_string());
+                return false;
+            }
+            true
+        });
+        sqfs_destroy(dm);
+        res?;
+        name.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("inode {} not in its parent", ino)))
+    }
+
+    // path of a directory, following parent inodes up to root
+    unsafe fn dir_path(&self, ino: u32) -> Result<String, Error> {
+
+        let mut names = Vec::new();
+        let mut cur = ino;
+        while self.export[cur as usize - 1] != self.sb.root_inode_ref {
+            if names.len() > self.export.len() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("parent loop above inode {}", ino)));
+            }
+            let inode = self.inode_by_number(cur)?;
+            let parent = Self::dir_parent(inode);
+            sqfs_free(inode as *mut c_void);
+            let parent = parent.ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("parent of inode {} is not a directory", ino)))?;
+
+            let pinode = self.inode_by_number(parent)?;
+            let name = self.child_name(pinode, cur);
+            sqfs_free(pinode as *mut c_void);
+            names.push(name?);
+            cur = parent;
+        }
+        names.reverse();
+        Ok(names.join("/"))
+    }
+
+    fn do_inode_metadata(&self, ino: u32) -> Result<Metadata, Error> {
+
+        unsafe {
+            let inode = self.inode_by_number(ino)?;
+            let n = self.new_node(inode, b"")?;
+            let meta = self.node_metadata(n);
+            sqfs_dir_tree_destroy(n);
+            meta
+        }
+    }
+
+    // directories know their parent, anything else is found by scanning the
+    // directory table unless the path index is built, with hard links the
+    // first path in order is returned
+    fn do_inode_path(&self, ino: u32) -> Result<String, Error> {
+
+        let not_found = || Error::new(ErrorKind::NotFound, format!("inode {} not found", ino));
+        if let Some(idx) = self.index.borrow().clone() {
+            return idx.path_of(ino).ok_or_else(not_found);
+        }
+        unsafe {
+            let inode = self.inode_by_number(ino)?;
+            let parent = Self::dir_parent(inode);
+            sqfs_free(inode as *mut c_void);
+            if parent.is_some() {
+                return self.dir_path(ino);
+            }
+            debug!("no path index, scan directory table for inode {}", ino);
+            let idx = PathIndex::build(self.file, self.cmp, &*self.sb)?;
+            idx.path_of(ino).ok_or_else(not_found)
+        }
+    }
+
+    // names kept as bytes, metadata of each child may fail on its own
+    fn do_list_dir(&self, path: &str) -> Result<Vec<(Vec<u8>, Result<Metadata, Error>)>, Error> {
+
+        unsafe {
+            let n = self.lookup_node(path)?;
+            let node_type = (*(*n).inode).base.type_ as u32;
+            if node_type != SQFS_INODE_TYPE_SQFS_INODE_DIR && node_type != SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR {
+                sqfs_dir_tree_destroy(n);
+                return Err(Error::new(ErrorKind::Other, format!("{} is not a directory", path)));
+            }
+
+            let mut entries = Vec::new();
+            let mut c = (*n).children as *const sqfs_tree_node_t;
+            while !c.is_null() {
+                let name = CStr::from_ptr((*c).name.as_ptr() as *const std::ffi::c_char).to_bytes();
+                entries.push((name.to_vec(), self.node_metadata(c)));
+                c = (*c).next;
+            }
+            sqfs_dir_tree_destroy(n);
+            Ok(entries)
+        }
+    }
+
+    fn do_extract_tree(&self, path: &str, outdir: &str, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+
+        if let Err(e) = extract::check_path(path) {
+            return opts.skip_unsafe(Err(e), Path::new(path));
+        }
+
+        let f = CString::new(path).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        let now = Instant::now();
+        debug!("start to extract tree {}", path);
+        unsafe {
+            let n = self.load_tree(&f, 0)?;
+
+            let root = match extract::SafeDir::open_root(Path::new(outdir)) {
+                Ok(root) => root,
+                Err(e) => {
+                    sqfs_dir_tree_destroy(n);
+                    return Err(e);
+                },
+            };
+
+            // extract root of archive into outdir itself, otherwise into outdir/<name>,
+            // outdir belongs to user and keeps its own metadata
+            let name = path.trim_end_matches('/').rsplit('/').next().unwrap_or("");
+            let res = if name.is_empty() {
+                self.extract_children(n, &root, opts)
+            } else {
+                self.extract_node(n, &root, OsStr::new(name), opts)
+            };
+            sqfs_dir_tree_destroy(n);
+            if let Ok(count) = res {
+                info!("{} entries of {} write to {}, cost: {:?}", count, path, outdir, now.elapsed());
+            }
+            res
+        }
+    }
+
+    unsafe fn extract_children(&self, n: *const sqfs_tree_node_t, dir: &extract::SafeDir, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+
+        let mut count = 0;
+        let mut c = (*n).children as *const sqfs_tree_node_t;
+        while !c.is_null() {
+            let name = OsStr::from_bytes(CStr::from_ptr((*c).name.as_ptr() as *const std::ffi::c_char).to_bytes());
+            count += self.extract_node(c, dir, name, opts)?;
+            c = (*c).next;
+        }
+        Ok(count)
+    }
+
+    // recreate node and its children as name in dir, return count of entries extracted
+    unsafe fn extract_node(&self, n: *const sqfs_tree_node_t, dir: &extract::SafeDir, name: &OsStr, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+        let res = self.extract_entry(n, dir, name, opts);
+        opts.skip_unsafe(res, &dir.child_path(name))
+    }
+
+    unsafe fn extract_entry(&self, n: *const sqfs_tree_node_t, dir: &extract::SafeDir, name: &OsStr, opts: &extract::ExtractOptions) -> Result<usize, Error> {
+
+        let inode = (*n).inode;
+        let outpath = dir.child_path(name);
+        debug!("extract {}", outpath.display());
+
+        match (*inode).base.type_ as u32 {
+            SQFS_INODE_TYPE_SQFS_INODE_DIR | SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => {
+                let sub = dir.subdir(name, opts.path_mode)?;
+                let count = self.extract_children(n, &sub, opts)?;
+                // directory metadata goes after children, so that mtime stays
+                // and a read-only directory does not block its children
+                sub.set_metadata(OsStr::new("."), &self.entry_meta(n))?;
+                Ok(count + 1)
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_FILE | SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE => {
+                let ino = (*inode).base.inode_number;
+                if opts.links.link(ino, dir, name)? {
+                    return Ok(1);
+                }
+
+                self.prefetch_extents(inode)?;
+                let mut output = dir.create_file(name)?;
+                self.write_file_data(inode, &mut output, &outpath.to_string_lossy())?;
+
+                if Self::stat(n)?.st_nlink > 1 {
+                    opts.links.record(ino, dir, name);
+                }
+                dir.set_metadata(name, &self.entry_meta(n))?;
+                Ok(1)
+            },
+            _ => {
+                if !self.extract_special(n, dir, name, opts)? {
+                    return Ok(0);
+                }
+                Ok(1)
+            },
+        }
+    }
+
+    // create symlink, fifo, socket or device node as name in dir with its metadata,
+    // return false if skipped
+    unsafe fn extract_special(&self, n: *const sqfs_tree_node_t, dir: &extract::SafeDir, name: &OsStr, opts: &extract::ExtractOptions) -> Result<bool, Error> {
+
+        let inode = (*n).inode;
+        let mode = (*inode).base.mode as u32;
+
+        let created = match (*inode).base.type_ as u32 {
+            SQFS_INODE_TYPE_SQFS_INODE_SLINK | SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => {
+                let target = Self::link_target(inode);
+                dir.symlink(OsStr::from_bytes(&target), name)?;
+                true
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_FIFO | SQFS_INODE_TYPE_SQFS_INODE_EXT_FIFO => {
+                dir.mknod(name, SFlag::S_IFIFO, mode, 0, opts.special)?
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_SOCKET | SQFS_INODE_TYPE_SQFS_INODE_EXT_SOCKET => {
+                dir.mknod(name, SFlag::S_IFSOCK, mode, 0, opts.special)?
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_BDEV => {
+                dir.mknod(name, SFlag::S_IFBLK, mode, extract::decode_devno((*inode).data.dev.devno), opts.special)?
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_CDEV => {
+                dir.mknod(name, SFlag::S_IFCHR, mode, extract::decode_devno((*inode).data.dev.devno), opts.special)?
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_BDEV => {
+                dir.mknod(name, SFlag::S_IFBLK, mode, extract::decode_devno((*inode).data.dev_ext.devno), opts.special)?
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_CDEV => {
+                dir.mknod(name, SFlag::S_IFCHR, mode, extract::decode_devno((*inode).data.dev_ext.devno), opts.special)?
+            },
+            _ => {
+                return Err(error::Error::INVALID(format!("unknown type {} of inode {}",
+                    (*inode).base.type_, (*inode).base.inode_number)).into());
+            },
+        };
+
+        if created {
+            dir.set_metadata(name, &self.entry_meta(n))?;
+        }
+        Ok(created)
+    }
+
+    fn do_print_list(&self, path: Option<String>) {
+
+        if path.is_none() {
+            self.print_write_tree_dfs_root();
+            return;
         }
-    }
 
-    fn file_stat(&self, filepath: &str) -> Option<libc::stat64> {
+        let f = match CString::new(path.unwrap()) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Invalid path, {}", e);
+                return;
+            },
+        };
+
         unsafe {
-            self.do_file_stat(filepath)
+            match self.load_tree(&f, 0) {
+                Ok(n) => {
+                    self.print_write_tree_dfs(n, false);
+                    sqfs_dir_tree_destroy(n);
+                },
+                Err(e) => println!("{}", e),
+            }
         }
     }
-}
 
-impl Archive {
+    // compressed byte ranges in archive hold data of a regular file,
+    // contiguous data blocks first then the fragment block if any
+    unsafe fn do_file_extents(&self, filepath: &str) -> Result<Vec<(usize, usize)>, Error> {
 
-    pub fn new(path: &str) -> Box<impl ArchiveFs> {
-        Box::new(Self::new_from_sparse(path, false))
+        let n = self.lookup_node(filepath)?;
+        let extents = self.inode_extents((*n).inode);
+        sqfs_dir_tree_destroy(n);
+        extents.ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("unable to resolve extents of {}", filepath)))
     }
 
-    pub fn new_from_sparse(path: &str, init_root: bool) -> impl ArchiveFs {
-        Self::do_new_from_sparse(path, init_root)
+    unsafe fn do_block_layout(&self, filepath: &str) -> Result<BlockLayout, Error> {
+
+        let n = self.lookup_file(filepath)?;
+        let layout = self.inode_layout((*n).inode);
+        sqfs_dir_tree_destroy(n);
+        layout
     }
 
-    fn do_new_from_sparse(path: &str, init_root: bool) -> Self {
-        let f = CString::new(path).unwrap();
-        let mut sb = MaybeUninit::<sqfs_super_t>::uninit();
-        let mut cfg = MaybeUninit::<sqfs_compressor_config_t>::uninit();
-        let mut cmp = MaybeUninit::<*mut sqfs_compressor_t>::uninit();
-        let mut root = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
-        unsafe {
-            let file = sqfs_open_file(f.as_ptr(), SQFS_FILE_OPEN_FLAGS_SQFS_FILE_OPEN_READ_ONLY);
-            if file.is_null() {
-                panic!("can not open file {}", path);
-            }
+    unsafe fn inode_layout(&self, inode: *const sqfs_inode_generic_t) -> Result<BlockLayout, Error> {
 
-            let ret = sqfs_super_read(sb.as_mut_ptr(), file);
-            if ret > 0 {
-                panic!("error reading super block");
-            }
-            let sb = Box::new(sb.assume_init());
+        let mut location = MaybeUninit::<sqfs_u64>::uninit();
+        sqfs_inode_get_file_block_start(inode, location.as_mut_ptr());
 
-            sqfs_compressor_config_init(cfg.as_mut_ptr(),
-                    sb.compression_id as u32,
-                    sb.block_size as usize,
-                    SQFS_COMP_FLAG_SQFS_COMP_FLAG_UNCOMPRESS as u16);
+        let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
+        let sizes = (*inode).extra.as_slice(blk_cnt).iter()
+                        .map(|x| x & ((1 << 24) - 1))
+                        .collect();
 
+        let mut frag_idx = MaybeUninit::<sqfs_u32>::uninit();
+        let mut frag_offset = MaybeUninit::<sqfs_u32>::uninit();
+        sqfs_inode_get_frag_location(inode, frag_idx.as_mut_ptr(), frag_offset.as_mut_ptr());
+        let frag_idx = frag_idx.assume_init();
 
-            let ret = sqfs_compressor_create(cfg.as_mut_ptr(), cmp.as_mut_ptr());
+        let mut fragment = None;
+        if frag_idx != 0xFFFFFFFF {
+            let mut frag = MaybeUninit::<sqfs_fragment_t>::uninit();
+            let ret = sqfs_frag_table_lookup(self.frag, frag_idx, frag.as_mut_ptr());
             if ret != 0 {
-                panic!("error creating compressor");
-            }
-
-            let block_size = sb.block_size;
-            let flags = sb.flags;
-            let sb_p = Box::into_raw(sb);
-            cfg.assume_init();
-            let cmp = cmp.assume_init();
-
-            let xattr: *mut sqfs_xattr_reader_t;
-            if (flags & SQFS_SUPER_FLAGS_SQFS_FLAG_NO_XATTRS as u16) == 0 {
-                xattr = sqfs_xattr_reader_create(0);
-                if xattr.is_null() {
-                    panic!("error creating xattr reader");
-                }
-
-                let ret = sqfs_xattr_reader_load(xattr, sb_p, file, cmp);
-                if ret != 0 {
-                    panic!("error loading xattr reader: {}", ret);
-                }
-            } else {
-                xattr = std::ptr::null_mut::<sqfs_xattr_reader_t>();
-            }
-
-            let idtbl = sqfs_id_table_create(0);
-            if idtbl.is_null() {
-                panic!("error creating ID table");
-            }
-
-            let ret = sqfs_id_table_read(idtbl, file, sb_p, cmp);
-            if ret > 1 {
-                panic!("error loading ID table");
+                return Err(error::sqfs(ret, &format!("looking up fragment {}", frag_idx)).into());
             }
+            let frag = frag.assume_init();
+            fragment = Some(Fragment {
+                index: frag_idx,
+                offset: frag_offset.assume_init(),
+                start: frag.start_offset,
+                size: frag.size & ((1 << 24) - 1),
+            });
+        }
 
-            let dir = sqfs_dir_reader_create(sb_p, cmp, file, 0);
-            if dir.is_null() {
-                panic!("error creating directory reader");
-            }
+        Ok(BlockLayout {
+            start: location.assume_init(),
+            sizes: sizes,
+            fragment: fragment,
+        })
+    }
 
-            if init_root {
-                let ret = sqfs_dir_reader_get_full_hierarchy(dir, idtbl, std::ptr::null(), 0, root.as_mut_ptr());
-                if ret != 0 {
-                    panic!("error loading directory tree {}", ret);
-                }
-            } else {
-                root.write(std::ptr::null_mut());
-            }
+    // tell fetcher exactly which extents going to be read, in extent mode
+    unsafe fn prefetch_extents(&self, inode: *const sqfs_inode_generic_t) -> Result<(), Error> {
+        self.prefetch_block_extents(inode, 0, usize::MAX)
+    }
 
-            let root = root.assume_init();
+    unsafe fn prefetch_block_extents(&self, inode: *const sqfs_inode_generic_t, first: usize, last: usize) -> Result<(), Error> {
+        prefetch_block_extents(self.frag, inode, first, last)
+    }
 
-            let data = sqfs_data_reader_create(file, block_size as usize, cmp, 0);
-            if data.is_null() {
-                panic!("error loading data reader");
-            }
+    unsafe fn inode_extents(&self, inode: *const sqfs_inode_generic_t) -> Option<Vec<(usize, usize)>> {
+        block_extents(self.frag, inode, 0, usize::MAX)
+    }
 
-            let ret = sqfs_data_reader_load_fragment_table(data, sb_p);
-            if ret != 0 {
-                panic!("error loading fragment table {}", ret);
-            }
+    // read and decompress one data or fragment block as sized on disk,
+    // return decompressed length
+    unsafe fn check_block(&self, start: u64, size: u32) -> Result<usize, Error> {
 
-            // as soon as init all struct, hook read_at
-            let read_at = (*file).read_at.replace(archive_read_at);
-            let _ = (*file).write_at.replace(
-                std::mem::transmute::<ReadAtType, WriteAtType>(read_at.unwrap())
-            );
+        let block_size = self.sb.block_size as usize;
+        let on_disk = (size & ((1 << 24) - 1)) as usize;
+        if on_disk == 0 || on_disk > block_size {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid size {} on disk", on_disk)));
+        }
+        if start + on_disk as u64 > self.sb.bytes_used {
+            return Err(Error::new(ErrorKind::InvalidData, format!("{}+{} past end of archive", start, on_disk)));
+        }
 
-            info!("s3 archive fs init success");
-            Self {
-                file: file,
-                sb: Box::from_raw(sb_p),
-                cmp: cmp,
-                xattr: xattr,
-                idtbl: idtbl,
-                dir: dir,
-                root: root,
-                data: data,
-            }
+        let mut buf = vec![0u8; on_disk];
+        let ret = ((*self.file).read_at.unwrap())(self.file, start, buf.as_mut_ptr() as *mut c_void, on_disk);
+        if ret != 0 {
+            return Err(error::sqfs(ret, &format!("reading block at {}", start)).into());
         }
+        // stored uncompressed
+        if size & (1 << 24) != 0 {
+            return Ok(on_disk);
+        }
+        let mut out = vec![0u8; block_size];
+        let ret = ((*self.cmp).do_block.unwrap())(self.cmp, buf.as_ptr(), on_disk as u32, out.as_mut_ptr(), block_size as u32);
+        if ret <= 0 {
+            return Err(error::sqfs(ret, &format!("decompressing block at {}", start)).into());
+        }
+        Ok(ret as usize)
     }
 
-    fn collect_xattrs(&self, inode: *const sqfs_inode_generic_t) -> Option<HashMap<Vec<u8>, Vec<u8>>> {
+    // read every table, directory, inode and data block, reads missing in
+    // local cache are fetched on demand, so a clean run hydrates the cache
+    fn do_fsck(&self) -> Result<fsck::Report, Error> {
 
-        if self.xattr.is_null() {
-            return None;
+        let mut report = fsck::Report::default();
+        for detail in fsck::check_superblock(&self.sb, self.get_archive_file_size() as u64) {
+            report.push(Area::SUPERBLOCK, "superblock", detail);
         }
-
-        let mut index = MaybeUninit::<sqfs_u32>::uninit();
-        let mut desc = MaybeUninit::<sqfs_xattr_id_t>::uninit();
         unsafe {
-            sqfs_inode_get_xattr_index(inode, index.as_mut_ptr());
-            let index = index.assume_init();
-
-            if index == 0xFFFFFFFF {
-                return None;
-            }
+            self.fsck_id_table(&mut report);
+            let frag_sizes = self.fsck_fragments(&mut report);
+            self.fsck_export_table(&mut report);
+            let xattr_count = self.fsck_xattrs(&mut report);
+            self.fsck_tree(&frag_sizes, xattr_count, &mut report);
+        }
+        info!("fsck done, {} directories, {} files, {} data blocks, {} fragment blocks, {} problems",
+            report.directories, report.files, report.data_blocks, report.fragment_blocks, report.problems.len());
+        Ok(report)
+    }
 
-            let ret = sqfs_xattr_reader_get_desc(self.xattr, index, desc.as_mut_ptr());
-            if ret != 0 {
-                error!("error resolving xattr index");
-                return None;
-            }
+    unsafe fn fsck_id_table(&self, report: &mut fsck::Report) {
 
-            let ret = sqfs_xattr_reader_seek_kv(self.xattr, desc.as_mut_ptr());
+        for i in 0..self.sb.id_count {
+            let mut id: sqfs_u32 = 0;
+            let ret = sqfs_id_table_index_to_id(self.idtbl, i, &mut id);
             if ret != 0 {
-                error!("error locating xattr KV pairs");
-                return None;
+                report.push(Area::TABLE, "id table", error::sqfs(ret, &format!("resolving id index {}", i)).to_string());
             }
+        }
+    }
 
-            let mut kv = HashMap::new();
-            let desc = desc.assume_init();
-            for _i in 0..desc.count {
-                let mut key = MaybeUninit::<*mut sqfs_xattr_entry_t>::uninit();
-                let mut val = MaybeUninit::<*mut sqfs_xattr_value_t>::uninit();
-
-                let ret = sqfs_xattr_reader_read_key(self.xattr, key.as_mut_ptr());
-                if ret != 0 {
-                    error!("error reading xattr key");
-                    return None;
-                }
-
-                let key = key.assume_init();
-                let ret = sqfs_xattr_reader_read_value(self.xattr, key, val.as_mut_ptr());
-                if ret != 0 {
-                    error!("error reading xattr value");
-                    return None;
-                }
-                let val = val.assume_init();
-
-                let k = std::slice::from_raw_parts((*key).key.as_ptr() as *const u8, (*key).size as usize);
-                let v = std::slice::from_raw_parts((*val).value.as_ptr() as *const u8, (*val).size as usize);
+    // decompressed size of every fragment block, None when unreadable
+    unsafe fn fsck_fragments(&self, report: &mut fsck::Report) -> Vec<Option<usize>> {
 
-                kv.insert(Vec::from(k), Vec::from(v));
+        let count = sqfs_frag_table_get_size(self.frag);
+        if count != self.sb.fragment_entry_count as usize {
+            report.push(Area::TABLE, "fragment table",
+                format!("{} entries, superblock counts {}", count, self.sb.fragment_entry_count));
+        }
 
-                sqfs_free(key as *mut c_void);
-                sqfs_free(val as *mut c_void);
+        let mut sizes = Vec::with_capacity(count);
+        for i in 0..count {
+            let mut frag = MaybeUninit::<sqfs_fragment_t>::uninit();
+            let ret = sqfs_frag_table_lookup(self.frag, i as u32, frag.as_mut_ptr());
+            if ret != 0 {
+                report.push(Area::TABLE, "fragment table", error::sqfs(ret, &format!("looking up fragment {}", i)).to_string());
+                sizes.push(None);
+                continue;
+            }
+            let frag = frag.assume_init();
+            match self.check_block(frag.start_offset, frag.size) {
+                Ok(len) => {
+                    report.fragment_blocks += 1;
+                    report.data_bytes += len as u64;
+                    sizes.push(Some(len));
+                },
+                Err(e) => {
+                    report.push(Area::TABLE, "fragment table", format!("fragment block {}: {}", i, e));
+                    sizes.push(None);
+                },
             }
-            Some(kv)
         }
+        sizes
     }
 
-    fn do_extract_one(&self, path: &str, outpath: &str) -> Result<usize, Error> {
+    // every entry must point to an inode carrying its own number
+    unsafe fn fsck_export_table(&self, report: &mut fsck::Report) {
 
-        let f = CString::new(path).unwrap();
-        let mut output = std::fs::File::create(outpath)?;
+        if (self.sb.flags & SQFS_SUPER_FLAGS_SQFS_FLAG_EXPORTABLE as u16) == 0 {
+            return;
+        }
+        if self.export.is_empty() {
+            let detail = match Self::read_export_table(self.file, self.cmp, &*self.sb) {
+                Err(e) => e.to_string(),
+                Ok(_) => "not loaded".to_string(),
+            };
+            report.push(Area::TABLE, "export table", detail);
+            return;
+        }
 
-        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
-        let mut file_size = MaybeUninit::<sqfs_u64>::uninit();
-        let filesz;
-        let now = Instant::now();
-        debug!("start to extract file {}", path);
-        unsafe {
-            let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), 0, n.as_mut_ptr());
+        for (i, inode_ref) in self.export.iter().enumerate() {
+            let mut inode: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+            let ret = index::read_inode(self.file, self.cmp, &*self.sb, *inode_ref, &mut inode);
             if ret != 0 {
-                if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
-                    println!("Entry not found");
-                    return Err(Error::new(ErrorKind::NotFound, "Entry not found"));
-                } else {
-                    panic!("error loading directory tree {}", ret);
-                }
+                report.push(Area::TABLE, "export table", error::sqfs(ret, &format!("reading inode {}", i + 1)).to_string());
+                continue;
             }
-            let n = n.assume_init();
-            let inode = (*n).inode;
-
-            debug!("{:>6}: {:?}", "name", CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap());
-            debug!("{:>6}: {}", "uid", (*n).uid);
-            debug!("{:>6}: {}", "gid", (*n).gid);
-            debug!("{:>6}: {}", "mode", (*inode).base.mode);
-            debug!("{:>6}: {}", "type", (*inode).base.type_);
-            debug!("{:>6}: {}", "modt", (*inode).base.mod_time);
-            debug!("{:>6}: {}", "ino", (*inode).base.inode_number);
-
-            if !s_isreg((*inode).base.mode) {
-                let s = format!("{} is not a regular file", path);
-                info!("{}", &s);
-                return Err(Error::new(ErrorKind::Other, s));
+            let ino = (*inode).base.inode_number;
+            sqfs_free(inode as *mut c_void);
+            if ino as usize != i + 1 {
+                report.push(Area::TABLE, "export table", format!("entry {} points to inode {}", i + 1, ino));
             }
+        }
+    }
 
-            sqfs_inode_get_file_size(inode, file_size.as_mut_ptr());
-            let mut file_size: size_t = file_size.assume_init() as size_t;
-            debug!("{:>6}: {}", "size", file_size);
-            filesz = file_size as usize;
-
-            let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
-
-            let mut i = 0;
-            while i < blk_cnt {
-
-                let mut chunk = MaybeUninit::<*mut sqfs_u8>::uninit();
-                let mut chunk_size = MaybeUninit::<size_t>::uninit();
-                let read: size_t;
-
-                if (file_size) < (self.sb.block_size as size_t) {
-                    read = file_size;
-                } else {
-                    read = self.sb.block_size as size_t;
-                }
-                
-                let ret = sqfs_data_reader_get_block(self.data, inode, i, chunk_size.as_mut_ptr(), chunk.as_mut_ptr());
-                if ret > 0 {
-                    panic!("error reding data block: {}", ret);
-                }
-                let chunk = chunk.assume_init();
-                let chunk_size = chunk_size.assume_init();
-                let buf = std::slice::from_raw_parts(chunk, chunk_size);
-                if let Err(e) = output.write(buf) {
-                    libc::free(chunk as *mut c_void);
-                    return Err(e);
-                }
+    // read every xattr set, return number of sets
+    unsafe fn fsck_xattrs(&self, report: &mut fsck::Report) -> u32 {
 
-                let res = output.flush();
-                libc::free(chunk as *mut c_void);
-                if res.is_err() {
-                    return Err(res.unwrap_err());
-                }
+        if self.xattr.is_null() {
+            return 0;
+        }
 
-                file_size -= read;
-                i += 1;
+        // xattr_table_start u64, xattr_ids u32, unused u32
+        let mut hdr = [0u8; 16];
+        let ret = ((*self.file).read_at.unwrap())(self.file, self.sb.xattr_id_table_start,
+                    hdr.as_mut_ptr() as *mut c_void, hdr.len());
+        if ret != 0 {
+            report.push(Area::TABLE, "xattr table", error::sqfs(ret, "reading xattr id table header").to_string());
+            return 0;
+        }
+        let count = u32::from_le_bytes([hdr[8], hdr[9], hdr[10], hdr[11]]);
+        for i in 0..count {
+            if let Err(e) = self.xattr_set(i) {
+                report.push(Area::TABLE, "xattr table", format!("set {}: {}", i, e));
             }
+        }
+        count
+    }
 
-            if file_size > 0 {
-                let mut chunk = MaybeUninit::<*mut sqfs_u8>::uninit();
-                let mut chunk_size = MaybeUninit::<size_t>::uninit();
-
-                debug!("file has {} fragmented", file_size);
-                let ret = sqfs_data_reader_get_fragment(self.data, inode, chunk_size.as_mut_ptr(), chunk.as_mut_ptr());
-                if ret > 0 {
-                    panic!("error reding fragment block: {}", ret);
-                }
+    // depth first from root inode, an unreadable directory is reported and
+    // its siblings are still walked
+    unsafe fn fsck_tree(&self, frag_sizes: &[Option<usize>], xattr_count: u32, report: &mut fsck::Report) {
 
-                let chunk = chunk.assume_init();
-                let chunk_size = chunk_size.assume_init();
-                let buf = std::slice::from_raw_parts(chunk, chunk_size);
-                if let Err(e) = output.write(buf) {
-                    libc::free(chunk as *mut c_void);
-                    return Err(e);
-                }
+        let mut root: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+        let ret = index::read_inode(self.file, self.cmp, &*self.sb, self.sb.root_inode_ref, &mut root);
+        if ret != 0 {
+            report.push(Area::TABLE, "inode table", error::sqfs(ret, "reading root inode").to_string());
+            return;
+        }
 
-                let res = output.flush();
-                libc::free(chunk as *mut c_void);
-                if res.is_err() {
-                    return Err(res.unwrap_err());
-                }
+        // data of hard linked files is checked once
+        let mut seen = std::collections::HashSet::new();
+        // an entry pointing back to a directory already walked would loop forever
+        let mut dirs = std::collections::HashSet::new();
+        let mut stack = vec![(String::new(), root)];
+        while let Some((path, inode)) = stack.pop() {
+            let what = format!("/{}", path);
+            let is_dir = Self::dir_parent(inode).is_some();
+            if is_dir && !dirs.insert((*inode).base.inode_number) {
+                report.push(Area::ENTRY, &what, format!("directory inode {} already walked, loop in directory tree",
+                    (*inode).base.inode_number));
+                sqfs_free(inode as *mut c_void);
+                continue;
             }
-            debug!("file content flushed to {}, cost: {:?}", outpath, now.elapsed());
-            let now = Instant::now();
+            self.fsck_inode(&what, inode, frag_sizes, xattr_count, &mut seen, report);
 
-            // fill metadata for output file
-            let meta = output.metadata()?;
-            // set permission
-            let mut perms = meta.permissions();
-            perms.set_mode((*inode).base.mode as u32);
-            std::fs::set_permissions(outpath, perms)?;
-            // set last mod time
-            filetime::set_file_mtime(outpath, filetime::FileTime::from_unix_time((*inode).base.mod_time as i64, 0))?;
-            // set uid/gid
-            if let Err(e) = file_owner::set_owner(outpath, file_owner::Owner::from_uid((*n).uid as u32)) {
-                info!("failed to set uid, error: {}", e);
-                // don't fail
-                //return Err(Error::new(ErrorKind::Other, "failed to set uid"));
-            }
-            if let Err(e) = file_owner::set_group(outpath, file_owner::Group::from_gid((*n).gid as u32)) {
-                info!("failed to set gid, error: {}", e);
-                // don't fail
-                //return Err(Error::new(ErrorKind::Other, "failed to set gid"));
-            }
-            // set xattr
-            if let Some(hashmap) = self.collect_xattrs(inode) {
-                for (k, v) in hashmap.iter() {
-                    let key = OsStr::from_bytes(k);
-                    xattr::set(outpath, key, &v)?;
+            if is_dir {
+                let mut children = Vec::new();
+                if let Err(e) = self.dir_children(inode, &mut children) {
+                    report.push(Area::ENTRY, &what, e.to_string());
+                }
+                // reversed so that pop goes in directory order
+                for (name, child) in children.into_iter().rev() {
+                    stack.push((entry::join_path(&path, &name), child));
                 }
-            } else {
-                debug!("no xattr found");
             }
-            debug!("write all metadata cost: {:?}", now.elapsed());
-            info!("output file write to {}", outpath);
+            sqfs_free(inode as *mut c_void);
         }
-        Ok(filesz)
     }
 
-    fn do_print_list(&self, path: Option<String>) {
+    // name and inode of every entry in directory, caller frees the inodes,
+    // which are kept in children even when reading stops on an error
+    unsafe fn dir_children(&self, dir: *const sqfs_inode_generic_t,
+            children: &mut Vec<(String, *mut sqfs_inode_generic_t)>) -> Result<(), Error> {
 
-        if path.is_none() {
-            self.print_write_tree_dfs_root();
-            return;
+        let ret = sqfs_dir_reader_open_dir(self.dir, dir, 0);
+        if ret != 0 {
+            return Err(error::sqfs(ret, "opening directory").into());
         }
+        loop {
+            let mut ent: *mut sqfs_dir_entry_t = std::ptr::null_mut();
+            let ret = sqfs_dir_reader_read(self.dir, &mut ent);
+            if ret > 0 {
+                return Ok(());
+            }
+            if ret < 0 {
+                return Err(error::sqfs(ret, "reading directory").into());
+            }
+            let name = String::from_utf8_lossy(
+                std::slice::from_raw_parts((*ent).name.as_ptr(), (*ent).size as usize + 1)
+            ).to_string();
+            sqfs_free(ent as *mut c_void);
 
-        let f = CString::new(path.unwrap()).unwrap();
-
-        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
-        unsafe {
-            let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), 0, n.as_mut_ptr());
+            let mut inode: *mut sqfs_inode_generic_t = std::ptr::null_mut();
+            let ret = sqfs_dir_reader_get_inode(self.dir, &mut inode);
             if ret != 0 {
-                if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
-                    println!("Entry not found");
-                    return;
-                } else {
-                    panic!("error loading directory tree {}", ret);
-                }
+                return Err(error::sqfs(ret, &format!("reading inode of {}", name)).into());
             }
-            let n = n.assume_init();
-            self.print_write_tree_dfs(n, false);
+            children.push((name, inode));
         }
     }
 
-    unsafe fn traverse_tree(mut n: *mut sqfs_tree_node_t, curr_path: &str, vec: &mut Vec<(String, libc::stat64)>) {
+    unsafe fn fsck_inode(&self, what: &str, inode: *const sqfs_inode_generic_t, frag_sizes: &[Option<usize>],
+            xattr_count: u32, seen: &mut std::collections::HashSet<u32>, report: &mut fsck::Report) {
 
-        /*
-        if (*n).children.is_null() {
-            let filepath = CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap();
-            let stat = Self::stat(n);
-            vec.push((filepath.to_string(), stat));
-            return;
+        match (*inode).base.type_ as u32 {
+            SQFS_INODE_TYPE_SQFS_INODE_DIR | SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR => report.directories += 1,
+            SQFS_INODE_TYPE_SQFS_INODE_FILE | SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE => report.files += 1,
+            SQFS_INODE_TYPE_SQFS_INODE_SLINK | SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => report.symlinks += 1,
+            _ => report.others += 1,
         }
-        */
-
-        n = (*n).children;
-        while !n.is_null() {
 
-            let node_type = (*(*n).inode).base.type_ as u32;
-            if node_type == SQFS_INODE_TYPE_SQFS_INODE_EXT_DIR || node_type == SQFS_INODE_TYPE_SQFS_INODE_DIR {
-                let path = curr_path.to_owned() + "/" + CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap();
-                Self::traverse_tree(n, &path, vec);
-            } else {
-                let filepath = curr_path.to_owned() + "/" + CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap();
-                let stat = Self::stat(n);
-                vec.push((filepath.to_string(), stat));
-            }
-            n = (*n).next;
+        let base = &(*inode).base;
+        if base.uid_idx >= self.sb.id_count || base.gid_idx >= self.sb.id_count {
+            report.push(Area::ENTRY, what, format!("uid index {} or gid index {} outside id table of {}",
+                base.uid_idx, base.gid_idx, self.sb.id_count));
+        }
+        if base.inode_number == 0 || base.inode_number > self.sb.inode_count {
+            report.push(Area::ENTRY, what, format!("inode number {} outside 1..={}", base.inode_number, self.sb.inode_count));
         }
-    }
-
-    unsafe fn do_file_list(&self, path: Option<String>) -> Vec<(String, libc::stat64)> {
 
-        let f;
-        let curr_path;
-        let _s;
-        if path.is_none() {
-            curr_path = "";
-            f = CString::new(curr_path).unwrap();
-        } else {
-            _s = path.unwrap();
-            curr_path = _s.as_str();
-            f = CString::new(curr_path).unwrap();
+        let mut xattr_idx = MaybeUninit::<sqfs_u32>::uninit();
+        sqfs_inode_get_xattr_index(inode, xattr_idx.as_mut_ptr());
+        let xattr_idx = xattr_idx.assume_init();
+        if xattr_idx != 0xFFFFFFFF && xattr_idx >= xattr_count {
+            report.push(Area::ENTRY, what, format!("xattr index {} outside xattr table of {}", xattr_idx, xattr_count));
         }
 
-        let mut vec = Vec::new();
+        if !s_isreg(base.mode) || !seen.insert(base.inode_number) {
+            return;
+        }
 
-        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
-        let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), 0, n.as_mut_ptr());
-        if ret != 0 {
-            if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
-                debug!("Entry not found");
-                return vec;
-            } else {
-                panic!("error loading directory tree {}", ret);
+        let file_size = Self::inode_file_size(inode);
+        let block_size = self.sb.block_size as usize;
+        let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
+        let mut location = MaybeUninit::<sqfs_u64>::uninit();
+        sqfs_inode_get_file_block_start(inode, location.as_mut_ptr());
+        let mut location = location.assume_init();
+
+        for (i, size) in (*inode).extra.as_slice(blk_cnt).iter().enumerate() {
+            let expect = std::cmp::min(block_size, file_size.saturating_sub(i * block_size));
+            if expect == 0 {
+                report.push(Area::ENTRY, what, format!("{} blocks for file size {}", blk_cnt, file_size));
+                break;
+            }
+            if size & ((1 << 24) - 1) == 0 {
+                report.sparse_blocks += 1;
+                continue;
+            }
+            match self.check_block(location, *size) {
+                Ok(len) if len == expect => {
+                    report.data_blocks += 1;
+                    report.data_bytes += len as u64;
+                },
+                Ok(len) => report.push(Area::ENTRY, what, format!("block {} decompressed to {} bytes, expect {}", i, len, expect)),
+                Err(e) => report.push(Area::ENTRY, what, format!("block {}: {}", i, e)),
             }
+            location += (size & ((1 << 24) - 1)) as u64;
         }
-        let n = n.assume_init();
 
-        Self::traverse_tree(n, curr_path, &mut vec);
-        vec
-    }
-
-    unsafe fn do_file_stat(&self, filepath: &str) -> Option<libc::stat64> {
+        let tail = file_size.saturating_sub(blk_cnt * block_size);
+        let mut frag_idx = MaybeUninit::<sqfs_u32>::uninit();
+        let mut frag_offset = MaybeUninit::<sqfs_u32>::uninit();
+        sqfs_inode_get_frag_location(inode, frag_idx.as_mut_ptr(), frag_offset.as_mut_ptr());
+        let frag_idx = frag_idx.assume_init();
+        let frag_offset = frag_offset.assume_init() as usize;
 
-        let f = CString::new(filepath).unwrap();
-
-        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
-        let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), 0, n.as_mut_ptr());
-        if ret != 0 {
-            if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
-                println!("Entry not found");
-                return None;
-            } else {
-                panic!("error loading directory tree {}", ret);
+        if frag_idx == 0xFFFFFFFF {
+            if tail > 0 {
+                report.push(Area::ENTRY, what, format!("{} bytes of tail without fragment", tail));
             }
+            return;
+        }
+        match frag_sizes.get(frag_idx as usize) {
+            None => report.push(Area::ENTRY, what, format!("fragment {} outside fragment table", frag_idx)),
+            Some(None) => report.push(Area::ENTRY, what, format!("tail in unreadable fragment {}", frag_idx)),
+            Some(Some(len)) if frag_offset + tail > *len => {
+                report.push(Area::ENTRY, what, format!("tail {}+{} past end of fragment {} of {} bytes",
+                    frag_offset, tail, frag_idx, len));
+            },
+            Some(Some(_)) => {},
         }
-        let n = n.assume_init();
-        Some(Self::stat(n))
     }
 
     fn do_print_file_stat(&self, filepath: &str) {
 
-        let f = CString::new(filepath).unwrap();
+        let f = match CString::new(filepath) {
+            Ok(f) => f,
+            Err(e) => {
+                println!("Invalid path, {}", e);
+                return;
+            },
+        };
 
-        let mut n = MaybeUninit::<*mut sqfs_tree_node_t>::uninit();
         unsafe {
-            let ret = sqfs_dir_reader_get_full_hierarchy(self.dir, self.idtbl, f.as_ptr(), 0, n.as_mut_ptr());
-            if ret != 0 {
-                if ret == SQFS_ERROR_SQFS_ERROR_NO_ENTRY {
-                    println!("Entry not found");
-                    return;
-                } else {
-                    panic!("error loading directory tree {}", ret);
-                }
+            match self.load_tree(&f, 0) {
+                Ok(n) => {
+                    self.print_stat(n);
+                    sqfs_dir_tree_destroy(n);
+                },
+                Err(e) => println!("{}", e),
             }
-            let n = n.assume_init();
-            self.print_stat(n);
         }
     }
 
@@ -540,7 +1514,7 @@ impl Archive {
         self.print_write_tree_dfs(self.root, false);
     }
 
-    pub fn print_write_tree_dfs(&self, mut n: *const sqfs_tree_node_t, print_stat: bool) {
+    pub(crate) fn print_write_tree_dfs(&self, mut n: *const sqfs_tree_node_t, print_stat: bool) {
 
         let mut p: *const sqfs_tree_node_t;
         let mut level;
@@ -597,7 +1571,7 @@ impl Archive {
         }
     }
 
-    pub unsafe fn stat(n: *const sqfs_tree_node_t) -> libc::stat64 {
+    pub(crate) unsafe fn stat(n: *const sqfs_tree_node_t) -> Result<libc::stat64, Error> {
 
         let inode = (*n).inode;
         let st_dev: u64 = 0;
@@ -629,7 +1603,7 @@ impl Archive {
 
                 st_mode |= libc::S_IFREG;
                 st_nlink = (*inode).data.file_ext.nlink as u64;
-                st_blocks = (st_size + (*inode).data.file_ext.sparse - 511) >> 9;
+                st_blocks = (st_size - (*inode).data.file_ext.sparse + 511) >> 9;
             },
             SQFS_INODE_TYPE_SQFS_INODE_DIR => {
                 st_mode |= libc::S_IFDIR;
@@ -688,7 +1662,8 @@ impl Archive {
                 st_size = (*inode).data.slink_ext.target_size as u64;
             },
             _ => {
-                panic!("unkown file type {}", (*inode).base.type_);
+                return Err(error::Error::INVALID(format!("unknown type {} of inode {}",
+                    (*inode).base.type_, (*inode).base.inode_number)).into());
             },
         }
 
@@ -711,16 +1686,16 @@ impl Archive {
         stat.st_ctime = 0;
         stat.st_ctime_nsec = 0;
 
-        stat
+        Ok(stat)
     }
 
-    pub fn print_stat(&self, n: *const sqfs_tree_node_t) {
+    pub(crate) fn print_stat(&self, n: *const sqfs_tree_node_t) {
 
         unsafe {
 
         let inode = (*n).inode;
 
-        println!("Name: {:?}", CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap());
+        println!("Name: {:?}", CStr::from_ptr((*n).name.as_ptr() as *const std::ffi::c_char).to_string_lossy());
         println!("Inode type: {:?}", (*inode).base.type_);
         println!("Inode ino: {:?}", (*inode).base.inode_number);
         println!("UID: {} (index = {})", (*n).uid, (*inode).base.uid_idx);
@@ -748,9 +1723,7 @@ impl Archive {
                 println!("Fragment offset: {}", frag_offset);
                 println!("File size: {}", size);
 
-                if (*inode).base.type_ as u32 == SQFS_INODE_TYPE_SQFS_INODE_EXT_FILE {
-                    println!("Sparse: {}", (*inode).data.file_ext.sparse);
-                }
+                println!("Sparse bytes: {}", self.sparse_bytes(inode));
 
                 println!("Blocks start: {}", location);
                 let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
@@ -760,7 +1733,11 @@ impl Archive {
                 while i < blk_cnt {
                     let blk_sz = (*inode).extra.as_slice(blk_cnt)[i] & ((1 << 24) - 1);
                     let _flag = (*inode).extra.as_slice(blk_cnt)[i] & (1 << 24);
-                    println!("\tBlock #{} size:{} ({})", i, blk_sz, if _flag == 0 {"compressed"} else {"uncompressed"});
+                    if blk_sz == 0 {
+                        println!("\tBlock #{} size:{} (sparse)", i, blk_sz);
+                    } else {
+                        println!("\tBlock #{} size:{} ({})", i, blk_sz, if _flag == 0 {"compressed"} else {"uncompressed"});
+                    }
                     i += 1;
                 }
             },
@@ -794,18 +1771,183 @@ impl Archive {
                     }
                     let idxp = idx.assume_init();
                     println!("\t{} -> block {}, header offset {}",
-                        CStr::from_ptr((*idxp).name.as_ptr() as *const std::ffi::c_char).to_str().unwrap(),
+                        CStr::from_ptr((*idxp).name.as_ptr() as *const std::ffi::c_char).to_string_lossy(),
                         (*idxp).start_block, (*idxp).index);
 
                     sqfs_free(idxp as *mut c_void);
                     i += 1;
                 }
             },
-            _ => {
-                unimplemented!("file type not yet implemented");
+            SQFS_INODE_TYPE_SQFS_INODE_SLINK => {
+                println!("Link count: {}", (*inode).data.slink.nlink);
+                println!("Link target: {}", String::from_utf8_lossy(&Self::link_target(inode)));
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_SLINK => {
+                println!("Link count: {}", (*inode).data.slink_ext.nlink);
+                println!("Link target: {}", String::from_utf8_lossy(&Self::link_target(inode)));
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_BDEV | SQFS_INODE_TYPE_SQFS_INODE_CDEV => {
+                let devno = (*inode).data.dev.devno;
+                println!("Link count: {}", (*inode).data.dev.nlink);
+                println!("Device: {},{}", libc::major(devno as libc::dev_t), libc::minor(devno as libc::dev_t));
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_BDEV | SQFS_INODE_TYPE_SQFS_INODE_EXT_CDEV => {
+                let devno = (*inode).data.dev_ext.devno;
+                println!("Link count: {}", (*inode).data.dev_ext.nlink);
+                println!("Device: {},{}", libc::major(devno as libc::dev_t), libc::minor(devno as libc::dev_t));
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_FIFO | SQFS_INODE_TYPE_SQFS_INODE_SOCKET => {
+                println!("Link count: {}", (*inode).data.ipc.nlink);
+            },
+            SQFS_INODE_TYPE_SQFS_INODE_EXT_FIFO | SQFS_INODE_TYPE_SQFS_INODE_EXT_SOCKET => {
+                println!("Link count: {}", (*inode).data.ipc_ext.nlink);
+            },
+            t => {
+                println!("ERROR unknown inode type {}", t);
             },
         }
 
         } // end of unsafe
     } // end of fn
 }
+
+// key value pairs of xattr set #index, shared by both archive readers
+pub(crate) unsafe fn xattr_set(xattr: *mut sqfs_xattr_reader_t, index: u32) -> Result<HashMap<Vec<u8>, Vec<u8>>, error::Error> {
+
+    let mut desc = MaybeUninit::<sqfs_xattr_id_t>::uninit();
+    let ret = sqfs_xattr_reader_get_desc(xattr, index, desc.as_mut_ptr());
+    if ret != 0 {
+        return Err(error::sqfs(ret, "resolving xattr index"));
+    }
+
+    let ret = sqfs_xattr_reader_seek_kv(xattr, desc.as_mut_ptr());
+    if ret != 0 {
+        return Err(error::sqfs(ret, "locating xattr KV pairs"));
+    }
+
+    let mut kv = HashMap::new();
+    let desc = desc.assume_init();
+    for _i in 0..desc.count {
+        let mut key = MaybeUninit::<*mut sqfs_xattr_entry_t>::uninit();
+        let mut val = MaybeUninit::<*mut sqfs_xattr_value_t>::uninit();
+
+        let ret = sqfs_xattr_reader_read_key(xattr, key.as_mut_ptr());
+        if ret != 0 {
+            return Err(error::sqfs(ret, "reading xattr key"));
+        }
+
+        let key = key.assume_init();
+        let ret = sqfs_xattr_reader_read_value(xattr, key, val.as_mut_ptr());
+        if ret != 0 {
+            sqfs_free(key as *mut c_void);
+            return Err(error::sqfs(ret, "reading xattr value"));
+        }
+        let val = val.assume_init();
+
+        // key is nul terminated and carries its prefix, e.g. user.
+        let k = CStr::from_ptr((*key).key.as_ptr() as *const std::ffi::c_char).to_bytes();
+        let v = std::slice::from_raw_parts((*val).value.as_ptr() as *const u8, (*val).size as usize);
+
+        kv.insert(Vec::from(k), Vec::from(v));
+
+        sqfs_free(key as *mut c_void);
+        sqfs_free(val as *mut c_void);
+    }
+    Ok(kv)
+}
+
+// tell fetcher exactly which extents going to be read, in extent mode,
+// shared by both archive readers
+pub(crate) unsafe fn prefetch_block_extents(frag_table: *mut sqfs_frag_table_t, inode: *const sqfs_inode_generic_t, first: usize, last: usize) -> Result<(), Error> {
+
+    let exact = CONTEXT.with(|c| {
+        c.borrow().as_ref().map(|l| l.fetch_mode() == FetchMode::EXTENT).unwrap_or(false)
+    });
+    if !exact {
+        return Ok(());
+    }
+
+    if let Some(extents) = block_extents(frag_table, inode, first, last) {
+        debug!("prefetch extents {:?}", extents);
+        CONTEXT.with(|c| {
+            c.borrow().as_ref().unwrap().fetch_extents(extents)
+        })?;
+    }
+    Ok(())
+}
+
+// compressed byte ranges of block #first to #last, index of block count
+// and above means fragment
+pub(crate) unsafe fn block_extents(frag_table: *mut sqfs_frag_table_t, inode: *const sqfs_inode_generic_t, first: usize, last: usize) -> Option<Vec<(usize, usize)>> {
+
+    if !s_isreg((*inode).base.mode) {
+        return Some(Vec::new());
+    }
+
+    let mut extents = Vec::new();
+
+    let mut location = MaybeUninit::<sqfs_u64>::uninit();
+    sqfs_inode_get_file_block_start(inode, location.as_mut_ptr());
+    let location = location.assume_init() as usize;
+
+    let blk_cnt = ((*inode).payload_bytes_used / std::mem::size_of::<sqfs_u32>() as u32) as usize;
+    let sizes: Vec<usize> = (*inode).extra.as_slice(blk_cnt).iter()
+                            .map(|x| (x & ((1 << 24) - 1)) as usize)
+                            .collect();
+    if let Some(extent) = data_extent(location, &sizes, first, last) {
+        extents.push(extent);
+    }
+
+    if last < blk_cnt {
+        return Some(extents);
+    }
+
+    let mut frag_idx = MaybeUninit::<sqfs_u32>::uninit();
+    let mut frag_offset = MaybeUninit::<sqfs_u32>::uninit();
+    sqfs_inode_get_frag_location(inode, frag_idx.as_mut_ptr(), frag_offset.as_mut_ptr());
+    let frag_idx = frag_idx.assume_init();
+
+    if frag_idx != 0xFFFFFFFF {
+        let mut frag = MaybeUninit::<sqfs_fragment_t>::uninit();
+        let ret = sqfs_frag_table_lookup(frag_table, frag_idx, frag.as_mut_ptr());
+        if ret != 0 {
+            error!("error looking up fragment {}: {}", frag_idx, ret);
+            return None;
+        }
+        let frag = frag.assume_init();
+        extents.push((frag.start_offset as usize, (frag.size & ((1 << 24) - 1)) as usize));
+    }
+
+    Some(extents)
+}
+
+// compressed range of data blocks #first to #last of a file starting at
+// location, last may be usize::MAX for all to the end
+fn data_extent(location: usize, sizes: &[usize], first: usize, last: usize) -> Option<(usize, usize)> {
+    if first >= sizes.len() {
+        return None;
+    }
+    let end = std::cmp::min(last.saturating_add(1), sizes.len());
+    let start = location + sizes[..first].iter().sum::<usize>();
+    let size: usize = sizes[first..end].iter().sum();
+    if size == 0 {
+        return None;
+    }
+    Some((start, size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_data_extent() {
+        let sizes = [100, 0, 50, 70];
+        // whole file, as prefetch and extents of a file ask for
+        assert_eq!(data_extent(1000, &sizes, 0, usize::MAX), Some((1000, 220)));
+        assert_eq!(data_extent(1000, &sizes, 1, 2), Some((1100, 50)));
+        assert_eq!(data_extent(1000, &sizes, 1, 1), None);
+        assert_eq!(data_extent(1000, &sizes, 4, usize::MAX), None);
+        assert_eq!(data_extent(1000, &[], 0, usize::MAX), None);
+    }
+}
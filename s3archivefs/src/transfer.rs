@@ -2,19 +2,21 @@ use std::env;
 use std::path::Path;
 use log::{info, warn};
 use std::collections::HashMap;
-use tokio::io::{Error, ErrorKind};
 use aws_config::meta::region::RegionProviderChain;
 use aws_sdk_s3::{Client, Region};
-use aws_sdk_s3::error::{UploadPartErrorKind, PutObjectErrorKind, GetObjectErrorKind, HeadObjectErrorKind};
+use aws_sdk_s3::error::{GetObjectErrorKind, HeadObjectErrorKind};
 use aws_sdk_s3::model::{CompletedMultipartUpload, CompletedPart};
 use aws_sdk_s3::model::StorageClass;
 use aws_sdk_s3::output::{CreateMultipartUploadOutput, HeadObjectOutput};
 use aws_smithy_http::byte_stream::{ByteStream, Length};
 use crate::stats::TimeStats;
+use crate::error::Error;
 
 const S3_MIN_CHUNK_SIZE: u64 = 5242880;
 const S3_MAX_CHUNK_SIZE: u64 = 5368709120;
 const S3_MAX_CHUNKS: u64 = 10000;
+const DEFAULT_GET_PART_SIZE: u64 = 8388608;
+const DEFAULT_GET_CONCURRENCY: usize = 8;
 
 fn match_storage_class(x: &str) -> StorageClass {
     match x {
@@ -33,6 +35,8 @@ pub struct TransferManager {
     client: Client,
     storage_class: StorageClass,
     mpu_chunk_size: u64,
+    get_part_size: u64,
+    get_concurrency: usize,
 }
 
 impl TransferManager {
@@ -54,13 +58,32 @@ impl TransferManager {
                             .map(|x| x.parse::<u64>().unwrap_or_default())
                             .unwrap_or_default();
 
+        // large range GET split into parts downloaded concurrently
+        let get_part_size = env::var("S3ARCHIVEFS_GET_PART_SIZE")
+                            .map(|x| x.parse::<u64>().unwrap_or(DEFAULT_GET_PART_SIZE))
+                            .unwrap_or(DEFAULT_GET_PART_SIZE);
+
+        let get_concurrency = env::var("S3ARCHIVEFS_GET_CONCURRENCY")
+                            .map(|x| x.parse::<usize>().unwrap_or(DEFAULT_GET_CONCURRENCY))
+                            .unwrap_or(DEFAULT_GET_CONCURRENCY);
+
         Self {
             client: client,
             storage_class: storage_class,
             mpu_chunk_size: mpu_chunk_size,
+            get_part_size: if get_part_size == 0 { DEFAULT_GET_PART_SIZE } else { get_part_size },
+            get_concurrency: get_concurrency,
         }
     }
 
+    pub fn get_part_size(&self) -> u64 {
+        self.get_part_size
+    }
+
+    pub fn get_concurrency(&self) -> usize {
+        self.get_concurrency
+    }
+
     pub async fn upload_object(&self, from: &str, bucket: &str, key: &str,
             mut chunksz: u64, metadata: Option<HashMap<String, String>>) -> Result<(), Error> {
 
@@ -83,22 +106,22 @@ impl TransferManager {
             .map(|x| x.len())?;
 
         if file_size == 0 {
-            return Err(Error::new(ErrorKind::InvalidInput, "invalid of file size"));
+            return Err(Error::INVALID("invalid of file size".to_string()));
         }
         if file_size > S3_MAX_CHUNK_SIZE * S3_MAX_CHUNKS {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("file size {} exceed 5TiB, too big", file_size)));
+            return Err(Error::INVALID(format!("file size {} exceed 5TiB, too big", file_size)));
         }
 
         if file_size > chunk_size {
             let chunk_count = (file_size / chunk_size) + 1;
             if chunk_count > S3_MAX_CHUNKS {
-                return Err(Error::new(ErrorKind::InvalidInput, "exceed max chunk count, pls increase your chunk size"));
+                return Err(Error::INVALID("exceed max chunk count, pls increase your chunk size".to_string()));
             }
             return self.multipart_upload_object(from, bucket, key, file_size, chunk_size, metadata).await;
         }
 
         if file_size > S3_MAX_CHUNK_SIZE {
-            return Err(Error::new(ErrorKind::InvalidInput,
+            return Err(Error::INVALID(
                 format!("file size {} can not exceed 5GiB for single PUT Object, pls make your chunk size {} smaller",
                     file_size, chunk_size)
                 ));
@@ -117,8 +140,9 @@ impl TransferManager {
                                                                 .storage_class(self.storage_class.clone())
                                                                 .send()
                                                                 .await
-                                                                .unwrap();
-        let upload_id = multipart_upload_res.upload_id().unwrap();
+                                                                .map_err(|e| Error::S3(format!("failed to create multipart upload, {}", e)))?;
+        let upload_id = multipart_upload_res.upload_id()
+                            .ok_or_else(|| Error::S3("no upload id in create multipart upload response".to_string()))?;
 
         let mut chunk_count = (file_size / chunk_size) + 1;
         let mut size_of_last_chunk = file_size % chunk_size;
@@ -145,7 +169,7 @@ impl TransferManager {
                 .length(Length::Exact(this_chunk))
                 .build()
                 .await
-                .unwrap();
+                .map_err(|e| Error::IO(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
             //Chunk index needs to start at 0, but part numbers start at 1.
             let part_number = (chunk_index as i32) + 1;
             let upload_part_res = self.client
@@ -157,21 +181,17 @@ impl TransferManager {
                 .part_number(part_number)
                 .send()
                 .await;
-            if upload_part_res.is_err() {
-                match upload_part_res {
-                    Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
-                        UploadPartErrorKind::Unhandled(_) => {}
-                        _ => {}
-                    },
-                    Err(e) => Err(e).unwrap(),
-                    _ => panic!(),
-                }
-
-                return Err(Error::new(ErrorKind::Other, "failed to upload part"));
-            }
+            let upload_part_res = match upload_part_res {
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("upload part {} of {} return error {}", part_number, from, e);
+                    self.abort_multipart_upload(bucket, key, upload_id).await;
+                    return Err(Error::S3(format!("failed to upload part {}, {}", part_number, e)));
+                },
+            };
             upload_parts.push(
                 CompletedPart::builder()
-                    .e_tag(upload_part_res.ok().unwrap().e_tag.unwrap_or_default())
+                    .e_tag(upload_part_res.e_tag.unwrap_or_default())
                     .part_number(part_number)
                     .build(),
             );
@@ -180,24 +200,43 @@ impl TransferManager {
             .set_parts(Some(upload_parts))
             .build();
 
-        let _complete_multipart_upload_res = self.client
+        let complete_multipart_upload_res = self.client
             .complete_multipart_upload()
             .bucket(bucket)
             .key(key)
             .multipart_upload(completed_multipart_upload)
             .upload_id(upload_id)
             .send()
-            .await
-            .unwrap();
+            .await;
+        if let Err(e) = complete_multipart_upload_res {
+            warn!("complete multipart upload of {} return error {}", from, e);
+            self.abort_multipart_upload(bucket, key, upload_id).await;
+            return Err(Error::S3(format!("failed to complete multipart upload, {}", e)));
+        }
 
         info!("multipart upload object {} success, cost: {}", from, stat.elapsed());
 
         Ok(())
     }
 
+    // best effort, so that failed upload does not leave parts billed
+    async fn abort_multipart_upload(&self, bucket: &str, key: &str, upload_id: &str) {
+        let res = self.client
+            .abort_multipart_upload()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await;
+        if let Err(e) = res {
+            warn!("failed to abort multipart upload {}, {}", upload_id, e);
+        }
+    }
+
     pub async fn put_object(&self, from: &str, bucket: &str, key: &str, metadata: Option<HashMap<String, String>>) -> Result<(), Error> {
 
-        let body = ByteStream::from_path(Path::new(from)).await;
+        let body = ByteStream::from_path(Path::new(from)).await
+                        .map_err(|e| Error::IO(std::io::Error::new(std::io::ErrorKind::Other, e)))?;
         let mut stat = TimeStats::new();
         info!("start to put object {} to s3://{}/{}", from, bucket, key);
         let res = self.client
@@ -206,22 +245,12 @@ impl TransferManager {
             .key(key)
             .set_metadata(metadata)
             .storage_class(self.storage_class.clone())
-            .body(body.unwrap())
+            .body(body)
             .send()
             .await;
-        if res.is_err() {
-            match &res {
-                Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
-                    PutObjectErrorKind::Unhandled(_) => {}
-                    _ => {}
-                },
-                Err(e) => {
-                    warn!("put object return error {}", e);
-                },
-                _ => panic!(),
-            }
-            warn!("failed to put object {}, reason: {:?}", from, res);
-            return Err(Error::new(ErrorKind::Other, "failed to upload part"));
+        if let Err(e) = res {
+            warn!("failed to put object {}, reason: {:?}", from, e);
+            return Err(Error::S3(format!("failed to put object, {}", e)));
         }
 
         info!("put object {} success, cost: {}", from, stat.elapsed());
@@ -236,23 +265,20 @@ impl TransferManager {
                         .key(key)
                         .send()
                         .await;
-        if res.is_err() {
-            match res {
-                Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
-                    GetObjectErrorKind::InvalidObjectState(_) => {}
-                    GetObjectErrorKind::NoSuchKey(_) => {}
-                    GetObjectErrorKind::Unhandled(_) => {}
-                    _ => {}
+        match res {
+            Ok(output) => Ok(output.body),
+            Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
+                GetObjectErrorKind::NoSuchKey(_) => Err(Error::NOTFOUND(format!("s3://{}/{}", bucket, key))),
+                _ => {
+                    warn!("get object return error {}", err);
+                    Err(Error::S3(format!("failed to get object, {}", err)))
                 },
-                Err(e) => {
-                    warn!("get object return error {}", e);
-                },
-                _ => panic!(),
-            }
-
-            return Err(Error::new(ErrorKind::Other, "failed to get object from S3"));
+            },
+            Err(e) => {
+                warn!("get object return error {}", e);
+                Err(Error::S3(format!("failed to get object, {}", e)))
+            },
         }
-        return Ok(res.ok().unwrap().body);
     }
 
     pub async fn head_object(&self, bucket: &str, key: &str) -> Result<HeadObjectOutput, Error> {
@@ -261,23 +287,19 @@ impl TransferManager {
                         .bucket(bucket)
                         .key(key)
                         .send().await;
-        if res.is_err() {
-            match res {
-                Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
-                    HeadObjectErrorKind::NotFound(_) => {
-                        return Err(Error::new(ErrorKind::NotFound, "object not found"));
-                    }
-                    HeadObjectErrorKind::Unhandled(_) => {}
-                    _ => {}
+        match res {
+            Ok(output) => Ok(output),
+            Err(aws_sdk_s3::types::SdkError::ServiceError { err, .. }) => match err.kind {
+                HeadObjectErrorKind::NotFound(_) => Err(Error::NOTFOUND(format!("s3://{}/{}", bucket, key))),
+                _ => {
+                    warn!("head object return error {}", err);
+                    Err(Error::S3(format!("failed to head object, {}", err)))
                 },
-                Err(e) => {
-                    warn!("head object return error {}", e);
-                },
-                _ => panic!(),
-            }
-
-            return Err(Error::new(ErrorKind::Other, "unhandled error"));
+            },
+            Err(e) => {
+                warn!("head object return error {}", e);
+                Err(Error::S3(format!("failed to head object, {}", e)))
+            },
         }
-        Ok(res.unwrap())
     }
 }